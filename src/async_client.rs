@@ -0,0 +1,212 @@
+use crate::commands::{self, CMD3G};
+use crate::{serialize_blocks, AppError, TgtStatus, BLOCK_LEN, REPLY_TIMEOUT};
+use std::io::ErrorKind;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Asynchronous counterpart of `send`, `exchange` and `get_status`, taking `&self` so that
+/// one client can be shared between tasks, e.g. one polling status while another streams a
+/// job. Every method is cancel safe: bytes left unsent or unread by a dropped future are
+/// sent or skipped by the next call, so the connection stays in step with the target.
+pub struct AsyncClient<S = TcpStream> {
+    connection: Mutex<Connection<S>>,
+}
+
+struct Connection<S> {
+    stream: S,
+    /// Bytes queued but not written yet
+    unsent: Vec<u8>,
+    /// Bytes of replies to queries already sent that nobody read
+    unread: usize,
+}
+
+impl AsyncClient<TcpStream> {
+    pub async fn connect<A: ToSocketAddrs>(address: A) -> Result<AsyncClient, AppError> {
+        Ok(AsyncClient::new(TcpStream::connect(address).await?))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncClient<S> {
+    pub fn new(stream: S) -> AsyncClient<S> {
+        AsyncClient {
+            connection: Mutex::new(Connection {
+                stream,
+                unsent: Vec::new(),
+                unread: 0,
+            }),
+        }
+    }
+
+    /// Sends commands to Newson target
+    pub async fn send(&self, commands: &[CMD3G]) -> Result<(), AppError> {
+        let mut connection = self.connection.lock().await;
+        connection.resync().await?;
+        connection.unsent = serialize_blocks(commands);
+        connection.flush().await
+    }
+
+    /// Sends queries and reads their replies, concatenated in the order of the queries
+    pub async fn exchange(&self, queries: &[CMD3G]) -> Result<Vec<u8>, AppError> {
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let mut connection = self.connection.lock().await;
+        connection.resync().await?;
+        connection.unsent = serialize_blocks(queries);
+        connection.unread = queries.iter().filter_map(commands::reply_len).sum();
+        connection.flush().await?;
+        let mut reply = vec![0_u8; connection.unread];
+        connection.read(&mut reply, deadline).await?;
+        Ok(reply)
+    }
+
+    /// Queries Newson target for status (positions, inputs/outputs state, etc.)
+    pub async fn get_status(&self) -> Result<TgtStatus, AppError> {
+        let reply = self.exchange(&commands::get_status()).await?;
+        Ok(bincode::deserialize(&reply)?)
+    }
+
+    /// Sends commands one block at a time, letting other calls through between blocks.
+    /// Returns false if `cancel` stopped the job before its end; the block being
+    /// written when it fires is completed, so that the target never sees half a frame.
+    pub async fn stream_job(
+        &self,
+        commands: &[CMD3G],
+        cancel: &CancellationToken,
+    ) -> Result<bool, AppError> {
+        for block in serialize_blocks(commands).chunks(BLOCK_LEN) {
+            let mut connection = tokio::select! {
+                // a cancelled job sends no more blocks, even with the lock free
+                biased;
+                _ = cancel.cancelled() => return Ok(false),
+                connection = self.connection.lock() => connection,
+            };
+            connection.resync().await?;
+            connection.unsent = block.to_vec();
+            connection.flush().await?;
+        }
+        Ok(true)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    /// Completes what a cancelled call left: writes its unsent bytes, skips its reply
+    async fn resync(&mut self) -> Result<(), AppError> {
+        self.flush().await?;
+        let mut skipped = vec![0_u8; self.unread];
+        self.read(&mut skipped, Instant::now() + REPLY_TIMEOUT)
+            .await
+    }
+
+    async fn flush(&mut self) -> Result<(), AppError> {
+        while !self.unsent.is_empty() {
+            let n = self.stream.write(&self.unsent).await?;
+            if n == 0 {
+                return Err(AppError::Io(ErrorKind::WriteZero.into()));
+            }
+            self.unsent.drain(..n);
+        }
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Reads the next `buf.len()` bytes of the replies owed
+    async fn read(&mut self, buf: &mut [u8], deadline: Instant) -> Result<(), AppError> {
+        let mut got = 0;
+        while got < buf.len() {
+            let n = tokio::time::timeout_at(deadline, self.stream.read(&mut buf[got..]))
+                .await
+                .map_err(|_| AppError::Timeout)??;
+            if n == 0 {
+                return Err(AppError::ShortReply {
+                    expected: buf.len(),
+                    got,
+                });
+            }
+            got += n;
+            self.unread -= n;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::io::{duplex, DuplexStream};
+
+    fn status_reply(inputs: u16) -> Vec<u8> {
+        let mut reply = vec![0_u8; commands::STATUS_REPLY_LEN];
+        reply[..2].copy_from_slice(&inputs.to_le_bytes());
+        reply
+    }
+
+    /// Target answering every INTSTATUS block with `inputs` counting the queries
+    async fn target(mut stream: DuplexStream) -> Vec<u8> {
+        let mut received = Vec::new();
+        let mut block = [0_u8; BLOCK_LEN];
+        let mut queries = 0;
+        while stream.read_exact(&mut block).await.is_ok() {
+            if block[6] == 0xC0 {
+                queries += 1;
+                let reply = status_reply(queries);
+                // byte by byte, as a busy network could
+                for byte in reply {
+                    stream.write_all(&[byte]).await.unwrap();
+                }
+            } else {
+                received.extend_from_slice(&block);
+            }
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn status_and_job() {
+        let (client, server) = duplex(64);
+        let target = tokio::spawn(target(server));
+        let client = Arc::new(AsyncClient::new(client));
+
+        let job = vec![CMD3G::new(0, 0, 0, 0, commands::CMD3G_OPCODE::CMD3G_SLEEP, 1); 200];
+        let streaming = {
+            let client = client.clone();
+            let job = job.clone();
+            tokio::spawn(async move { client.stream_job(&job, &CancellationToken::new()).await })
+        };
+        for queries in 1..=3 {
+            let status = client.get_status().await.unwrap();
+            assert_eq!(status.inputs().bits(), queries);
+        }
+        assert!(streaming.await.unwrap().unwrap());
+
+        drop(client);
+        let received = target.await.unwrap();
+        assert_eq!(received, serialize_blocks(&job));
+    }
+
+    #[tokio::test]
+    async fn cancellation() {
+        let (client, server) = duplex(64);
+        let target = tokio::spawn(target(server));
+        let client = AsyncClient::new(client);
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let job = vec![CMD3G::new(0, 0, 0, 0, commands::CMD3G_OPCODE::CMD3G_SLEEP, 1); 200];
+        assert!(!client.stream_job(&job, &cancel).await.unwrap());
+
+        // a status query dropped halfway, its block only partly written to the 64-byte
+        // pipe, is completed and its reply skipped by the next call
+        tokio::select! {
+            biased;
+            _ = client.get_status() => panic!("query completed in a single poll"),
+            _ = std::future::ready(()) => {}
+        }
+        assert_eq!(client.get_status().await.unwrap().inputs().bits(), 2);
+
+        drop(client);
+        assert!(target.await.unwrap().is_empty());
+    }
+}
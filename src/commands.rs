@@ -1,9 +1,12 @@
+use crate::compiler::Compiler;
 use crate::parsing::ScannerCommand;
 use serde::Deserialize;
 use serde::Serialize;
+use std::convert::TryFrom;
+use std::net::Ipv4Addr;
 
 /// Atomic Newson command
-#[derive(Debug, Serialize, PartialEq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct CMD3G {
     x: u16,
     y: u16,
@@ -43,10 +46,36 @@ impl CMD3G {
             target,
         }
     }
+    pub fn x(&self) -> u16 {
+        self.x
+    }
+    pub fn y(&self) -> u16 {
+        self.y
+    }
+    pub fn op_code(&self) -> CMD3G_OPCODE {
+        self.op_code
+    }
+    pub fn target(&self) -> u8 {
+        self.target
+    }
+    /// Position encoded in a movement command
+    pub fn position(&self) -> Position {
+        RawPosition {
+            x: self.x,
+            y: self.y,
+            xh: self.xh,
+            yh: self.yh,
+        }
+        .to_position()
+    }
+    /// 32-bit value split over the x (low half) and y (high half) fields
+    pub fn long(&self) -> u32 {
+        self.x as u32 | (self.y as u32) << 16
+    }
 }
 
 /// Target status, including positions, inputs/outputs state, etc.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, PartialEq, Clone)]
 pub struct TgtStatus {
     inputs: u16,
     outputs: u16,
@@ -64,18 +93,162 @@ pub struct TgtStatus {
     deflz: i32,
 }
 impl TgtStatus {
+    /// Raw deflection position, in micrometres
     pub fn get_position(&self) -> (i32, i32) {
         (self.deflx, self.defly)
     }
+    pub fn inputs(&self) -> IoPins {
+        IoPins(self.inputs)
+    }
+    pub fn outputs(&self) -> IoPins {
+        IoPins(self.outputs)
+    }
+    /// State of the target's execution unit
+    pub fn system_state(&self) -> SystemState {
+        SystemState(self.me)
+    }
+    /// Value returned by the last command that returns one
+    pub fn return_value(&self) -> u8 {
+        self.return_value
+    }
+    pub fn counter(&self) -> u16 {
+        self.counter
+    }
+    /// Analog channel `channel` (0 to 3), None for other channels
+    pub fn analog(&self, channel: usize) -> Option<u8> {
+        self.analog.get(channel).copied()
+    }
+    pub fn analogs(&self) -> [u8; 4] {
+        self.analog
+    }
+    /// On-the-fly encoder position, in counts
+    pub fn otf(&self) -> (i32, i32) {
+        (self.otfx, self.otfy)
+    }
+    /// Table axes position, in counts
+    pub fn table(&self) -> (i32, i32, i32) {
+        (self.tablex, self.tabley, self.tablez)
+    }
+    /// Deflection position in millimetres, the scale used by `Position::to_raw`
+    pub fn deflection(&self) -> Position {
+        Position::new(self.deflx as f64 / 1000.0, self.defly as f64 / 1000.0)
+    }
+    /// Deflection focus (z) in millimetres
+    pub fn deflection_z(&self) -> f64 {
+        self.deflz as f64 / 1000.0
+    }
+}
+
+/// State of 16 digital inputs or outputs, pin 0 being the least significant bit
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct IoPins(pub u16);
+impl IoPins {
+    /// State of pin `pin`, false for pins above 15
+    pub fn pin(&self, pin: u8) -> bool {
+        pin < 16 && self.0 & (1 << pin) != 0
+    }
+    /// Whether `self & mask == value`, the condition tested by WaitIO and WhileIO
+    pub fn matches(&self, value: u16, mask: u16) -> bool {
+        self.0 & mask == value
+    }
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+}
+
+/// Flags of the `me` status byte, e.g. SYSIDLE
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SystemState(pub u8);
+impl SystemState {
+    /// Whether every bit of `flag` is set
+    pub fn contains(&self, flag: u8) -> bool {
+        self.0 & flag == flag
+    }
+    /// Whether the target is done executing commands
+    pub fn is_idle(&self) -> bool {
+        self.contains(SYSIDLE)
+    }
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+}
+
+/// Identification of a target, the reply to INTGTID.
+///
+/// Provisional: no protocol document describes this layout and no reply from a card has
+/// been captured to check it against; it is only known to agree with `MockController`.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct TargetId {
+    kind: u16,
+    model: u16,
+    serial: u32,
+    hardware: u16,
+    firmware: [u8; 4],
+    id: u8,
+    _reserved: u8,
+}
+impl TargetId {
+    /// Target type, e.g. deflection head or I/O board
+    pub fn kind(&self) -> u16 {
+        self.kind
+    }
+    pub fn model(&self) -> u16 {
+        self.model
+    }
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+    /// Hardware revision
+    pub fn hardware(&self) -> u16 {
+        self.hardware
+    }
+    /// Firmware version as (major, minor, patch, build)
+    pub fn firmware(&self) -> (u8, u8, u8, u8) {
+        let [major, minor, patch, build] = self.firmware;
+        (major, minor, patch, build)
+    }
+    /// ID of the target on the controller bus
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+}
+
+/// Network configuration of the controller, the reply to INTGETIP.
+///
+/// Provisional, as is the layout of `TargetId`.
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct IpConfig {
+    ip: [u8; 4],
+    mask: [u8; 4],
+    gateway: [u8; 4],
+    mac: [u8; 6],
+}
+impl IpConfig {
+    pub fn ip(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.ip)
+    }
+    pub fn mask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.mask)
+    }
+    pub fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.gateway)
+    }
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
 }
 
 pub const TARGET: u8 = 0x01;
 pub const SYSIDLE: u8 = 0x40;
 pub const TGTALL: u8 = 0xFF;
+/// INTRUNMODE argument starting the stored list
+pub const RUNMODE_START: u16 = 0x01;
+/// Arcs with a smaller bulge are sent as MOVETO, with the laser off
+pub const FLAT_BULGE: f32 = 0.000001;
 
 /// Newson command types
-#[derive(Debug, Serialize, PartialEq, Clone)]
-#[serde(into = "u8")]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+#[serde(into = "u8", try_from = "u8")]
 #[allow(non_camel_case_types)]
 #[repr(u8)]
 pub enum CMD3G_OPCODE {
@@ -176,14 +349,145 @@ pub enum CMD3G_OPCODE {
     INTSCANREAD = 0xc8,
     INTGETIP = 0xc9,
 }
+impl TryFrom<u8> for CMD3G_OPCODE {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<CMD3G_OPCODE, u8> {
+        match value {
+            0x00 => Ok(CMD3G_OPCODE::CMD3G_NOP),
+            0x02 => Ok(CMD3G_OPCODE::CMD3G_SETIO),
+            0x03 => Ok(CMD3G_OPCODE::CMD3G_SETSPEED),
+            0x04 => Ok(CMD3G_OPCODE::CMD3G_JUMPTO),
+            0x05 => Ok(CMD3G_OPCODE::CMD3G_MOVETO),
+            0x06 => Ok(CMD3G_OPCODE::CMD3G_PULSTO),
+            0x07 => Ok(CMD3G_OPCODE::CMD3G_LINETO),
+            0x08 => Ok(CMD3G_OPCODE::CMD3G_PARAMS),
+            0x09 => Ok(CMD3G_OPCODE::CMD3G_ARCMOVE),
+            0x0a => Ok(CMD3G_OPCODE::CMD3G_ARCLINE),
+            0x0b => Ok(CMD3G_OPCODE::CMD3G_SPEED),
+            0x0c => Ok(CMD3G_OPCODE::CMD3G_JUMPSPEED),
+            0x0d => Ok(CMD3G_OPCODE::CMD3G_SETOSC),
+            0x0e => Ok(CMD3G_OPCODE::CMD3G_SETLOOP),
+            0x0f => Ok(CMD3G_OPCODE::CMD3G_DOLOOP),
+            0x10 => Ok(CMD3G_OPCODE::CMD3G_SLEEP),
+            0x11 => Ok(CMD3G_OPCODE::CMD3G_SUSPEND),
+            0x12 => Ok(CMD3G_OPCODE::CMD3G_SETDELAYS),
+            0x13 => Ok(CMD3G_OPCODE::CMD3G_SETLIDLE),
+            0x14 => Ok(CMD3G_OPCODE::CMD3G_SM_LASER),
+            0x15 => Ok(CMD3G_OPCODE::CMD3G_EXCHLLINK),
+            0x16 => Ok(CMD3G_OPCODE::CMD3G_TABLESET),
+            0x17 => Ok(CMD3G_OPCODE::CMD3G_TABLESSIZE),
+            0x18 => Ok(CMD3G_OPCODE::CMD3G_TABLEDELAY),
+            0x19 => Ok(CMD3G_OPCODE::CMD3G_TABLE1D),
+            0x1a => Ok(CMD3G_OPCODE::CMD3G_SETFDATA),
+            0x1b => Ok(CMD3G_OPCODE::CMD3G_TABLEMOVETO),
+            0x1c => Ok(CMD3G_OPCODE::CMD3G_SETIMGAIJ),
+            0x1d => Ok(CMD3G_OPCODE::CMD3G_SETIMGROT),
+            0x1e => Ok(CMD3G_OPCODE::CMD3G_SETIMGOFFS),
+            0x1f => Ok(CMD3G_OPCODE::CMD3G_SETIMGOFFSR),
+            0x20 => Ok(CMD3G_OPCODE::CMD3G_SETIMGOFFSZ),
+            0x21 => Ok(CMD3G_OPCODE::CMD3G_SETFS),
+            0x22 => Ok(CMD3G_OPCODE::CMD3G_SETFSZ),
+            0x23 => Ok(CMD3G_OPCODE::CMD3G_SETSPFLTR),
+            0x24 => Ok(CMD3G_OPCODE::CMD3G_BURST),
+            0x25 => Ok(CMD3G_OPCODE::CMD3G_IDXFETCH),
+            0x26 => Ok(CMD3G_OPCODE::CMD3G_LIST),
+            0x27 => Ok(CMD3G_OPCODE::CMD3G_SETCNTR),
+            0x28 => Ok(CMD3G_OPCODE::CMD3G_SETANA),
+            0x29 => Ok(CMD3G_OPCODE::CMD3G_CFG_IO),
+            0x2a => Ok(CMD3G_OPCODE::CMD3G_OTFSTEP),
+            0x2b => Ok(CMD3G_OPCODE::CMD3G_OTFRANGE),
+            0x2c => Ok(CMD3G_OPCODE::CMD3G_OTFSET),
+            0x2d => Ok(CMD3G_OPCODE::CMD3G_IOWAIT),
+            0x2e => Ok(CMD3G_OPCODE::CMD3G_OTFWAIT),
+            0x2f => Ok(CMD3G_OPCODE::CMD3G_OTFENABLE),
+            0x30 => Ok(CMD3G_OPCODE::CMD3G_SETMINGATE),
+            0x31 => Ok(CMD3G_OPCODE::CMD3G_SETBULGE),
+            0x32 => Ok(CMD3G_OPCODE::CMD3G_SETDDELAY),
+            0x33 => Ok(CMD3G_OPCODE::CMD3G_CIRCLE),
+            0x34 => Ok(CMD3G_OPCODE::CMD3G_TABLEJOG),
+            0x35 => Ok(CMD3G_OPCODE::CMD3G_TABLELINETO),
+            0x36 => Ok(CMD3G_OPCODE::CMD3G_TABLEJUMPTO),
+            0x37 => Ok(CMD3G_OPCODE::CMD3G_TABLEARCLINE),
+            0x38 => Ok(CMD3G_OPCODE::CMD3G_BRANCH),
+            0x39 => Ok(CMD3G_OPCODE::CMD3G_SETAIJ),
+            0x3a => Ok(CMD3G_OPCODE::CMD3G_SETROT),
+            0x3b => Ok(CMD3G_OPCODE::CMD3G_SETOFFS),
+            0x3c => Ok(CMD3G_OPCODE::CMD3G_SETOFFSZ),
+            0x3d => Ok(CMD3G_OPCODE::CMD3G_SETWOBBLE),
+            0x3e => Ok(CMD3G_OPCODE::CMD3G_JUMPTO3D),
+            0x3f => Ok(CMD3G_OPCODE::CMD3G_MOVETO3D),
+            0x41 => Ok(CMD3G_OPCODE::CMD3G_LINETO3D),
+            0x42 => Ok(CMD3G_OPCODE::CMD3G_TABLEWHILEIO),
+            0x43 => Ok(CMD3G_OPCODE::CMD3G_BSTR0),
+            0x44 => Ok(CMD3G_OPCODE::CMD3G_BSTRN),
+            0x45 => Ok(CMD3G_OPCODE::CMD3G_UDPSEND),
+            0x46 => Ok(CMD3G_OPCODE::CMD3G_TABLESNAP),
+            0xFF => Ok(CMD3G_OPCODE::CMD3G_EOF),
+            0x80 => Ok(CMD3G_OPCODE::INTCMD),
+            0x81 => Ok(CMD3G_OPCODE::INTSUSPEND),
+            0x82 => Ok(CMD3G_OPCODE::INTRESUME),
+            0x83 => Ok(CMD3G_OPCODE::INTABORT),
+            0x85 => Ok(CMD3G_OPCODE::INTRUNMODE),
+            0x87 => Ok(CMD3G_OPCODE::INTFLASHEP),
+            0x88 => Ok(CMD3G_OPCODE::INTFLASHPP),
+            0x89 => Ok(CMD3G_OPCODE::INTSETIO),
+            0x8a => Ok(CMD3G_OPCODE::INTUARTOPEN),
+            0x8b => Ok(CMD3G_OPCODE::INTUARTWRITE),
+            0x8c => Ok(CMD3G_OPCODE::INTEXCHLLINK),
+            0x8d => Ok(CMD3G_OPCODE::INTTESTIO),
+            0x8e => Ok(CMD3G_OPCODE::INTWAITIDLE),
+            0x8f => Ok(CMD3G_OPCODE::INTMAPTGT),
+            0x90 => Ok(CMD3G_OPCODE::INTSCANTGT),
+            0x91 => Ok(CMD3G_OPCODE::INTUDPSEND),
+            0x92 => Ok(CMD3G_OPCODE::INTWAITIO),
+            0x40 => Ok(CMD3G_OPCODE::INTREPLY),
+            0xc0 => Ok(CMD3G_OPCODE::INTSTATUS),
+            0xc1 => Ok(CMD3G_OPCODE::INTTGTSTATUS),
+            0xc4 => Ok(CMD3G_OPCODE::INTFLASHRD),
+            0xc5 => Ok(CMD3G_OPCODE::INTGTID),
+            0xc6 => Ok(CMD3G_OPCODE::INTFLASHRDY),
+            0xc7 => Ok(CMD3G_OPCODE::INTUARTREAD),
+            0xc8 => Ok(CMD3G_OPCODE::INTSCANREAD),
+            0xc9 => Ok(CMD3G_OPCODE::INTGETIP),
+            _ => Err(value),
+        }
+    }
+}
 impl From<CMD3G_OPCODE> for u8 {
     fn from(value: CMD3G_OPCODE) -> u8 {
         value as u8
     }
 }
 
+/// How the target handles the commands following a ListOpen
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ListMode {
+    /// Commands are executed as soon as they are received
+    Immediate = 0,
+    /// Commands are appended to the list stored on the target
+    Append = 1,
+    /// Commands are compiled into a list stored on the target, to be started later
+    CompileAndStore = 2,
+    /// Commands are compiled into a list stored on the target, started on ListClose
+    CompileAndStart = 4,
+}
+impl TryFrom<u32> for ListMode {
+    type Error = u32;
+
+    fn try_from(value: u32) -> Result<ListMode, u32> {
+        match value {
+            0 => Ok(ListMode::Immediate),
+            1 => Ok(ListMode::Append),
+            2 => Ok(ListMode::CompileAndStore),
+            4 => Ok(ListMode::CompileAndStart),
+            _ => Err(value),
+        }
+    }
+}
+
 /// A 2D position
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Position {
     x: f64,
     y: f64,
@@ -196,10 +500,74 @@ pub struct RawPosition {
     xh: u8,
     yh: u8,
 }
+impl RawPosition {
+    /// Converts back to millimetres, the high bytes holding the sign-extended upper bits
+    pub fn to_position(&self) -> Position {
+        let x = ((self.xh as i8 as i32) << 16) | self.x as i32;
+        let y = ((self.yh as i8 as i32) << 16) | self.y as i32;
+        Position::new(x as f64 / 1000.0, y as f64 / 1000.0)
+    }
+}
 impl Position {
     pub fn new(x: f64, y: f64) -> Position {
         Position { x, y }
     }
+    pub fn x(&self) -> f64 {
+        self.x
+    }
+    pub fn y(&self) -> f64 {
+        self.y
+    }
+    /// Rotates the position around `center` by `angle` degrees, counterclockwise
+    pub fn rotate(&self, center: &Position, angle: f64) -> Position {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let (dx, dy) = (self.x - center.x, self.y - center.y);
+        Position {
+            x: center.x + dx * cos - dy * sin,
+            y: center.y + dx * sin + dy * cos,
+        }
+    }
+    pub fn distance(&self, other: &Position) -> f64 {
+        (other.x - self.x).hypot(other.y - self.y)
+    }
+    /// Centre of the arc from the position to `to` whose bulge is the tangent of a quarter
+    /// of its included angle, counterclockwise for a positive bulge; None for a straight one
+    pub fn arc_center(&self, to: &Position, bulge: f64) -> Option<Position> {
+        let (dx, dy) = (to.x - self.x, to.y - self.y);
+        let angle = 4.0 * bulge.atan();
+        if angle.abs() < 1e-9 || (dx == 0.0 && dy == 0.0) {
+            return None;
+        }
+        // the centre lies on the bisector of the chord, left of it for a positive bulge
+        let offset = 0.5 / (angle / 2.0).tan();
+        Some(Position {
+            x: self.x + dx / 2.0 - dy * offset,
+            y: self.y + dy / 2.0 + dx * offset,
+        })
+    }
+    /// Length of the arc from the position to `to` with `bulge`, see `arc_center`
+    pub fn arc_length(&self, to: &Position, bulge: f64) -> f64 {
+        let chord = self.distance(to);
+        let angle = 4.0 * bulge.atan();
+        if angle.abs() < 1e-9 || chord == 0.0 {
+            return chord;
+        }
+        let radius = chord / (2.0 * (angle / 2.0).sin().abs());
+        radius * angle.abs()
+    }
+    /// Smallest and largest corners of the box holding `points`, None if there are none
+    pub fn bounds<'a, I>(points: I) -> Option<(Position, Position)>
+    where
+        I: IntoIterator<Item = &'a Position>,
+    {
+        points.into_iter().fold(None, |bounds, p| {
+            let (min, max) = bounds.unwrap_or((*p, *p));
+            Some((
+                Position::new(min.x.min(p.x), min.y.min(p.y)),
+                Position::new(max.x.max(p.x), max.y.max(p.y)),
+            ))
+        })
+    }
     pub fn to_raw(&self) -> RawPosition {
         RawPosition {
             x: ((self.x * 1000.0).round() as i32 & 0xFFFF) as u16,
@@ -210,16 +578,27 @@ impl Position {
     }
 }
 
-/// Turns a scanner command into a vector of atomic CMD3G commands
-fn build_command(command: &ScannerCommand) -> Vec<CMD3G> {
+/// Turns a scanner command into a vector of atomic CMD3G commands addressed to `target`.
+/// Commands depending on the ones around them (ListClose, DoWhile, SetTarget) are
+/// completed by `Compiler`.
+pub(crate) fn build_command(command: &ScannerCommand, target: u8) -> Vec<CMD3G> {
     match command {
-        ScannerCommand::ListOpen(_) => vec![], //TODO
-        ScannerCommand::ListClose => vec![],   //TODO
+        ScannerCommand::ListOpen(ListMode::Immediate) => vec![],
+        ScannerCommand::ListOpen(mode) => vec![CMD3G::new(
+            *mode as u16,
+            0,
+            0,
+            0,
+            CMD3G_OPCODE::CMD3G_LIST,
+            target,
+        )],
+        // depends on the mode of the matching ListOpen
+        ScannerCommand::ListClose => vec![],
         ScannerCommand::Jump(pos) => {
             vec![CMD3G::new_movement(
                 &pos.to_raw(),
                 CMD3G_OPCODE::CMD3G_JUMPTO,
-                TARGET,
+                target,
             )]
         }
         ScannerCommand::SetIO(value, mask) => vec![CMD3G::new(
@@ -228,7 +607,7 @@ fn build_command(command: &ScannerCommand) -> Vec<CMD3G> {
             0,
             0,
             CMD3G_OPCODE::CMD3G_SETIO,
-            TARGET,
+            target,
         )],
         ScannerCommand::SetAnalog(value, mask) => vec![CMD3G::new(
             *value,
@@ -236,38 +615,38 @@ fn build_command(command: &ScannerCommand) -> Vec<CMD3G> {
             0,
             0,
             CMD3G_OPCODE::CMD3G_SETANA,
-            TARGET,
+            target,
         )],
         ScannerCommand::Arc(center, bf) => {
-            if bf.abs() < 0.000001 {
+            if bf.abs() < FLAT_BULGE {
                 return vec![CMD3G::new_movement(
                     &center.to_raw(),
                     CMD3G_OPCODE::CMD3G_MOVETO,
-                    TARGET,
+                    target,
                 )];
             }
             vec![
-                CMD3G::new_movement(&center.to_raw(), CMD3G_OPCODE::CMD3G_ARCLINE, TARGET),
+                CMD3G::new_movement(&center.to_raw(), CMD3G_OPCODE::CMD3G_ARCLINE, target),
                 CMD3G::new(
                     ((bf.to_bits() & 0xFFFF0000) >> 16) as u16,
                     0,
                     0,
                     0,
                     CMD3G_OPCODE::CMD3G_PARAMS,
-                    TARGET,
+                    target,
                 ),
             ]
         }
         ScannerCommand::Circle(center, angle) => {
             vec![
-                CMD3G::new_movement(&center.to_raw(), CMD3G_OPCODE::CMD3G_CIRCLE, TARGET),
+                CMD3G::new_movement(&center.to_raw(), CMD3G_OPCODE::CMD3G_CIRCLE, target),
                 CMD3G::new(
                     (angle.to_bits() & 0xFFFF) as u16,
                     ((angle.to_bits() & 0xFFFF0000) >> 16) as u16,
                     0,
                     0,
                     CMD3G_OPCODE::CMD3G_PARAMS,
-                    TARGET,
+                    target,
                 ),
             ]
         }
@@ -275,15 +654,25 @@ fn build_command(command: &ScannerCommand) -> Vec<CMD3G> {
             vec![CMD3G::new_movement(
                 &pos.to_raw(),
                 CMD3G_OPCODE::CMD3G_LINETO,
-                TARGET,
+                target,
             )]
         }
-        ScannerCommand::WaitIO => vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_NOP, 0)],
+        ScannerCommand::WaitIO(value, mask, timeout) => vec![
+            CMD3G::new(*value, *mask, 0, 0, CMD3G_OPCODE::CMD3G_IOWAIT, target),
+            CMD3G::new(
+                (timeout & 0xFFFF) as u16,
+                ((timeout & 0xFFFF0000) >> 16) as u16,
+                0,
+                0,
+                CMD3G_OPCODE::CMD3G_PARAMS,
+                target,
+            ),
+        ],
         ScannerCommand::Move(pos) => {
             vec![CMD3G::new_movement(
                 &pos.to_raw(),
                 CMD3G_OPCODE::CMD3G_MOVETO,
-                TARGET,
+                target,
             )]
         }
         ScannerCommand::SetSpeed(speed) => vec![CMD3G::new(
@@ -292,7 +681,7 @@ fn build_command(command: &ScannerCommand) -> Vec<CMD3G> {
             0,
             0,
             CMD3G_OPCODE::CMD3G_SPEED,
-            TARGET,
+            target,
         )],
         ScannerCommand::SetJumpSpeed(speed) => vec![CMD3G::new(
             (speed.to_bits() & 0xFFFF) as u16,
@@ -300,7 +689,7 @@ fn build_command(command: &ScannerCommand) -> Vec<CMD3G> {
             0,
             0,
             CMD3G_OPCODE::CMD3G_JUMPSPEED,
-            TARGET,
+            target,
         )],
         ScannerCommand::Sleep(time) => vec![CMD3G::new(
             *time,
@@ -308,7 +697,7 @@ fn build_command(command: &ScannerCommand) -> Vec<CMD3G> {
             0,
             0,
             CMD3G_OPCODE::CMD3G_SLEEP,
-            TARGET,
+            target,
         )],
         ScannerCommand::Burst(time) => vec![CMD3G::new(
             *time,
@@ -316,7 +705,7 @@ fn build_command(command: &ScannerCommand) -> Vec<CMD3G> {
             0,
             0,
             CMD3G_OPCODE::CMD3G_BURST,
-            TARGET,
+            target,
         )],
         ScannerCommand::SetLaser(on) => vec![CMD3G::new(
             *on as u16,
@@ -324,7 +713,7 @@ fn build_command(command: &ScannerCommand) -> Vec<CMD3G> {
             0,
             0,
             CMD3G_OPCODE::CMD3G_SETLIDLE,
-            TARGET,
+            target,
         )],
         ScannerCommand::SetLaserTimes(on_delay, off_delay) => vec![CMD3G::new(
             *on_delay,
@@ -332,39 +721,225 @@ fn build_command(command: &ScannerCommand) -> Vec<CMD3G> {
             0,
             0,
             CMD3G_OPCODE::CMD3G_SETDELAYS,
-            TARGET,
+            target,
         )],
-        ScannerCommand::WhileIO => vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_NOP, 0)],
-        ScannerCommand::DoWhile => vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_NOP, 0)],
-        ScannerCommand::SetLoop => vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_NOP, 0)],
-        ScannerCommand::DoLoop => vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_NOP, 0)],
-        ScannerCommand::SetTarget(_) => vec![], //TODO
-        _ => vec![],
+        // the PARAMS frame holds the forward offset to the frame following the matching
+        // BRANCH, it is filled in by Compiler once the loop body is known
+        ScannerCommand::WhileIO(value, mask) => vec![
+            CMD3G::new(
+                *value,
+                *mask,
+                0,
+                0,
+                CMD3G_OPCODE::CMD3G_TABLEWHILEIO,
+                target,
+            ),
+            CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_PARAMS, target),
+        ],
+        // the backward offset to the matching TABLEWHILEIO is filled in by Compiler
+        ScannerCommand::DoWhile => vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_BRANCH, target)],
+        ScannerCommand::SetLoop(count) => vec![CMD3G::new(
+            *count,
+            0,
+            0,
+            0,
+            CMD3G_OPCODE::CMD3G_SETLOOP,
+            target,
+        )],
+        ScannerCommand::DoLoop => vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_DOLOOP, target)],
+        // changes the target of the following commands
+        ScannerCommand::SetTarget(_) => vec![],
+        // no frame to send, Compiler warns about it
+        ScannerCommand::None => vec![],
     }
 }
 
-/// Turns a vector of scanner commands into a vector of atomic CMD3G commands
+/// Turns a vector of scanner commands into a vector of atomic CMD3G commands,
+/// see `Compiler` to also get errors and warnings or to compile a job in several parts
 pub fn build_commandlist(command_vec: &[ScannerCommand]) -> Vec<CMD3G> {
-    command_vec
+    Compiler::new().compile_unchecked(command_vec)
+}
+
+/// Constructs a request to start the list stored on the target
+pub fn start_list(target: u8) -> Vec<CMD3G> {
+    run_mode(target, RUNMODE_START)
+}
+
+/// Constructs an interrupt setting the run mode of `target`, e.g. RUNMODE_START
+pub fn run_mode(target: u8, mode: u16) -> Vec<CMD3G> {
+    vec![CMD3G::new(mode, 0, 0, 0, CMD3G_OPCODE::INTRUNMODE, target)]
+}
+
+/// Constructs an interrupt stopping whatever `target` is executing
+pub fn abort(target: u8) -> Vec<CMD3G> {
+    vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::INTABORT, target)]
+}
+
+/// Constructs an interrupt pausing the execution of `target`
+pub fn suspend(target: u8) -> Vec<CMD3G> {
+    vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::INTSUSPEND, target)]
+}
+
+/// Constructs an interrupt resuming the execution of `target` after a suspend
+pub fn resume(target: u8) -> Vec<CMD3G> {
+    vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::INTRESUME, target)]
+}
+
+/// Constructs an interrupt holding back the following requests until `target` is idle.
+/// It has no reply of its own: a status request sent after it acknowledges it.
+pub fn wait_idle(target: u8) -> Vec<CMD3G> {
+    vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::INTWAITIDLE, target)]
+}
+
+/// Constructs an interrupt holding back the following requests until the inputs of
+/// `target` match `value` under `mask`, acknowledged like `wait_idle`
+pub fn wait_io(target: u8, value: u16, mask: u16) -> Vec<CMD3G> {
+    vec![CMD3G::new(
+        value,
+        mask,
+        0,
+        0,
+        CMD3G_OPCODE::INTWAITIO,
+        target,
+    )]
+}
+
+/// Constructs a request to scan the bus for targets, replied with a bitmap of their IDs
+pub fn scan_targets() -> Vec<CMD3G> {
+    vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::INTSCANTGT, TGTALL)]
+}
+
+/// Length in bytes of the reply to INTSCANTGT: one bit per target ID.
+///
+/// Provisional, as is the layout of `TargetId`: the reply length and bit order are
+/// unverified against a card.
+pub const SCAN_REPLY_LEN: usize = 32;
+
+/// Decodes the reply to INTSCANTGT into the list of target IDs found on the bus, assuming
+/// the provisional layout of `SCAN_REPLY_LEN`
+pub fn parse_scan_reply(reply: &[u8]) -> Vec<u8> {
+    reply
         .iter()
-        .flat_map(|cmd| build_command(cmd))
-        .collect::<Vec<CMD3G>>()
+        .take(SCAN_REPLY_LEN)
+        .enumerate()
+        .flat_map(|(byte, bits)| {
+            (0..8)
+                .filter(move |bit| bits & (1 << bit) != 0)
+                .map(move |bit| (byte * 8 + bit) as u8)
+        })
+        .filter(|id| *id != TGTALL)
+        .collect()
+}
+
+/// Constructs a request assigning the target ID `target` to the head on `port`.
+///
+/// Provisional: the argument order of INTMAPTGT is unverified against a card.
+pub fn map_target(port: u8, target: u8) -> Vec<CMD3G> {
+    vec![CMD3G::new(
+        port as u16,
+        target as u16,
+        0,
+        0,
+        CMD3G_OPCODE::INTMAPTGT,
+        TGTALL,
+    )]
 }
 
+/// Readdresses frames to `target`, e.g. TGTALL to broadcast them to every head
+pub fn retarget(commands: &[CMD3G], target: u8) -> Vec<CMD3G> {
+    commands
+        .iter()
+        .map(|c| CMD3G {
+            target,
+            ..c.clone()
+        })
+        .collect()
+}
+
+/// Length in bytes of the reply to INTSTATUS, i.e. of a serialized TgtStatus
+pub const STATUS_REPLY_LEN: usize = 0x2C;
+
 /// Constructs a request to get status
 pub fn get_status() -> Vec<CMD3G> {
-    vec![CMD3G::new(0, 0x2C, 0, 0, CMD3G_OPCODE::INTSTATUS, TARGET)]
+    vec![CMD3G::new(
+        0,
+        STATUS_REPLY_LEN as u16,
+        0,
+        0,
+        CMD3G_OPCODE::INTSTATUS,
+        TARGET,
+    )]
+}
+
+/// Length in bytes of the reply to `query`, None for requests the target does not answer
+pub fn reply_len(query: &CMD3G) -> Option<usize> {
+    match query.op_code {
+        // the requested length travels in the query
+        CMD3G_OPCODE::INTSTATUS | CMD3G_OPCODE::INTGTID | CMD3G_OPCODE::INTGETIP => {
+            Some(query.y as usize)
+        }
+        CMD3G_OPCODE::INTSCANTGT => Some(SCAN_REPLY_LEN),
+        _ => None,
+    }
 }
 
-/// Constructs a request to get target ID
-pub fn get_target_id() -> Vec<CMD3G> {
-    vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::INTGTID, 0)]
+/// Length in bytes of the reply to INTGTID, i.e. of a serialized TargetId
+pub const TARGET_ID_REPLY_LEN: usize = 16;
+
+/// Constructs a request to identify `target`
+pub fn get_target_id(target: u8) -> Vec<CMD3G> {
+    vec![CMD3G::new(
+        0,
+        TARGET_ID_REPLY_LEN as u16,
+        0,
+        0,
+        CMD3G_OPCODE::INTGTID,
+        target,
+    )]
+}
+
+/// Length in bytes of the reply to INTGETIP, i.e. of a serialized IpConfig
+pub const IP_REPLY_LEN: usize = 18;
+
+/// Constructs a request to get the network configuration of the controller
+pub fn get_ip() -> Vec<CMD3G> {
+    vec![CMD3G::new(
+        0,
+        IP_REPLY_LEN as u16,
+        0,
+        0,
+        CMD3G_OPCODE::INTGETIP,
+        TARGET,
+    )]
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn geometry() {
+        let near = |a: Position, b: Position| a.distance(&b) < 1e-9;
+        let (from, to) = (Position::new(1.0, 0.0), Position::new(-1.0, 0.0));
+        // a half circle counterclockwise from (1, 0) passes over the origin
+        let center = from.arc_center(&to, 1.0).unwrap();
+        assert!(near(center, Position::new(0.0, 0.0)));
+        assert!(near(from.arc_center(&to, -1.0).unwrap(), center));
+        assert_eq!(from.arc_center(&to, 0.0), None);
+        assert!((from.arc_length(&to, 1.0) - std::f64::consts::PI).abs() < 1e-9);
+        assert_eq!(from.arc_length(&to, 0.0), 2.0);
+        let quarter = (std::f64::consts::PI / 8.0).tan();
+        let center = from.arc_center(&Position::new(0.0, 1.0), quarter).unwrap();
+        assert!(near(center, Position::new(0.0, 0.0)));
+
+        let points = [from, to, Position::new(0.5, 2.0)];
+        assert_eq!(
+            Position::bounds(&points),
+            Some((Position::new(-1.0, 0.0), Position::new(1.0, 2.0)))
+        );
+        assert_eq!(Position::bounds(&[]), None);
+    }
+
     #[test]
     fn build_commands() {
         struct TestCase {
@@ -480,7 +1055,7 @@ mod tests {
             },
         ];
         for test in test_cases {
-            let got = build_command(&test.scanner_cmd);
+            let got = build_command(&test.scanner_cmd, TARGET);
             assert_eq!(got.len(), test.cmd3g_cmd.len());
             assert!(
                 got.iter().zip(test.cmd3g_cmd.iter()).all(|(a, b)| a == b),
@@ -490,4 +1065,313 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn build_loops() {
+        let test_cases = vec![
+            (
+                ScannerCommand::SetLoop(12),
+                vec![CMD3G::new(12, 0, 0, 0, CMD3G_OPCODE::CMD3G_SETLOOP, TARGET)],
+            ),
+            (
+                ScannerCommand::DoLoop,
+                vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_DOLOOP, TARGET)],
+            ),
+            (
+                ScannerCommand::WaitIO(0x01, 0x03, 70000),
+                vec![
+                    CMD3G::new(0x01, 0x03, 0, 0, CMD3G_OPCODE::CMD3G_IOWAIT, TARGET),
+                    CMD3G::new(0x1170, 0x01, 0, 0, CMD3G_OPCODE::CMD3G_PARAMS, TARGET),
+                ],
+            ),
+        ];
+        for (command, want) in test_cases {
+            assert_eq!(build_command(&command, TARGET), want, "{:?}", command);
+        }
+    }
+
+    #[test]
+    fn build_while_loop() {
+        let commands = vec![
+            ScannerCommand::SetLoop(3),
+            ScannerCommand::WhileIO(0x10, 0x10),
+            ScannerCommand::Line(Position::new(1.0, 0.0)),
+            ScannerCommand::Line(Position::new(0.0, 0.0)),
+            ScannerCommand::DoWhile,
+            ScannerCommand::DoLoop,
+        ];
+        let want = vec![
+            CMD3G::new(3, 0, 0, 0, CMD3G_OPCODE::CMD3G_SETLOOP, TARGET),
+            CMD3G::new(0x10, 0x10, 0, 0, CMD3G_OPCODE::CMD3G_TABLEWHILEIO, TARGET),
+            CMD3G::new(5, 0, 0, 0, CMD3G_OPCODE::CMD3G_PARAMS, TARGET),
+            CMD3G::new(1000, 0, 0, 0, CMD3G_OPCODE::CMD3G_LINETO, TARGET),
+            CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_LINETO, TARGET),
+            CMD3G::new(4, 0, 0, 0, CMD3G_OPCODE::CMD3G_BRANCH, TARGET),
+            CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_DOLOOP, TARGET),
+        ];
+        assert_eq!(build_commandlist(&commands), want);
+    }
+
+    #[test]
+    fn build_lists() {
+        use ScannerCommand::*;
+        let body = || Sleep(10);
+        let sleep = CMD3G::new(10, 0, 0, 0, CMD3G_OPCODE::CMD3G_SLEEP, TARGET);
+        let eof = CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_EOF, TARGET);
+        let list = |mode| CMD3G::new(mode, 0, 0, 0, CMD3G_OPCODE::CMD3G_LIST, TARGET);
+        let run = CMD3G::new(1, 0, 0, 0, CMD3G_OPCODE::INTRUNMODE, TARGET);
+
+        let test_cases = vec![
+            (ListMode::Immediate, vec![sleep.clone()]),
+            (ListMode::Append, vec![list(1), sleep.clone(), eof.clone()]),
+            (
+                ListMode::CompileAndStore,
+                vec![list(2), sleep.clone(), eof.clone()],
+            ),
+            (
+                ListMode::CompileAndStart,
+                vec![list(4), sleep.clone(), eof.clone(), run.clone()],
+            ),
+        ];
+        for (mode, want) in test_cases {
+            let got = build_commandlist(&[ListOpen(mode), body(), ListClose]);
+            assert_eq!(got, want, "{:?}", mode);
+        }
+
+        // store a list, then stream immediate commands
+        let got = build_commandlist(&[
+            ListOpen(ListMode::CompileAndStore),
+            body(),
+            ListClose,
+            body(),
+        ]);
+        assert_eq!(got, vec![list(2), sleep.clone(), eof, sleep]);
+    }
+
+    #[test]
+    fn build_targets() {
+        use ScannerCommand::*;
+        let got = build_commandlist(&[
+            Sleep(1),
+            SetTarget(2),
+            ListOpen(ListMode::CompileAndStart),
+            Sleep(2),
+            ListClose,
+            SetTarget(TGTALL),
+            Sleep(3),
+        ]);
+        let want = vec![
+            CMD3G::new(1, 0, 0, 0, CMD3G_OPCODE::CMD3G_SLEEP, TARGET),
+            CMD3G::new(4, 0, 0, 0, CMD3G_OPCODE::CMD3G_LIST, 2),
+            CMD3G::new(2, 0, 0, 0, CMD3G_OPCODE::CMD3G_SLEEP, 2),
+            CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_EOF, 2),
+            CMD3G::new(1, 0, 0, 0, CMD3G_OPCODE::INTRUNMODE, 2),
+            CMD3G::new(3, 0, 0, 0, CMD3G_OPCODE::CMD3G_SLEEP, TGTALL),
+        ];
+        assert_eq!(got, want);
+
+        assert_eq!(
+            retarget(&get_status(), TGTALL),
+            vec![CMD3G::new(0, 0x2C, 0, 0, CMD3G_OPCODE::INTSTATUS, TGTALL)]
+        );
+    }
+
+    #[test]
+    fn scan_reply() {
+        let mut reply = [0_u8; SCAN_REPLY_LEN];
+        reply[0] = 0b0000_0110;
+        reply[2] = 0b1000_0000;
+        reply[31] = 0b1000_0000;
+        assert_eq!(parse_scan_reply(&reply), vec![1, 2, 23]);
+        assert!(parse_scan_reply(&[]).is_empty());
+    }
+
+    #[test]
+    fn interrupts() {
+        let frames = [
+            (abort(2), CMD3G_OPCODE::INTABORT),
+            (suspend(2), CMD3G_OPCODE::INTSUSPEND),
+            (resume(2), CMD3G_OPCODE::INTRESUME),
+            (wait_idle(2), CMD3G_OPCODE::INTWAITIDLE),
+            (run_mode(2, RUNMODE_START), CMD3G_OPCODE::INTRUNMODE),
+        ];
+        for (frames, op_code) in frames.iter() {
+            assert_eq!(frames, &vec![CMD3G::new(frames[0].x, 0, 0, 0, *op_code, 2)]);
+            assert_eq!(reply_len(&frames[0]), None);
+        }
+        assert_eq!(start_list(2), run_mode(2, RUNMODE_START));
+        assert_eq!(
+            wait_io(TARGET, 0x10, 0x30),
+            vec![CMD3G::new(
+                0x10,
+                0x30,
+                0,
+                0,
+                CMD3G_OPCODE::INTWAITIO,
+                TARGET
+            )]
+        );
+        assert_eq!(reply_len(&get_status()[0]), Some(STATUS_REPLY_LEN));
+    }
+
+    #[test]
+    fn status() {
+        let mut reply = vec![0x05, 0x00, 0x00, 0x80, 0x41, 0x07, 0x2C, 0x01, 1, 2, 3, 4];
+        for value in &[10_i32, -10, 1, 2, 3, 1500, -2500, 250] {
+            reply.extend_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(reply.len(), STATUS_REPLY_LEN);
+        let status: TgtStatus = bincode::deserialize(&reply).unwrap();
+
+        assert!(status.inputs().pin(0));
+        assert!(!status.inputs().pin(1));
+        assert!(status.inputs().pin(2));
+        assert!(!status.inputs().pin(16));
+        assert!(status.inputs().matches(0x04, 0x0C));
+        assert!(status.outputs().pin(15));
+        assert_eq!(status.outputs().bits(), 0x8000);
+        assert!(status.system_state().is_idle());
+        assert!(status.system_state().contains(0x01));
+        assert_eq!(status.return_value(), 7);
+        assert_eq!(status.counter(), 300);
+        assert_eq!(status.analog(3), Some(4));
+        assert_eq!(status.analog(4), None);
+        assert_eq!(status.otf(), (10, -10));
+        assert_eq!(status.table(), (1, 2, 3));
+        assert_eq!(status.get_position(), (1500, -2500));
+        assert_eq!(status.deflection(), Position::new(1.5, -2.5));
+        assert_eq!(status.deflection_z(), 0.25);
+    }
+
+    #[test]
+    fn identification() {
+        let query = &get_target_id(3)[0];
+        assert_eq!(
+            (query.target(), reply_len(query)),
+            (3, Some(TARGET_ID_REPLY_LEN))
+        );
+        let reply = [2, 0, 0x10, 0, 0x39, 0x30, 0, 0, 1, 0, 4, 2, 0, 17, 3, 0];
+        let id: TargetId = bincode::deserialize(&reply).unwrap();
+        assert_eq!((id.kind(), id.model(), id.serial()), (2, 0x10, 12345));
+        assert_eq!(id.hardware(), 1);
+        assert_eq!(id.firmware(), (4, 2, 0, 17));
+        assert_eq!(id.id(), 3);
+
+        assert_eq!(reply_len(&get_ip()[0]), Some(IP_REPLY_LEN));
+        let reply = [
+            192, 168, 0, 6, 255, 255, 255, 0, 192, 168, 0, 1, 0, 0x50, 0xC2, 1, 2, 3,
+        ];
+        let ip: IpConfig = bincode::deserialize(&reply).unwrap();
+        assert_eq!(ip.ip(), Ipv4Addr::new(192, 168, 0, 6));
+        assert_eq!(ip.mask(), Ipv4Addr::new(255, 255, 255, 0));
+        assert_eq!(ip.gateway(), Ipv4Addr::new(192, 168, 0, 1));
+        assert_eq!(ip.mac(), [0, 0x50, 0xC2, 1, 2, 3]);
+    }
+
+    #[test]
+    fn parse_and_build() {
+        use std::str::FromStr;
+        let test_cases = vec![
+            (
+                "rtArcTo(-0.5,0.5,360)",
+                vec![
+                    CMD3G::new(0xFE0C, 500, 0xFF, 0, CMD3G_OPCODE::CMD3G_ARCLINE, TARGET),
+                    CMD3G::new(0x43B4, 0, 0, 0, CMD3G_OPCODE::CMD3G_PARAMS, TARGET),
+                ],
+            ),
+            (
+                "rtCircle(-0.5, 0.5, 360)",
+                vec![
+                    CMD3G::new(0xFE0C, 500, 0xFF, 0, CMD3G_OPCODE::CMD3G_CIRCLE, TARGET),
+                    CMD3G::new(0, 0x43B4, 0, 0, CMD3G_OPCODE::CMD3G_PARAMS, TARGET),
+                ],
+            ),
+            (
+                "rtSetIO(512,1024)",
+                vec![CMD3G::new(
+                    512,
+                    1024,
+                    0,
+                    0,
+                    CMD3G_OPCODE::CMD3G_SETIO,
+                    TARGET,
+                )],
+            ),
+            (
+                "rtSetAnalog(1024,512)",
+                vec![CMD3G::new(
+                    1024,
+                    512,
+                    0,
+                    0,
+                    CMD3G_OPCODE::CMD3G_SETANA,
+                    TARGET,
+                )],
+            ),
+            (
+                "rtSleep(500)",
+                vec![CMD3G::new(500, 0, 0, 0, CMD3G_OPCODE::CMD3G_SLEEP, TARGET)],
+            ),
+            (
+                "rtBurst(20)",
+                vec![CMD3G::new(20, 0, 0, 0, CMD3G_OPCODE::CMD3G_BURST, TARGET)],
+            ),
+            (
+                "rtSetLaser(1)",
+                vec![CMD3G::new(1, 0, 0, 0, CMD3G_OPCODE::CMD3G_SETLIDLE, TARGET)],
+            ),
+            (
+                "rtSetLaser(false)",
+                vec![CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_SETLIDLE, TARGET)],
+            ),
+            (
+                "rtSetLaserTimes(100,200)",
+                vec![CMD3G::new(
+                    100,
+                    200,
+                    0,
+                    0,
+                    CMD3G_OPCODE::CMD3G_SETDELAYS,
+                    TARGET,
+                )],
+            ),
+            (
+                "rtSetSpeed(1200)",
+                vec![CMD3G::new(
+                    0,
+                    0x4496,
+                    0,
+                    0,
+                    CMD3G_OPCODE::CMD3G_SPEED,
+                    TARGET,
+                )],
+            ),
+            (
+                "rtSetJumpSpeed(600)",
+                vec![CMD3G::new(
+                    0,
+                    0x4416,
+                    0,
+                    0,
+                    CMD3G_OPCODE::CMD3G_JUMPSPEED,
+                    TARGET,
+                )],
+            ),
+            (
+                "rtLineTo(-0.2,6)",
+                vec![CMD3G::new(
+                    65336,
+                    6000,
+                    255,
+                    0,
+                    CMD3G_OPCODE::CMD3G_LINETO,
+                    TARGET,
+                )],
+            ),
+        ];
+        for (script, want) in test_cases {
+            let got = build_command(&ScannerCommand::from_str(script).unwrap(), TARGET);
+            assert_eq!(got, want, "{}", script);
+        }
+    }
 }
@@ -0,0 +1,501 @@
+use crate::commands::{build_command, start_list, ListMode, Position, CMD3G, CMD3G_OPCODE, TARGET};
+use crate::parsing::ScannerCommand;
+use std::fmt;
+
+/// Error making a command list impossible to compile, with indices into the command list
+#[derive(Debug, PartialEq)]
+pub enum CompileError {
+    /// A ListClose, DoLoop or DoWhile without a preceding ListOpen, SetLoop or WhileIO
+    Unopened(usize),
+    /// A ListOpen, SetLoop or WhileIO never closed
+    Unclosed(usize),
+    /// A DoLoop closing a WhileIO, a DoWhile closing a SetLoop, etc.
+    Mismatched { open: usize, close: usize },
+    /// A ListOpen while a list is already open, or inside a loop
+    NestedList(usize),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::Unopened(i) => {
+                write!(f, "command #{} closes a loop or list that is not open", i)
+            }
+            CompileError::Unclosed(i) => {
+                write!(f, "loop or list opened by command #{} is never closed", i)
+            }
+            CompileError::Mismatched { open, close } => write!(
+                f,
+                "command #{} does not close the loop or list opened by command #{}",
+                close, open
+            ),
+            CompileError::NestedList(i) => {
+                write!(f, "command #{} opens a list inside a list or a loop", i)
+            }
+        }
+    }
+}
+impl std::error::Error for CompileError {}
+
+/// Reason of a compilation warning
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WarningKind {
+    /// A marking command before any SetSpeed
+    MarkWithoutSpeed,
+    /// A Jump before any SetJumpSpeed
+    JumpWithoutSpeed,
+    /// An Arc or Circle before any command setting the position it starts from
+    UnknownStartPosition,
+    /// A command with no CMD3G encoding, dropped from the output
+    Unsupported,
+    /// A SetLoop with a count of 0
+    EmptyLoop,
+}
+
+/// Something suspicious about a command list that still compiles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Warning {
+    /// Index of the offending command in the command list
+    pub index: usize,
+    pub kind: WarningKind,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "command #{}: ", self.index)?;
+        match self.kind {
+            WarningKind::MarkWithoutSpeed => write!(
+                f,
+                "marking before any SetSpeed, the controller's current speed is used"
+            ),
+            WarningKind::JumpWithoutSpeed => write!(
+                f,
+                "jumping before any SetJumpSpeed, the controller's current jump speed is used"
+            ),
+            WarningKind::UnknownStartPosition => write!(
+                f,
+                "arc or circle starting from an unknown position, jump to its start first"
+            ),
+            WarningKind::Unsupported => write!(f, "command cannot be encoded and is dropped"),
+            WarningKind::EmptyLoop => write!(
+                f,
+                "loop repeated 0 times, skipped by the estimate and the mock, \
+                 the controller's behaviour is undocumented"
+            ),
+        }
+    }
+}
+
+/// Kind of block opened by a command and waiting for its closing command, with the target
+/// it was opened on, which its closing frames go to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Block {
+    List {
+        mode: ListMode,
+        target: u8,
+    },
+    Loop {
+        target: u8,
+    },
+    /// Index of the TABLEWHILEIO frame
+    While {
+        frame: usize,
+        target: u8,
+    },
+}
+
+/// Turns scanner commands into CMD3G frames, tracking the state of the target along the way:
+/// the addressed target, the current position, the speeds, and the open lists and loops.
+///
+/// The target, position and speeds carry over from one call of `compile` to the next, so a
+/// job may be compiled in several parts; lists and loops must be closed within one call.
+#[derive(Clone)]
+pub struct Compiler {
+    target: u8,
+    position: Option<Position>,
+    speed: Option<f32>,
+    jump_speed: Option<f32>,
+    /// Open blocks along with the index of the command opening them, innermost last
+    blocks: Vec<(usize, Block)>,
+    frames: Vec<CMD3G>,
+    warnings: Vec<Warning>,
+}
+
+impl Default for Compiler {
+    fn default() -> Compiler {
+        Compiler::new()
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        Compiler::with_target(TARGET)
+    }
+
+    /// Creates a compiler addressing `target` until a SetTarget selects another one
+    pub fn with_target(target: u8) -> Compiler {
+        Compiler {
+            target,
+            position: None,
+            speed: None,
+            jump_speed: None,
+            blocks: Vec::new(),
+            frames: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Target addressed by the next commands
+    pub fn target(&self) -> u8 {
+        self.target
+    }
+
+    /// Position the scanner is at after the commands compiled so far, if known
+    pub fn position(&self) -> Option<Position> {
+        self.position
+    }
+
+    /// Warnings emitted by every call to `compile` so far
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Compiles a command list into CMD3G frames
+    pub fn compile(&mut self, commands: &[ScannerCommand]) -> Result<Vec<CMD3G>, CompileError> {
+        self.frames.clear();
+        self.blocks.clear();
+        for (index, command) in commands.iter().enumerate() {
+            self.command(index, command)?;
+        }
+        match self.blocks.pop() {
+            Some((index, _)) => Err(CompileError::Unclosed(index)),
+            None => Ok(std::mem::take(&mut self.frames)),
+        }
+    }
+
+    /// Compiles a command list like `compile`, encoding the commands of lists and loops that
+    /// do not nest as they stand rather than failing
+    pub fn compile_unchecked(&mut self, commands: &[ScannerCommand]) -> Vec<CMD3G> {
+        self.frames.clear();
+        self.blocks.clear();
+        for (index, command) in commands.iter().enumerate() {
+            // the frames of the command are kept, only the block structure is given up
+            let _ = self.command(index, command);
+        }
+        self.blocks.clear();
+        std::mem::take(&mut self.frames)
+    }
+
+    fn warn(&mut self, index: usize, kind: WarningKind) {
+        self.warnings.push(Warning { index, kind });
+    }
+
+    /// Warns only about the first occurrence of a recurring problem
+    fn warn_once(&mut self, index: usize, kind: WarningKind) {
+        if !self.warnings.iter().any(|w| w.kind == kind) {
+            self.warn(index, kind);
+        }
+    }
+
+    fn command(&mut self, index: usize, command: &ScannerCommand) -> Result<(), CompileError> {
+        let start = self.frames.len();
+        let mut frames = build_command(command, self.target);
+        if frames.is_empty() && *command == ScannerCommand::None {
+            self.warn(index, WarningKind::Unsupported);
+        }
+        self.frames.append(&mut frames);
+
+        match command {
+            ScannerCommand::SetTarget(target) => self.target = *target,
+            ScannerCommand::SetSpeed(speed) => self.speed = Some(*speed),
+            ScannerCommand::SetJumpSpeed(speed) => self.jump_speed = Some(*speed),
+            ScannerCommand::Jump(pos) => {
+                if self.jump_speed.is_none() {
+                    self.warn_once(index, WarningKind::JumpWithoutSpeed);
+                }
+                self.position = Some(*pos);
+            }
+            ScannerCommand::Move(pos) => self.position = Some(*pos),
+            ScannerCommand::Line(pos) => {
+                self.check_mark_speed(index);
+                self.position = Some(*pos);
+            }
+            ScannerCommand::Arc(pos, _) => {
+                self.check_mark_speed(index);
+                if self.position.is_none() {
+                    self.warn(index, WarningKind::UnknownStartPosition);
+                }
+                self.position = Some(*pos);
+            }
+            ScannerCommand::Circle(center, angle) => {
+                self.check_mark_speed(index);
+                match self.position {
+                    Some(pos) => self.position = Some(pos.rotate(center, *angle as f64)),
+                    None => self.warn(index, WarningKind::UnknownStartPosition),
+                }
+            }
+            ScannerCommand::ListOpen(mode) => {
+                if !self.blocks.is_empty() {
+                    return Err(CompileError::NestedList(index));
+                }
+                let target = self.target;
+                self.blocks.push((
+                    index,
+                    Block::List {
+                        mode: *mode,
+                        target,
+                    },
+                ));
+            }
+            ScannerCommand::SetLoop(count) => {
+                if *count == 0 {
+                    self.warn(index, WarningKind::EmptyLoop);
+                }
+                let target = self.target;
+                self.blocks.push((index, Block::Loop { target }))
+            }
+            ScannerCommand::WhileIO(_, _) => self.blocks.push((
+                index,
+                Block::While {
+                    frame: start,
+                    target: self.target,
+                },
+            )),
+            ScannerCommand::ListClose | ScannerCommand::DoLoop | ScannerCommand::DoWhile => {
+                match self.close(index, command)? {
+                    Block::List { mode, target } => self.close_list(mode, target),
+                    Block::While { frame, target } => self.close_while(start, frame, target),
+                    Block::Loop { target } => {
+                        self.frames[start] =
+                            CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_DOLOOP, target)
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Warns about marking commands sent without a marking speed
+    fn check_mark_speed(&mut self, index: usize) {
+        if self.speed.is_none() {
+            self.warn_once(index, WarningKind::MarkWithoutSpeed);
+        }
+    }
+
+    /// Pops the innermost block, checking that `command` is the one closing it
+    fn close(&mut self, index: usize, command: &ScannerCommand) -> Result<Block, CompileError> {
+        let (open, block) = self.blocks.pop().ok_or(CompileError::Unopened(index))?;
+        match (block, command) {
+            (Block::List { .. }, ScannerCommand::ListClose)
+            | (Block::Loop { .. }, ScannerCommand::DoLoop)
+            | (Block::While { .. }, ScannerCommand::DoWhile) => Ok(block),
+            _ => Err(CompileError::Mismatched { open, close: index }),
+        }
+    }
+
+    /// Terminates a list on `target` according to the mode it was opened with
+    fn close_list(&mut self, mode: ListMode, target: u8) {
+        let eof = CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_EOF, target);
+        match mode {
+            ListMode::Immediate => {}
+            ListMode::CompileAndStart => {
+                self.frames.push(eof);
+                self.frames.append(&mut start_list(target));
+            }
+            ListMode::Append | ListMode::CompileAndStore => self.frames.push(eof),
+        }
+    }
+
+    /// Fills in the offsets between a TABLEWHILEIO frame and its closing BRANCH frame
+    fn close_while(&mut self, branch: usize, frame: usize, target: u8) {
+        let back = (branch - frame) as u32;
+        let forward = (self.frames.len() - frame) as u32;
+        self.frames[branch] = CMD3G::new(
+            (back & 0xFFFF) as u16,
+            (back >> 16) as u16,
+            0,
+            0,
+            CMD3G_OPCODE::CMD3G_BRANCH,
+            target,
+        );
+        self.frames[frame + 1] = CMD3G::new(
+            (forward & 0xFFFF) as u16,
+            (forward >> 16) as u16,
+            0,
+            0,
+            CMD3G_OPCODE::CMD3G_PARAMS,
+            target,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::TGTALL;
+    use ScannerCommand::*;
+
+    fn warnings(commands: &[ScannerCommand]) -> Vec<Warning> {
+        let mut compiler = Compiler::new();
+        compiler.compile(commands).unwrap();
+        compiler.warnings().to_vec()
+    }
+
+    #[test]
+    fn loop_nesting() {
+        let compile = |commands: &[ScannerCommand]| Compiler::new().compile(commands).map(|_| ());
+        assert_eq!(
+            compile(&[SetLoop(2), WhileIO(1, 1), DoWhile, DoLoop]),
+            Ok(())
+        );
+        assert_eq!(
+            compile(&[SetLoop(2), DoLoop, DoLoop]),
+            Err(CompileError::Unopened(2))
+        );
+        assert_eq!(
+            compile(&[SetLoop(2), WhileIO(1, 1), DoWhile]),
+            Err(CompileError::Unclosed(0))
+        );
+        assert_eq!(
+            compile(&[SetLoop(2), WhileIO(1, 1), DoLoop, DoWhile]),
+            Err(CompileError::Mismatched { open: 1, close: 2 })
+        );
+
+        // unless unchecked, the frames being kept as they stand
+        let frames = Compiler::new().compile_unchecked(&[DoLoop, Sleep(1), SetLoop(2)]);
+        let opcodes: Vec<_> = frames.iter().map(|frame| frame.op_code()).collect();
+        assert_eq!(
+            opcodes,
+            [
+                CMD3G_OPCODE::CMD3G_DOLOOP,
+                CMD3G_OPCODE::CMD3G_SLEEP,
+                CMD3G_OPCODE::CMD3G_SETLOOP
+            ]
+        );
+    }
+
+    #[test]
+    fn list_nesting() {
+        let compile = |commands: &[ScannerCommand]| Compiler::new().compile(commands).map(|_| ());
+        let open = || ListOpen(ListMode::CompileAndStore);
+        assert_eq!(compile(&[open(), SetLoop(2), DoLoop, ListClose]), Ok(()));
+        assert_eq!(
+            compile(&[open(), open(), ListClose, ListClose]),
+            Err(CompileError::NestedList(1))
+        );
+        assert_eq!(
+            compile(&[SetLoop(2), open(), ListClose, DoLoop]),
+            Err(CompileError::NestedList(1))
+        );
+        assert_eq!(
+            compile(&[open(), SetLoop(2), ListClose, DoLoop]),
+            Err(CompileError::Mismatched { open: 1, close: 2 })
+        );
+        assert_eq!(compile(&[ListClose]), Err(CompileError::Unopened(0)));
+        assert_eq!(compile(&[open()]), Err(CompileError::Unclosed(0)));
+    }
+
+    #[test]
+    fn speed_warnings() {
+        let p = || Position::new(1.0, 1.0);
+        assert_eq!(
+            warnings(&[Jump(p()), Line(p()), Line(p())]),
+            vec![
+                Warning {
+                    index: 0,
+                    kind: WarningKind::JumpWithoutSpeed
+                },
+                Warning {
+                    index: 1,
+                    kind: WarningKind::MarkWithoutSpeed
+                },
+            ]
+        );
+        assert!(warnings(&[SetJumpSpeed(100.0), SetSpeed(10.0), Jump(p()), Line(p())]).is_empty());
+        assert_eq!(
+            Warning {
+                index: 1,
+                kind: WarningKind::MarkWithoutSpeed
+            }
+            .to_string(),
+            "command #1: marking before any SetSpeed, the controller's current speed is used"
+        );
+    }
+
+    #[test]
+    fn position_warnings() {
+        let p = Position::new(1.0, 1.0);
+        assert_eq!(
+            warnings(&[SetSpeed(10.0), Arc(p, 0.5), None]),
+            vec![
+                Warning {
+                    index: 1,
+                    kind: WarningKind::UnknownStartPosition
+                },
+                Warning {
+                    index: 2,
+                    kind: WarningKind::Unsupported
+                },
+            ]
+        );
+
+        assert_eq!(
+            warnings(&[SetLoop(0), DoLoop]),
+            vec![Warning {
+                index: 0,
+                kind: WarningKind::EmptyLoop
+            }]
+        );
+    }
+
+    #[test]
+    fn closing_target() {
+        // blocks are closed on the target they were opened on
+        let frames = Compiler::new()
+            .compile(&[
+                ListOpen(ListMode::CompileAndStart),
+                SetLoop(2),
+                WhileIO(1, 1),
+                SetTarget(2),
+                DoWhile,
+                DoLoop,
+                ListClose,
+            ])
+            .unwrap();
+        assert!(frames.len() > 6);
+        assert!(frames.iter().all(|frame| frame.target() == TARGET));
+    }
+
+    #[test]
+    fn state() {
+        let mut compiler = Compiler::new();
+        compiler
+            .compile(&[
+                SetSpeed(10.0),
+                Move(Position::new(1.0, 0.0)),
+                Circle(Position::new(0.0, 0.0), 90.0),
+                SetTarget(TGTALL),
+            ])
+            .unwrap();
+        let pos = compiler.position().unwrap();
+        assert!(pos.x().abs() < 1e-9 && (pos.y() - 1.0).abs() < 1e-9);
+        assert_eq!(compiler.target(), TGTALL);
+
+        // target, position and speeds carry over to the next part of the job
+        let frames = compiler.compile(&[Line(Position::new(2.0, 2.0))]).unwrap();
+        assert_eq!(
+            frames,
+            vec![CMD3G::new(
+                2000,
+                2000,
+                0,
+                0,
+                CMD3G_OPCODE::CMD3G_LINETO,
+                TGTALL
+            )]
+        );
+        assert!(compiler.warnings().is_empty());
+        assert_eq!(compiler.position(), Some(Position::new(2.0, 2.0)));
+    }
+}
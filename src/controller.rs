@@ -0,0 +1,406 @@
+use crate::commands::{self, CMD3G};
+use crate::compiler::{Compiler, Warning};
+use crate::parsing::ScannerCommand;
+use crate::watch::StatusSubscription;
+use crate::{acknowledged, exchange_within, send, AppError, IpConfig, TargetId, TgtStatus};
+use std::io::ErrorKind;
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Port Newson controllers listen on
+pub const DEFAULT_PORT: u16 = 10002;
+
+/// How a `Controller` connects and talks to the target
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    reply_timeout: Duration,
+    reconnect: bool,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> ConnectOptions {
+        ConnectOptions {
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_millis(100),
+            write_timeout: Duration::from_secs(5),
+            reply_timeout: crate::REPLY_TIMEOUT,
+            reconnect: true,
+        }
+    }
+}
+
+impl ConnectOptions {
+    pub fn new() -> ConnectOptions {
+        ConnectOptions::default()
+    }
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> ConnectOptions {
+        self.connect_timeout = timeout;
+        self
+    }
+    /// Longest single socket read; replies are awaited up to the reply timeout regardless
+    pub fn with_read_timeout(mut self, timeout: Duration) -> ConnectOptions {
+        self.read_timeout = timeout;
+        self
+    }
+    pub fn with_write_timeout(mut self, timeout: Duration) -> ConnectOptions {
+        self.write_timeout = timeout;
+        self
+    }
+    /// Longest wait for a complete reply
+    pub fn with_reply_timeout(mut self, timeout: Duration) -> ConnectOptions {
+        self.reply_timeout = timeout;
+        self
+    }
+    /// Whether a call after a connection failure reconnects, true by default
+    pub fn with_reconnect(mut self, reconnect: bool) -> ConnectOptions {
+        self.reconnect = reconnect;
+        self
+    }
+}
+
+/// Connection to a Newson controller. A transport error drops the connection, which the
+/// next call reopens; queries (status, target ID, abort) are retried once right away.
+/// The connection is shut down when the Controller is dropped.
+pub struct Controller {
+    addresses: Vec<SocketAddr>,
+    options: ConnectOptions,
+    stream: Option<TcpStream>,
+    /// Whether the connection was dropped after an error
+    broken: bool,
+    compiler: Compiler,
+}
+
+impl Controller {
+    /// Connects to `address`, e.g. `"192.168.0.6:10002"` or `(ip, DEFAULT_PORT)`
+    pub fn connect<A: ToSocketAddrs>(
+        address: A,
+        options: ConnectOptions,
+    ) -> Result<Controller, AppError> {
+        let mut controller = Controller {
+            addresses: address.to_socket_addrs()?.collect(),
+            options,
+            stream: None,
+            broken: false,
+            compiler: Compiler::new(),
+        };
+        controller.stream()?;
+        Ok(controller)
+    }
+
+    /// Address of the open connection, or of the first one to try
+    pub fn address(&self) -> Option<SocketAddr> {
+        match &self.stream {
+            Some(stream) => stream.peer_addr().ok(),
+            None => self.addresses.first().copied(),
+        }
+    }
+
+    /// Status of the target the commands are addressed to
+    pub fn status(&mut self) -> Result<TgtStatus, AppError> {
+        let query = commands::retarget(&commands::get_status(), self.compiler.target());
+        let reply = self.retry(|c| c.exchange(&query))?;
+        Ok(bincode::deserialize(&reply)?)
+    }
+
+    /// Polls `status` every `interval`, yielding only the snapshots that changed
+    pub fn watch_status(&mut self, interval: Duration) -> StatusSubscription<'_, Controller> {
+        StatusSubscription::new(self, interval)
+    }
+
+    /// Identifies the target the commands are addressed to
+    pub fn target_id(&mut self) -> Result<TargetId, AppError> {
+        let target = self.compiler.target();
+        let reply = self.retry(|c| c.exchange(&commands::get_target_id(target)))?;
+        Ok(bincode::deserialize(&reply)?)
+    }
+
+    /// Network configuration of the controller
+    pub fn get_ip(&mut self) -> Result<IpConfig, AppError> {
+        let reply = self.retry(|c| c.exchange(&commands::get_ip()))?;
+        Ok(bincode::deserialize(&reply)?)
+    }
+
+    /// Compiles and sends `commands`, returning the compiler's warnings. The target and
+    /// position reached carry over to the next call, once the commands are sent.
+    pub fn run(&mut self, commands: &[ScannerCommand]) -> Result<Vec<Warning>, AppError> {
+        let mut compiler = self.compiler.clone();
+        let seen = compiler.warnings().len();
+        let frames = compiler.compile(commands)?;
+        self.send(&frames)?;
+        self.compiler = compiler;
+        Ok(self.compiler.warnings()[seen..].to_vec())
+    }
+
+    /// Sends raw queries, returning their replies in the order of the queries
+    pub fn query(&mut self, queries: &[CMD3G]) -> Result<Vec<u8>, AppError> {
+        self.retry(|c| c.exchange(queries))
+    }
+
+    /// Sends raw commands
+    pub fn send(&mut self, frames: &[CMD3G]) -> Result<(), AppError> {
+        let result = send(frames, self.stream()?);
+        self.check(result)
+    }
+
+    /// Starts the list stored on the target
+    pub fn start_list(&mut self) -> Result<(), AppError> {
+        let target = self.compiler.target();
+        self.send(&commands::start_list(target))
+    }
+
+    /// Stops whatever the target is executing
+    pub fn abort(&mut self) -> Result<(), AppError> {
+        let target = self.compiler.target();
+        self.retry(|c| c.send(&commands::abort(target)))
+    }
+
+    /// Pauses the execution of the target
+    pub fn suspend(&mut self) -> Result<(), AppError> {
+        let target = self.compiler.target();
+        self.send(&commands::suspend(target))
+    }
+
+    /// Resumes the execution of the target after `suspend`
+    pub fn resume(&mut self) -> Result<(), AppError> {
+        let target = self.compiler.target();
+        self.send(&commands::resume(target))
+    }
+
+    /// Waits up to `timeout` for the target to be idle, returning its status then
+    pub fn wait_idle(&mut self, timeout: Duration) -> Result<TgtStatus, AppError> {
+        let target = self.compiler.target();
+        self.acknowledged(commands::wait_idle(target), timeout)
+    }
+
+    /// Waits up to `timeout` for the inputs to match `value` under `mask`
+    pub fn wait_io(
+        &mut self,
+        value: u16,
+        mask: u16,
+        timeout: Duration,
+    ) -> Result<TgtStatus, AppError> {
+        let target = self.compiler.target();
+        self.acknowledged(commands::wait_io(target, value, mask), timeout)
+    }
+
+    /// Closes the connection, the next call reopening it
+    pub fn disconnect(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn acknowledged(
+        &mut self,
+        queries: Vec<CMD3G>,
+        timeout: Duration,
+    ) -> Result<TgtStatus, AppError> {
+        let target = self.compiler.target();
+        // a timed out wait leaves its reply to come, the connection is dropped as out of step
+        let result = acknowledged(queries, target, timeout, self.stream()?);
+        self.check(result)
+    }
+
+    fn exchange(&mut self, queries: &[CMD3G]) -> Result<Vec<u8>, AppError> {
+        let timeout = self.options.reply_timeout;
+        let result = exchange_within(queries, self.stream()?, timeout);
+        self.check(result)
+    }
+
+    /// Runs `call` again on a new connection if it failed on one that was open
+    fn retry<T, F>(&mut self, mut call: F) -> Result<T, AppError>
+    where
+        F: FnMut(&mut Controller) -> Result<T, AppError>,
+    {
+        let was_open = self.stream.is_some();
+        match call(self) {
+            // the connection may have been stale, e.g. after the controller rebooted
+            Err(_) if was_open && self.stream.is_none() && self.options.reconnect => call(self),
+            result => result,
+        }
+    }
+
+    /// Drops the connection after a transport error, as it may be out of step
+    fn check<T>(&mut self, result: Result<T, AppError>) -> Result<T, AppError> {
+        if let Err(
+            AppError::Io(_)
+            | AppError::Timeout
+            | AppError::ShortReply { .. }
+            | AppError::Decode(_)
+            | AppError::UnexpectedOpcode { .. },
+        ) = &result
+        {
+            self.disconnect();
+            self.broken = true;
+        }
+        result
+    }
+
+    /// The open connection, reopened if needed
+    fn stream(&mut self) -> Result<&mut TcpStream, AppError> {
+        if self.stream.is_none() {
+            if self.broken && !self.options.reconnect {
+                return Err(AppError::Io(ErrorKind::NotConnected.into()));
+            }
+            self.stream = Some(self.open()?);
+            self.broken = false;
+        }
+        Ok(self.stream.as_mut().unwrap())
+    }
+
+    fn open(&self) -> Result<TcpStream, AppError> {
+        let mut last = AppError::Io(ErrorKind::AddrNotAvailable.into());
+        for address in &self.addresses {
+            match TcpStream::connect_timeout(address, self.options.connect_timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.options.read_timeout))?;
+                    stream.set_write_timeout(Some(self.options.write_timeout))?;
+                    stream.set_nodelay(true)?;
+                    return Ok(stream);
+                }
+                Err(e) => last = e.into(),
+            }
+        }
+        Err(last)
+    }
+}
+
+impl Drop for Controller {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{ListMode, Position, STATUS_REPLY_LEN, TARGET_ID_REPLY_LEN};
+    use crate::compiler::WarningKind;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// Target replying to INTSTATUS with `me` and to INTGTID with the ID queried, dropping
+    /// each connection after `blocks` blocks and forwarding the other blocks it receives.
+    /// Busy, it never gets past an INTWAITIDLE.
+    fn target(me: u8, blocks: usize) -> (SocketAddr, mpsc::Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (received, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut block = [0_u8; crate::BLOCK_LEN];
+                let mut waiting = false;
+                for _ in 0..blocks {
+                    if stream.read_exact(&mut block).is_err() {
+                        break;
+                    }
+                    let mut query = false;
+                    for frame in block.chunks(8) {
+                        match frame[6] {
+                            0x8E => waiting |= me & commands::SYSIDLE == 0,
+                            0xC0 if !waiting => {
+                                let mut reply = vec![0_u8; STATUS_REPLY_LEN];
+                                reply[4] = me;
+                                stream.write_all(&reply).unwrap();
+                            }
+                            0xC5 => {
+                                let mut reply = [0_u8; TARGET_ID_REPLY_LEN];
+                                reply[14] = frame[7];
+                                stream.write_all(&reply).unwrap();
+                            }
+                            _ => continue,
+                        }
+                        query = true;
+                    }
+                    if !query && received.send(block.to_vec()).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        (address, receiver)
+    }
+
+    #[test]
+    fn status_and_reconnect() {
+        let (address, _) = target(commands::SYSIDLE, 2);
+        let mut controller = Controller::connect(address, ConnectOptions::new()).unwrap();
+        // the third query goes through a new connection
+        for _ in 0..5 {
+            assert!(controller.status().unwrap().system_state().is_idle());
+        }
+        assert!(controller.wait_idle(Duration::from_secs(1)).is_ok());
+        assert_eq!(controller.target_id().unwrap().id(), commands::TARGET);
+
+        let (address, _) = target(0, 1);
+        let options = ConnectOptions::new().with_reconnect(false);
+        let mut controller = Controller::connect(address, options).unwrap();
+        assert!(controller.status().is_ok());
+        // the connection is gone, reset or closed depending on timing
+        assert!(controller.status().is_err());
+        match controller.status() {
+            Err(AppError::Io(e)) => assert_eq!(e.kind(), ErrorKind::NotConnected),
+            other => panic!("expected no reconnection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn wait_idle() {
+        let (address, _) = target(commands::SYSIDLE, usize::MAX);
+        let mut controller = Controller::connect(address, ConnectOptions::new()).unwrap();
+        let status = controller.wait_idle(Duration::from_millis(500)).unwrap();
+        assert!(status.system_state().is_idle());
+
+        let (address, _) = target(0, usize::MAX);
+        let mut controller = Controller::connect(address, ConnectOptions::new()).unwrap();
+        assert!(matches!(
+            controller.wait_idle(Duration::from_millis(30)),
+            Err(AppError::Timeout)
+        ));
+        // the late acknowledgement went away with the connection
+        assert!(controller.status().is_ok());
+    }
+
+    #[test]
+    fn run() {
+        let (address, received) = target(commands::SYSIDLE, usize::MAX);
+        let mut controller = Controller::connect(address, ConnectOptions::new()).unwrap();
+        let job = [
+            ScannerCommand::ListOpen(ListMode::Append),
+            ScannerCommand::SetTarget(2),
+            ScannerCommand::Line(Position::new(1.0, 1.0)),
+            ScannerCommand::ListClose,
+        ];
+        let warnings = controller.run(&job).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::MarkWithoutSpeed);
+        let block = received.recv().unwrap();
+        let frames = crate::disasm::decode_frames(&block).unwrap();
+        assert_eq!(frames[0].op_code(), commands::CMD3G_OPCODE::CMD3G_LIST);
+        assert_eq!(frames[1].target(), 2);
+        // the list is terminated on the target it was opened on
+        assert_eq!(frames[2].op_code(), commands::CMD3G_OPCODE::CMD3G_EOF);
+        assert_eq!(frames[2].target(), commands::TARGET);
+
+        // the target set by the job carries over
+        controller.abort().unwrap();
+        let block = received.recv().unwrap();
+        assert_eq!((block[6], block[7]), (0x83, 2));
+
+        // but not that of a job that could not be sent
+        let (address, _) = target(commands::SYSIDLE, 1);
+        let options = ConnectOptions::new().with_reconnect(false);
+        let mut controller = Controller::connect(address, options).unwrap();
+        assert!(controller.status().is_ok());
+        while controller.status().is_ok() {}
+        assert!(controller.run(&job).is_err());
+        assert_eq!(controller.compiler.target(), commands::TARGET);
+        assert!(controller.compiler.warnings().is_empty());
+    }
+}
@@ -0,0 +1,275 @@
+use crate::commands::{ListMode, CMD3G, CMD3G_OPCODE, RUNMODE_START, TARGET};
+use crate::parsing::ScannerCommand;
+use bincode::Options;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// Size in bytes of a serialized CMD3G
+pub const FRAME_LEN: usize = 8;
+
+/// Error decoding a CMD3G byte stream
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    /// The stream ends in the middle of a frame starting at this byte offset
+    Truncated { offset: usize },
+    /// The frame at this byte offset has an opcode unknown to CMD3G_OPCODE
+    UnknownOpcode { offset: usize, opcode: u8 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Truncated { offset } => {
+                write!(f, "truncated frame at offset {:#06x}", offset)
+            }
+            DecodeError::UnknownOpcode { offset, opcode } => write!(
+                f,
+                "unknown opcode {:#04x} in frame at offset {:#06x}",
+                opcode, offset
+            ),
+        }
+    }
+}
+impl std::error::Error for DecodeError {}
+
+/// Splits a byte stream into CMD3G frames
+pub fn decode_frames(bytes: &[u8]) -> Result<Vec<CMD3G>, DecodeError> {
+    bytes
+        .chunks(FRAME_LEN)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let offset = i * FRAME_LEN;
+            if chunk.len() < FRAME_LEN {
+                return Err(DecodeError::Truncated { offset });
+            }
+            CMD3G_OPCODE::try_from(chunk[6])
+                .map_err(|opcode| DecodeError::UnknownOpcode { offset, opcode })?;
+            // the opcode is valid and the length right, nothing else can fail
+            Ok(bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .deserialize(chunk)
+                .expect("valid CMD3G frame"))
+        })
+        .collect()
+}
+
+/// What a group of frames decodes to
+#[derive(Debug, PartialEq)]
+pub enum Decoded {
+    Command(ScannerCommand),
+    /// A frame with no ScannerCommand equivalent, e.g. an interrupt or an orphan PARAMS
+    Raw(CMD3G),
+}
+
+/// A group of frames making up one command
+#[derive(Debug, PartialEq)]
+pub struct Instruction {
+    /// Index of the first frame of the group in the stream
+    pub index: usize,
+    /// Number of frames in the group
+    pub len: usize,
+    pub decoded: Decoded,
+}
+
+/// Regroups frames into scanner commands, e.g. an ARCLINE and its PARAMS into an Arc.
+/// A SetTarget is inserted whenever the target byte changes, starting from TARGET,
+/// and NOP frames such as block padding are skipped.
+pub fn disassemble(frames: &[CMD3G]) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut target = TARGET;
+    let mut i = 0;
+    while i < frames.len() {
+        let frame = &frames[i];
+        if frame.op_code() == CMD3G_OPCODE::CMD3G_NOP {
+            i += 1;
+            continue;
+        }
+        if frame.target() != target && !is_interrupt(frame.op_code()) {
+            target = frame.target();
+            instructions.push(Instruction {
+                index: i,
+                len: 0,
+                decoded: Decoded::Command(ScannerCommand::SetTarget(target)),
+            });
+        }
+
+        let params = frames
+            .get(i + 1)
+            .filter(|next| next.op_code() == CMD3G_OPCODE::CMD3G_PARAMS);
+        let (decoded, len) = match (decode_single(frame), params) {
+            (Some(command), _) => (Decoded::Command(command), 1),
+            (None, Some(params)) => match decode_pair(frame, params) {
+                Some(command) => (Decoded::Command(command), 2),
+                None => (Decoded::Raw(frame.clone()), 1),
+            },
+            (None, None) => (Decoded::Raw(frame.clone()), 1),
+        };
+        // a list started on ListClose is followed by INTRUNMODE, which belongs to it
+        let len = match (&decoded, frames.get(i + 1)) {
+            (Decoded::Command(ScannerCommand::ListClose), Some(next))
+                if next.op_code() == CMD3G_OPCODE::INTRUNMODE && next.x() == RUNMODE_START =>
+            {
+                2
+            }
+            _ => len,
+        };
+        instructions.push(Instruction {
+            index: i,
+            len,
+            decoded,
+        });
+        i += len;
+    }
+    instructions
+}
+
+/// Interrupts are addressed independently of the list being built
+fn is_interrupt(op_code: CMD3G_OPCODE) -> bool {
+    u8::from(op_code) & 0x80 != 0 && op_code != CMD3G_OPCODE::CMD3G_EOF
+}
+
+/// Decodes a command made of a single frame
+fn decode_single(frame: &CMD3G) -> Option<ScannerCommand> {
+    let float = f32::from_bits(frame.long());
+    let command = match frame.op_code() {
+        CMD3G_OPCODE::CMD3G_JUMPTO => ScannerCommand::Jump(frame.position()),
+        CMD3G_OPCODE::CMD3G_MOVETO => ScannerCommand::Move(frame.position()),
+        CMD3G_OPCODE::CMD3G_LINETO => ScannerCommand::Line(frame.position()),
+        CMD3G_OPCODE::CMD3G_SETIO => ScannerCommand::SetIO(frame.x(), frame.y()),
+        CMD3G_OPCODE::CMD3G_SETANA => ScannerCommand::SetAnalog(frame.x(), frame.y()),
+        CMD3G_OPCODE::CMD3G_SPEED => ScannerCommand::SetSpeed(float),
+        CMD3G_OPCODE::CMD3G_JUMPSPEED => ScannerCommand::SetJumpSpeed(float),
+        CMD3G_OPCODE::CMD3G_SLEEP => ScannerCommand::Sleep(frame.x()),
+        CMD3G_OPCODE::CMD3G_BURST => ScannerCommand::Burst(frame.x()),
+        CMD3G_OPCODE::CMD3G_SETLIDLE => ScannerCommand::SetLaser(frame.x() != 0),
+        CMD3G_OPCODE::CMD3G_SETDELAYS => ScannerCommand::SetLaserTimes(frame.x(), frame.y()),
+        CMD3G_OPCODE::CMD3G_SETLOOP => ScannerCommand::SetLoop(frame.x()),
+        CMD3G_OPCODE::CMD3G_DOLOOP => ScannerCommand::DoLoop,
+        CMD3G_OPCODE::CMD3G_BRANCH => ScannerCommand::DoWhile,
+        CMD3G_OPCODE::CMD3G_LIST => {
+            ScannerCommand::ListOpen(ListMode::try_from(frame.x() as u32).ok()?)
+        }
+        CMD3G_OPCODE::CMD3G_EOF => ScannerCommand::ListClose,
+        _ => return None,
+    };
+    Some(command)
+}
+
+/// Decodes a command made of a frame followed by a PARAMS frame
+fn decode_pair(frame: &CMD3G, params: &CMD3G) -> Option<ScannerCommand> {
+    let command = match frame.op_code() {
+        // only the upper half of the bulge factor is transmitted
+        CMD3G_OPCODE::CMD3G_ARCLINE => {
+            ScannerCommand::Arc(frame.position(), f32::from_bits((params.x() as u32) << 16))
+        }
+        CMD3G_OPCODE::CMD3G_CIRCLE => {
+            ScannerCommand::Circle(frame.position(), f32::from_bits(params.long()))
+        }
+        CMD3G_OPCODE::CMD3G_IOWAIT => ScannerCommand::WaitIO(frame.x(), frame.y(), params.long()),
+        CMD3G_OPCODE::CMD3G_TABLEWHILEIO => ScannerCommand::WhileIO(frame.x(), frame.y()),
+        _ => return None,
+    };
+    Some(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{build_commandlist, Position};
+    use crate::serialize_commands;
+
+    #[test]
+    fn frames() {
+        let bytes = [0xB0, 0x04, 0x78, 0x05, 0x00, 0x00, 0x04, 0x01];
+        assert_eq!(
+            decode_frames(&bytes).unwrap(),
+            vec![CMD3G::new(1200, 1400, 0, 0, CMD3G_OPCODE::CMD3G_JUMPTO, 1)]
+        );
+        assert_eq!(
+            decode_frames(&bytes[..7]),
+            Err(DecodeError::Truncated { offset: 0 })
+        );
+        let mut bytes = bytes.to_vec();
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x01, 0]);
+        assert_eq!(
+            decode_frames(&bytes),
+            Err(DecodeError::UnknownOpcode {
+                offset: 8,
+                opcode: 0x01
+            })
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        use ScannerCommand::*;
+        let commands = vec![
+            ListOpen(ListMode::CompileAndStart),
+            SetJumpSpeed(1200.0),
+            SetSpeed(600.5),
+            Jump(Position::new(-6.0, -6.0)),
+            Line(Position::new(6.0, 6.0)),
+            Move(Position::new(-1.2, 3.4)),
+            Arc(Position::new(-0.5, 0.5), 360.0),
+            Circle(Position::new(1.0, -2.0), 90.0),
+            SetIO(512, 1024),
+            SetAnalog(1024, 512),
+            WaitIO(1, 3, 70000),
+            Sleep(500),
+            Burst(20),
+            SetLaser(true),
+            SetLaserTimes(100, 200),
+            SetLoop(3),
+            WhileIO(0x10, 0x10),
+            Line(Position::new(1.0, 0.0)),
+            DoWhile,
+            DoLoop,
+            ListClose,
+            SetTarget(2),
+            Sleep(1),
+        ];
+        let mut bytes = serialize_commands(&build_commandlist(&commands));
+        bytes.resize(512, 0);
+        let got = disassemble(&decode_frames(&bytes).unwrap())
+            .into_iter()
+            .map(|i| match i.decoded {
+                Decoded::Command(command) => command,
+                Decoded::Raw(frame) => panic!("undecoded frame {:?}", frame),
+            })
+            .collect::<Vec<ScannerCommand>>();
+        assert_eq!(got, commands);
+    }
+
+    #[test]
+    fn grouping() {
+        let frames = vec![
+            CMD3G::new(0xFE0C, 500, 0xFF, 0, CMD3G_OPCODE::CMD3G_ARCLINE, TARGET),
+            CMD3G::new(0x43B4, 0, 0, 0, CMD3G_OPCODE::CMD3G_PARAMS, TARGET),
+            // ARCLINE missing its PARAMS
+            CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_ARCLINE, TARGET),
+            CMD3G::new(0, 0x2C, 0, 0, CMD3G_OPCODE::INTSTATUS, 0),
+            CMD3G::new(0, 0, 0, 0, CMD3G_OPCODE::CMD3G_NOP, 0),
+        ];
+        let got = disassemble(&frames);
+        assert_eq!(
+            got,
+            vec![
+                Instruction {
+                    index: 0,
+                    len: 2,
+                    decoded: Decoded::Command(ScannerCommand::Arc(Position::new(-0.5, 0.5), 360.0)),
+                },
+                Instruction {
+                    index: 2,
+                    len: 1,
+                    decoded: Decoded::Raw(frames[2].clone()),
+                },
+                Instruction {
+                    index: 3,
+                    len: 1,
+                    decoded: Decoded::Raw(frames[3].clone()),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,335 @@
+use crate::commands::{Position, FLAT_BULGE};
+use crate::parsing::ScannerCommand;
+use std::time::Duration;
+
+/// Execution time and path of a job, as estimated by `Estimator`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Estimate {
+    pub total: Duration,
+    /// Time spent moving with the laser on
+    pub mark_time: Duration,
+    /// Time spent moving with the laser off: jumps and moves
+    pub jump_time: Duration,
+    /// Time spent in sleeps and laser delays
+    pub wait_time: Duration,
+    /// Marking plus bursts
+    pub laser_on: Duration,
+    /// Length marked, in millimetres
+    pub mark_length: f64,
+    /// Length travelled with the laser off, in millimetres
+    pub jump_length: f64,
+    /// Moves made before any speed was set, whose time is unknown and not counted
+    pub untimed: usize,
+    /// WaitIOs counted as immediate and WhileIO loops counted as running once, their
+    /// actual duration depending on the inputs
+    pub input_dependent: usize,
+}
+
+/// Walks scanner commands to estimate how long the target takes to execute them.
+///
+/// Speeds are in mm/s, Sleep and Burst times in ms and laser delays in µs. The on-delay
+/// is spent before each run of marking moves and the off-delay after it. Loops are
+/// unrolled; the scanner starts at the origin unless told otherwise.
+#[derive(Debug, Clone)]
+pub struct Estimator {
+    position: Position,
+    speed: Option<f32>,
+    jump_speed: Option<f32>,
+}
+
+impl Default for Estimator {
+    fn default() -> Estimator {
+        Estimator::new()
+    }
+}
+
+/// State of the walk through the commands
+struct Walk {
+    position: Position,
+    speed: Option<f32>,
+    jump_speed: Option<f32>,
+    /// Laser delays in µs
+    delays: (u16, u16),
+    marking: bool,
+    /// Seconds, summed as floats to avoid rounding each move
+    mark_time: f64,
+    jump_time: f64,
+    wait_time: f64,
+    burst_time: f64,
+    estimate: Estimate,
+}
+
+impl Estimator {
+    pub fn new() -> Estimator {
+        Estimator {
+            position: Position::new(0.0, 0.0),
+            speed: None,
+            jump_speed: None,
+        }
+    }
+
+    /// Starts from `position` rather than from the origin
+    pub fn with_position(mut self, position: Position) -> Estimator {
+        self.position = position;
+        self
+    }
+
+    /// Speeds the target is set to before the job, used until it sets its own
+    pub fn with_speeds(mut self, speed: f32, jump_speed: f32) -> Estimator {
+        self.speed = Some(speed);
+        self.jump_speed = Some(jump_speed);
+        self
+    }
+
+    pub fn estimate(&self, commands: &[ScannerCommand]) -> Estimate {
+        let mut walk = Walk {
+            position: self.position,
+            speed: self.speed,
+            jump_speed: self.jump_speed,
+            delays: (0, 0),
+            marking: false,
+            mark_time: 0.0,
+            jump_time: 0.0,
+            wait_time: 0.0,
+            burst_time: 0.0,
+            estimate: Estimate::default(),
+        };
+        let mut loops = Loops::default();
+        let mut i = 0;
+        while i < commands.len() {
+            match &commands[i] {
+                ScannerCommand::SetLoop(_) | ScannerCommand::DoLoop => {}
+                ScannerCommand::WhileIO(..) => walk.estimate.input_dependent += 1,
+                command => walk.command(command),
+            }
+            i = loops.next(commands, i);
+        }
+        walk.finish()
+    }
+}
+
+/// SetLoop/DoLoop blocks met while walking a command list, run as the target runs them
+#[derive(Debug, Default)]
+pub(crate) struct Loops {
+    /// Index of the first command of each open loop and the repetitions left, innermost last
+    open: Vec<(usize, u16)>,
+}
+
+impl Loops {
+    /// Index of the command run after the one at `i`: back to the start of the loop for a
+    /// DoLoop with repetitions left, past the matching DoLoop for a SetLoop(0), whose
+    /// commands are skipped, and the next one otherwise
+    pub(crate) fn next(&mut self, commands: &[ScannerCommand], i: usize) -> usize {
+        match commands[i] {
+            ScannerCommand::SetLoop(0) => end_of_loop(commands, i) + 1,
+            ScannerCommand::SetLoop(count) => {
+                self.open.push((i + 1, count));
+                i + 1
+            }
+            ScannerCommand::DoLoop => match self.open.last_mut() {
+                Some((start, left)) if *left > 1 => {
+                    *left -= 1;
+                    *start
+                }
+                _ => {
+                    self.open.pop();
+                    i + 1
+                }
+            },
+            _ => i + 1,
+        }
+    }
+}
+
+/// Index of the DoLoop closing the SetLoop at `start`
+fn end_of_loop(commands: &[ScannerCommand], start: usize) -> usize {
+    let mut depth = 0;
+    for (i, command) in commands.iter().enumerate().skip(start) {
+        match command {
+            ScannerCommand::SetLoop(_) => depth += 1,
+            ScannerCommand::DoLoop if depth == 1 => return i,
+            ScannerCommand::DoLoop => depth -= 1,
+            _ => {}
+        }
+    }
+    commands.len()
+}
+
+impl Walk {
+    fn command(&mut self, command: &ScannerCommand) {
+        match command {
+            ScannerCommand::Jump(to) => {
+                let length = self.position.distance(to);
+                self.travel(length, self.jump_speed);
+                self.position = *to;
+            }
+            ScannerCommand::Move(to) => {
+                let length = self.position.distance(to);
+                self.travel(length, self.speed);
+                self.position = *to;
+            }
+            ScannerCommand::Line(to) => {
+                let length = self.position.distance(to);
+                self.mark(length);
+                self.position = *to;
+            }
+            // the target runs a flat arc as MOVETO, with the laser off
+            ScannerCommand::Arc(to, bulge) if bulge.abs() < FLAT_BULGE => {
+                let length = self.position.distance(to);
+                self.travel(length, self.speed);
+                self.position = *to;
+            }
+            ScannerCommand::Arc(to, bulge) => {
+                let length = self.position.arc_length(to, *bulge as f64);
+                self.mark(length);
+                self.position = *to;
+            }
+            ScannerCommand::Circle(center, angle) => {
+                let angle = *angle as f64;
+                let length = self.position.distance(center) * angle.to_radians().abs();
+                self.mark(length);
+                self.position = self.position.rotate(center, angle);
+            }
+            ScannerCommand::SetSpeed(speed) => self.speed = Some(*speed),
+            ScannerCommand::SetJumpSpeed(speed) => self.jump_speed = Some(*speed),
+            ScannerCommand::SetLaserTimes(on_delay, off_delay) => {
+                self.delays = (*on_delay, *off_delay)
+            }
+            ScannerCommand::Sleep(ms) => {
+                self.laser_off();
+                self.wait_time += *ms as f64 / 1e3;
+            }
+            ScannerCommand::Burst(ms) => {
+                self.laser_off();
+                self.burst_time += *ms as f64 / 1e3;
+            }
+            ScannerCommand::WaitIO(..) => {
+                self.laser_off();
+                self.estimate.input_dependent += 1;
+            }
+            _ => {}
+        }
+    }
+
+    /// Moves `length` mm with the laser on
+    fn mark(&mut self, length: f64) {
+        if !self.marking {
+            self.marking = true;
+            self.wait_time += self.delays.0 as f64 / 1e6;
+        }
+        self.estimate.mark_length += length;
+        match self.speed {
+            Some(speed) if speed > 0.0 => self.mark_time += length / speed as f64,
+            _ => self.estimate.untimed += 1,
+        }
+    }
+
+    /// Moves `length` mm with the laser off at `speed`
+    fn travel(&mut self, length: f64, speed: Option<f32>) {
+        self.laser_off();
+        self.estimate.jump_length += length;
+        match speed {
+            Some(speed) if speed > 0.0 => self.jump_time += length / speed as f64,
+            _ => self.estimate.untimed += 1,
+        }
+    }
+
+    fn laser_off(&mut self) {
+        if self.marking {
+            self.marking = false;
+            self.wait_time += self.delays.1 as f64 / 1e6;
+        }
+    }
+
+    fn finish(mut self) -> Estimate {
+        self.laser_off();
+        let seconds = Duration::from_secs_f64;
+        let estimate = &mut self.estimate;
+        estimate.mark_time = seconds(self.mark_time);
+        estimate.jump_time = seconds(self.jump_time);
+        estimate.wait_time = seconds(self.wait_time);
+        estimate.laser_on = seconds(self.mark_time + self.burst_time);
+        estimate.total =
+            seconds(self.mark_time + self.jump_time + self.wait_time + self.burst_time);
+        self.estimate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_script;
+    use std::f64::consts::PI;
+
+    fn estimate(script: &str) -> Estimate {
+        Estimator::new().estimate(&parse_script(script).unwrap())
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn times() {
+        let e = estimate(
+            "rtSetJumpSpeed(1000); rtSetSpeed(100); rtSetLaserTimes(100, 200);
+             rtJumpTo(3, 4); rtLineTo(3, 14); rtLineTo(13, 14); rtJumpTo(0, 0);
+             rtSleep(250); rtBurst(50);",
+        );
+        assert!(close(e.jump_length, 5.0 + 13.0_f64.hypot(14.0)));
+        assert!(close(e.mark_length, 20.0));
+        assert_eq!(e.mark_time, Duration::from_millis(200));
+        assert_eq!(e.laser_on, Duration::from_millis(250));
+        // one run of marking moves: 100 µs before it, 200 µs after it
+        assert_eq!(e.wait_time, Duration::from_micros(250_300));
+        let jumps = e.jump_length / 1000.0;
+        assert!(close(e.total.as_secs_f64(), 0.2 + jumps + 0.2503 + 0.05));
+        assert_eq!((e.untimed, e.input_dependent), (0, 0));
+    }
+
+    #[test]
+    fn curves_and_loops() {
+        // a half circle of radius 5 and a quarter circle of radius 10, then a flat arc the
+        // target runs as a move
+        let e = estimate(
+            "rtSetSpeed(10); rtSetJumpSpeed(100); rtJumpTo(-5, 0); rtArcTo(5, 0, 1);
+             rtCircle(5, 10, 90); rtArcTo(15, 20, 0);",
+        );
+        let expected = 5.0 * PI + 10.0 * PI / 2.0;
+        assert!(close(e.mark_length, expected));
+        assert!(close(e.mark_time.as_secs_f64(), expected / 10.0));
+        assert!(close(e.jump_length, 5.0 + 10.0));
+        assert!(close(e.jump_time.as_secs_f64(), 5.0 / 100.0 + 10.0 / 10.0));
+
+        let e = estimate(
+            "rtSetSpeed(10); rtSetLoop(3); rtSetLoop(2); rtLineTo(1, 0); rtLineTo(0, 0);
+             rtDoLoop(); rtSleep(10); rtDoLoop();",
+        );
+        assert!(close(e.mark_length, 12.0));
+        assert_eq!(e.wait_time, Duration::from_millis(30));
+
+        // a loop repeated 0 times is skipped, along with the loops inside it
+        let e = estimate(
+            "rtSetSpeed(10); rtSetLoop(0); rtSetLoop(2); rtLineTo(1, 0); rtDoLoop();
+             rtSleep(10); rtDoLoop(); rtSetLoop(1); rtSleep(5); rtDoLoop();",
+        );
+        assert_eq!(e.mark_length, 0.0);
+        assert_eq!(e.wait_time, Duration::from_millis(5));
+    }
+
+    #[test]
+    fn unknowns() {
+        let e = estimate(
+            "rtLineTo(1, 0); rtWaitIO(1, 1, 0); rtWhileIO(1, 1); rtSleep(5); rtDoWhile();",
+        );
+        assert_eq!(e.untimed, 1);
+        assert_eq!(e.input_dependent, 2);
+        assert_eq!(e.total, Duration::from_millis(5));
+
+        let e = Estimator::new()
+            .with_speeds(10.0, 100.0)
+            .with_position(Position::new(1.0, 0.0))
+            .estimate(&parse_script("rtLineTo(1, 2);").unwrap());
+        assert_eq!((e.untimed, e.mark_time), (0, Duration::from_millis(200)));
+    }
+}
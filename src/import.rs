@@ -0,0 +1,614 @@
+use crate::commands::{Position, FLAT_BULGE};
+use crate::parsing::ScannerCommand;
+use std::collections::HashMap;
+use std::fmt;
+
+mod dxf;
+mod gcode;
+mod hpgl;
+mod svg;
+
+pub use self::dxf::import_dxf;
+pub use self::gcode::import_gcode;
+pub use self::hpgl::import_hpgl;
+pub use self::svg::import_svg;
+
+/// Error reading a drawing
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportError {
+    /// Line of the drawing the error is on, if known
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl ImportError {
+    pub fn new<M: Into<String>>(line: Option<usize>, message: M) -> ImportError {
+        ImportError {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+impl std::error::Error for ImportError {}
+
+/// How a drawing is turned into scanner commands
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    tolerance: f64,
+    field: Option<f64>,
+    speeds: Option<(f32, f32)>,
+    defaults: Parameters,
+    plotter_unit: f64,
+    layers: HashMap<String, Parameters>,
+}
+
+impl Default for ImportOptions {
+    fn default() -> ImportOptions {
+        ImportOptions {
+            tolerance: 0.01,
+            field: None,
+            speeds: None,
+            defaults: Parameters::default(),
+            plotter_unit: 0.025,
+            layers: HashMap::new(),
+        }
+    }
+}
+
+impl ImportOptions {
+    pub fn new() -> ImportOptions {
+        ImportOptions::default()
+    }
+    /// Largest distance in millimetres between a curve and the lines replacing it
+    pub fn with_tolerance(mut self, tolerance: f64) -> ImportOptions {
+        self.tolerance = tolerance.max(1e-6);
+        self
+    }
+    /// Centres the drawing on the origin, scaling it down to fit a square field of
+    /// `size` millimetres if it is larger
+    pub fn with_field(mut self, size: f64) -> ImportOptions {
+        self.field = Some(size);
+        self
+    }
+    /// Starts the commands with a SetJumpSpeed, in mm/s, and marks at `speed` the figures
+    /// whose layer sets no speed of its own
+    pub fn with_speeds(mut self, speed: f32, jump_speed: f32) -> ImportOptions {
+        self.speeds = Some((speed, jump_speed));
+        self
+    }
+    /// Millimetres per HPGL plotter unit, 0.025 by default
+    pub fn with_plotter_unit(mut self, unit: f64) -> ImportOptions {
+        self.plotter_unit = unit;
+        self
+    }
+    /// Parameters of the figures whose layer does not set them, `with_speeds` giving the
+    /// default speed otherwise
+    pub fn with_defaults(mut self, parameters: Parameters) -> ImportOptions {
+        self.defaults = parameters;
+        self
+    }
+    /// Marks the figures of `layer` with `parameters`
+    pub fn with_layer(mut self, layer: &str, parameters: Parameters) -> ImportOptions {
+        self.layers.insert(layer.to_string(), parameters);
+        self
+    }
+    pub fn tolerance(&self) -> f64 {
+        self.tolerance
+    }
+
+    /// SetJumpSpeed starting the commands, if `with_speeds` was given
+    fn jump_speed(&self) -> Option<ScannerCommand> {
+        self.speeds
+            .map(|(_, jump_speed)| ScannerCommand::SetJumpSpeed(jump_speed))
+    }
+
+    /// Default parameters, completed by the speed of `with_speeds`
+    fn defaults(&self) -> Parameters {
+        let speed = self.speeds.map(|(speed, _)| speed);
+        Parameters {
+            speed: self.defaults.speed.or(speed),
+            laser_times: self.defaults.laser_times,
+        }
+    }
+}
+
+/// Speed and laser delays of the figures of a layer, set before marking them
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Parameters {
+    speed: Option<f32>,
+    laser_times: Option<(u16, u16)>,
+}
+
+impl Parameters {
+    pub fn new() -> Parameters {
+        Parameters::default()
+    }
+    /// Marking speed in mm/s
+    pub fn with_speed(mut self, speed: f32) -> Parameters {
+        self.speed = Some(speed);
+        self
+    }
+    /// Laser on and off delays in µs
+    pub fn with_laser_times(mut self, on_delay: u16, off_delay: u16) -> Parameters {
+        self.laser_times = Some((on_delay, off_delay));
+        self
+    }
+    /// `self`, completed by `defaults` where unset
+    fn or(&self, defaults: &Parameters) -> Parameters {
+        Parameters {
+            speed: self.speed.or(defaults.speed),
+            laser_times: self.laser_times.or(defaults.laser_times),
+        }
+    }
+    fn commands(&self) -> impl Iterator<Item = ScannerCommand> {
+        let speed = self.speed.map(ScannerCommand::SetSpeed);
+        let times = self
+            .laser_times
+            .map(|(on, off)| ScannerCommand::SetLaserTimes(on, off));
+        speed.into_iter().chain(times)
+    }
+}
+
+/// Piece of a figure, from the end of the previous one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Segment {
+    Line(Position),
+    /// Arc to a position, its bulge being the tangent of a quarter of its included angle,
+    /// positive counterclockwise
+    Arc(Position, f64),
+    /// Rotation around a centre by an angle in degrees, positive counterclockwise
+    Circle(Position, f64),
+}
+
+/// Connected segments, drawn without lifting the laser
+#[derive(Debug, Clone, PartialEq)]
+pub struct Figure {
+    pub start: Position,
+    pub segments: Vec<Segment>,
+    pub layer: Option<String>,
+}
+
+/// Shapes of an imported drawing, in millimetres with y pointing up
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Drawing {
+    pub figures: Vec<Figure>,
+    layer: Option<String>,
+}
+
+impl Drawing {
+    pub fn new() -> Drawing {
+        Drawing::default()
+    }
+
+    /// Layer of the figures started from now on
+    pub fn set_layer(&mut self, layer: Option<&str>) {
+        self.layer = layer.map(str::to_string);
+    }
+
+    /// Starts a new figure
+    pub fn move_to(&mut self, position: Position) {
+        self.figures.push(Figure {
+            start: position,
+            segments: Vec::new(),
+            layer: self.layer.clone(),
+        });
+    }
+
+    /// Goes on with the current figure if it ends at `position` on the current layer,
+    /// starts a new one otherwise
+    pub fn continue_at(&mut self, position: Position) {
+        let joined = match (self.figures.last(), self.position()) {
+            (Some(figure), Some(end)) => figure.layer == self.layer && near(&end, &position),
+            _ => false,
+        };
+        if !joined {
+            self.move_to(position);
+        }
+    }
+
+    /// Adds a segment to the current figure, starting one at the origin if there is none
+    pub fn add(&mut self, segment: Segment) {
+        if self.figures.is_empty() {
+            self.move_to(Position::new(0.0, 0.0));
+        }
+        let figure = self.figures.last_mut().unwrap();
+        figure.segments.push(segment);
+    }
+
+    pub fn line_to(&mut self, position: Position) {
+        self.add(Segment::Line(position));
+    }
+
+    /// Current position: the end of the last segment of the current figure
+    pub fn position(&self) -> Option<Position> {
+        let figure = self.figures.last()?;
+        Some(figure.segments.iter().fold(figure.start, end))
+    }
+
+    /// Closes the current figure with a line back to its start, if not there already
+    pub fn close(&mut self) {
+        if let (Some(figure), Some(position)) = (self.figures.last(), self.position()) {
+            let start = figure.start;
+            if !figure.segments.is_empty() && !near(&position, &start) {
+                self.line_to(start);
+            }
+        }
+    }
+
+    /// Smallest and largest corners of the box holding the drawing, arcs included
+    pub fn bounds(&self) -> Option<(Position, Position)> {
+        let mut points = Vec::new();
+        for figure in &self.figures {
+            points.push(figure.start);
+            let mut position = figure.start;
+            for segment in &figure.segments {
+                points.extend(sample(&position, segment));
+                position = end(position, segment);
+            }
+        }
+        Position::bounds(&points)
+    }
+
+    /// Scales the drawing by `scale` around the origin, then moves it by `offset`
+    pub fn scale(&mut self, scale: f64, offset: Position) {
+        let map =
+            |p: &Position| Position::new(p.x() * scale + offset.x(), p.y() * scale + offset.y());
+        for figure in &mut self.figures {
+            figure.start = map(&figure.start);
+            for segment in &mut figure.segments {
+                *segment = match *segment {
+                    Segment::Line(to) => Segment::Line(map(&to)),
+                    Segment::Arc(to, bulge) => Segment::Arc(map(&to), bulge),
+                    Segment::Circle(center, angle) => Segment::Circle(map(&center), angle),
+                };
+            }
+        }
+    }
+
+    /// Turns the drawing into commands: a Jump to the start of each figure, then its
+    /// segments as Lines, Arcs and Circles.
+    ///
+    /// Whenever the parameters change from one figure to the next, all of them are set
+    /// again, so that no figure inherits those of another layer. This fails if a layer sets
+    /// a parameter that has no default to restore for the figures after it.
+    pub fn to_commands(&self, options: &ImportOptions) -> Result<Vec<ScannerCommand>, ImportError> {
+        let mut drawing = self.clone();
+        if let (Some(field), Some(bounds)) = (options.field, self.bounds()) {
+            let (scale, offset) = fit(field, bounds);
+            drawing.scale(scale, offset);
+        }
+        let mut commands: Vec<_> = options.jump_speed().into_iter().collect();
+        let defaults = options.defaults();
+        let mut current = Parameters::new();
+        for figure in drawing.figures.iter().filter(|f| !f.segments.is_empty()) {
+            let layer = figure.layer.as_ref().and_then(|l| options.layers.get(l));
+            let parameters = layer.map_or(defaults, |layer| layer.or(&defaults));
+            let unset = |what: &str| {
+                let layer = figure.layer.as_deref().unwrap_or("");
+                let message = format!(
+                    "no default {} for the figures on layer `{}` after ones with their own",
+                    what, layer
+                );
+                ImportError::new(None, message)
+            };
+            if parameters.speed.is_none() && current.speed.is_some() {
+                return Err(unset("speed"));
+            }
+            if parameters.laser_times.is_none() && current.laser_times.is_some() {
+                return Err(unset("laser times"));
+            }
+            if parameters != current {
+                commands.extend(parameters.commands());
+                current = parameters;
+            }
+            commands.push(ScannerCommand::Jump(figure.start));
+            commands.extend(figure.segments.iter().map(|segment| match *segment {
+                Segment::Line(to) => ScannerCommand::Line(to),
+                Segment::Arc(to, bulge) => arc(to, bulge),
+                Segment::Circle(center, angle) => ScannerCommand::Circle(center, angle as f32),
+            }));
+        }
+        Ok(commands)
+    }
+}
+
+/// Affine transform mapping (x, y) to (a x + c y + e, b x + d y + f), as in SVG
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Affine {
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+    pub d: f64,
+    pub e: f64,
+    pub f: f64,
+}
+
+impl Default for Affine {
+    fn default() -> Affine {
+        Affine::new(1.0, 0.0, 0.0, 1.0, 0.0, 0.0)
+    }
+}
+
+impl Affine {
+    pub fn new(a: f64, b: f64, c: f64, d: f64, e: f64, f: f64) -> Affine {
+        Affine { a, b, c, d, e, f }
+    }
+
+    pub fn translate(x: f64, y: f64) -> Affine {
+        Affine::new(1.0, 0.0, 0.0, 1.0, x, y)
+    }
+
+    pub fn scale(x: f64, y: f64) -> Affine {
+        Affine::new(x, 0.0, 0.0, y, 0.0, 0.0)
+    }
+
+    /// Rotation by `angle` degrees, counterclockwise with y pointing up
+    pub fn rotate(angle: f64) -> Affine {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        Affine::new(cos, sin, -sin, cos, 0.0, 0.0)
+    }
+
+    /// `self` applied first, then `outer`
+    pub fn then(&self, outer: &Affine) -> Affine {
+        Affine {
+            a: outer.a * self.a + outer.c * self.b,
+            b: outer.b * self.a + outer.d * self.b,
+            c: outer.a * self.c + outer.c * self.d,
+            d: outer.b * self.c + outer.d * self.d,
+            e: outer.a * self.e + outer.c * self.f + outer.e,
+            f: outer.b * self.e + outer.d * self.f + outer.f,
+        }
+    }
+
+    pub fn apply(&self, x: f64, y: f64) -> Position {
+        Position::new(
+            self.a * x + self.c * y + self.e,
+            self.b * x + self.d * y + self.f,
+        )
+    }
+
+    pub fn determinant(&self) -> f64 {
+        self.a * self.d - self.b * self.c
+    }
+
+    /// Whether the transform turns circles into circles: rotations, mirrors and
+    /// uniform scales
+    pub fn is_similarity(&self) -> bool {
+        let (x, y) = (self.a.hypot(self.b), self.c.hypot(self.d));
+        let eps = 1e-9 * x.max(y);
+        (x - y).abs() <= eps && (self.a * self.c + self.b * self.d).abs() <= eps
+    }
+
+    /// Scale of a similarity; the geometric mean of the scales otherwise
+    pub fn scale_factor(&self) -> f64 {
+        self.determinant().abs().sqrt()
+    }
+}
+
+fn near(a: &Position, b: &Position) -> bool {
+    (a.x() - b.x()).abs() < 1e-9 && (a.y() - b.y()).abs() < 1e-9
+}
+
+/// End of `segment` drawn from `from`
+/// Arc command to `to`, or a line when the target would take the arc for a move
+fn arc(to: Position, bulge: f64) -> ScannerCommand {
+    if bulge.abs() < FLAT_BULGE as f64 {
+        ScannerCommand::Line(to)
+    } else {
+        ScannerCommand::Arc(to, bulge as f32)
+    }
+}
+
+fn end(from: Position, segment: &Segment) -> Position {
+    match *segment {
+        Segment::Line(to) | Segment::Arc(to, _) => to,
+        Segment::Circle(center, angle) => from.rotate(&center, angle),
+    }
+}
+
+/// Scale and offset centring the box `bounds` on the origin, scaled down to fit a square
+/// field of `field` millimetres if it is larger
+fn fit(field: f64, (min, max): (Position, Position)) -> (f64, Position) {
+    let size = (max.x() - min.x()).max(max.y() - min.y());
+    let scale = if size > field { field / size } else { 1.0 };
+    let center = Position::new((min.x() + max.x()) / 2.0, (min.y() + max.y()) / 2.0);
+    (
+        scale,
+        Position::new(-center.x() * scale, -center.y() * scale),
+    )
+}
+
+/// Points along `segment`, every few degrees for curves
+fn sample(from: &Position, segment: &Segment) -> Vec<Position> {
+    let (center, angle) = match *segment {
+        Segment::Line(to) => return vec![to],
+        Segment::Arc(to, bulge) => match from.arc_center(&to, bulge) {
+            Some(center) => (center, 4.0 * bulge.atan().to_degrees()),
+            None => return vec![to],
+        },
+        Segment::Circle(center, angle) => (center, angle),
+    };
+    let steps = ((angle.abs() / 5.0).ceil() as usize).max(1);
+    (1..=steps)
+        .map(|i| from.rotate(&center, angle * i as f64 / steps as f64))
+        .collect()
+}
+
+/// Flattens a cubic Bézier curve into line ends, within `tolerance` of the curve
+pub fn flatten_cubic(points: [Position; 4], tolerance: f64) -> Vec<Position> {
+    let [p0, p1, p2, p3] = points;
+    // Wang's formula: the second differences bound the distance to the chords
+    let second = |a: &Position, b: &Position, c: &Position| {
+        (a.x() - 2.0 * b.x() + c.x()).hypot(a.y() - 2.0 * b.y() + c.y())
+    };
+    let m = second(&p0, &p1, &p2).max(second(&p1, &p2, &p3));
+    let steps = ((0.75 * m / tolerance).sqrt().ceil() as usize).max(1);
+    (1..=steps)
+        .map(|i| {
+            let t = i as f64 / steps as f64;
+            let u = 1.0 - t;
+            let (w0, w1, w2, w3) = (u * u * u, 3.0 * u * u * t, 3.0 * u * t * t, t * t * t);
+            Position::new(
+                w0 * p0.x() + w1 * p1.x() + w2 * p2.x() + w3 * p3.x(),
+                w0 * p0.y() + w1 * p1.y() + w2 * p2.y() + w3 * p3.y(),
+            )
+        })
+        .collect()
+}
+
+/// Flattens a quadratic Bézier curve, see `flatten_cubic`
+pub fn flatten_quadratic(points: [Position; 3], tolerance: f64) -> Vec<Position> {
+    let [p0, p1, p2] = points;
+    // the same curve raised to a cubic
+    let lerp = |a: &Position, b: &Position| {
+        Position::new(
+            a.x() + (b.x() - a.x()) * 2.0 / 3.0,
+            a.y() + (b.y() - a.y()) * 2.0 / 3.0,
+        )
+    };
+    flatten_cubic([p0, lerp(&p0, &p1), lerp(&p2, &p1), p2], tolerance)
+}
+
+/// Number of lines replacing an arc of `radius` and `angle` degrees within `tolerance`
+pub fn arc_steps(radius: f64, angle: f64, tolerance: f64) -> usize {
+    let step = if tolerance < radius {
+        2.0 * (1.0 - tolerance / radius).acos().to_degrees()
+    } else {
+        90.0
+    };
+    ((angle.abs() / step.max(0.1)).ceil() as usize).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drawing() {
+        let mut drawing = Drawing::new();
+        drawing.move_to(Position::new(0.0, 0.0));
+        drawing.line_to(Position::new(10.0, 0.0));
+        drawing.add(Segment::Arc(Position::new(10.0, 10.0), 1.0));
+        drawing.close();
+        drawing.move_to(Position::new(30.0, 0.0));
+        drawing.add(Segment::Circle(Position::new(25.0, 0.0), 360.0));
+        // an arc bulging right of the chord, to x = 15
+        let (min, max) = drawing.bounds().unwrap();
+        assert!(near(&min, &Position::new(0.0, -5.0)));
+        assert!(near(&max, &Position::new(30.0, 10.0)));
+
+        let options = ImportOptions::new()
+            .with_field(15.0)
+            .with_speeds(100.0, 1000.0);
+        let commands = drawing.to_commands(&options).unwrap();
+        assert_eq!(commands.len(), 2 + 4 + 2);
+        assert_eq!(
+            commands[2],
+            ScannerCommand::Jump(Position::new(-7.5, -1.25))
+        );
+        assert_eq!(
+            commands[4],
+            ScannerCommand::Arc(Position::new(-2.5, 3.75), 1.0)
+        );
+        assert_eq!(
+            commands[7],
+            ScannerCommand::Circle(Position::new(5.0, -1.25), 360.0)
+        );
+
+        // float noise in a bulge must not turn the stroke into a move
+        let mut drawing = Drawing::new();
+        drawing.move_to(Position::new(0.0, 0.0));
+        drawing.add(Segment::Arc(Position::new(10.0, 0.0), 1e-16));
+        let commands = drawing.to_commands(&ImportOptions::new()).unwrap();
+        assert_eq!(
+            commands.last(),
+            Some(&ScannerCommand::Line(Position::new(10.0, 0.0)))
+        );
+    }
+
+    #[test]
+    fn layers() {
+        use ScannerCommand::{Jump, Line, SetJumpSpeed, SetLaserTimes, SetSpeed};
+        let p = |x| Position::new(x, 0.0);
+        let mut drawing = Drawing::new();
+        for (i, layer) in [Some("CUT"), Some("0"), None, Some("ENGRAVE")]
+            .iter()
+            .enumerate()
+        {
+            drawing.set_layer(*layer);
+            drawing.move_to(p(i as f64));
+            drawing.line_to(p(i as f64 + 0.5));
+        }
+        let options = ImportOptions::new()
+            .with_layer(
+                "CUT",
+                Parameters::new().with_speed(50.0).with_laser_times(1, 2),
+            )
+            .with_layer("ENGRAVE", Parameters::new().with_laser_times(10, 20));
+        let error = drawing.to_commands(&options).unwrap_err();
+        assert_eq!(
+            error.message,
+            "no default speed for the figures on layer `0` after ones with their own"
+        );
+
+        let options = options
+            .with_speeds(100.0, 1000.0)
+            .with_defaults(Parameters::new().with_laser_times(3, 4));
+        assert_eq!(
+            drawing.to_commands(&options).unwrap(),
+            [
+                SetJumpSpeed(1000.0),
+                SetSpeed(50.0),
+                SetLaserTimes(1, 2),
+                Jump(p(0.0)),
+                Line(p(0.5)),
+                SetSpeed(100.0),
+                SetLaserTimes(3, 4),
+                Jump(p(1.0)),
+                Line(p(1.5)),
+                Jump(p(2.0)),
+                Line(p(2.5)),
+                SetSpeed(100.0),
+                SetLaserTimes(10, 20),
+                Jump(p(3.0)),
+                Line(p(3.5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn transforms() {
+        let t = Affine::translate(1.0, 2.0).then(&Affine::rotate(90.0));
+        assert!(near(&t.apply(1.0, 0.0), &Position::new(-2.0, 2.0)));
+        assert!(t.is_similarity());
+        let mirror = Affine::scale(2.0, -2.0);
+        assert!(mirror.is_similarity() && mirror.determinant() < 0.0);
+        assert!(!Affine::scale(1.0, 2.0).is_similarity());
+        assert!(!Affine::new(1.0, 0.0, 0.5, 1.0, 0.0, 0.0).is_similarity());
+    }
+
+    #[test]
+    fn flattening() {
+        let p = |x, y| Position::new(x, y);
+        // a quarter circle approximation stays within tolerance of its chords
+        let k = 0.552_284_749_8;
+        let points = flatten_cubic([p(1.0, 0.0), p(1.0, k), p(k, 1.0), p(0.0, 1.0)], 0.001);
+        assert!(near(points.last().unwrap(), &p(0.0, 1.0)));
+        assert!(points.len() > 4 && points.len() < 40);
+        assert_eq!(
+            flatten_cubic([p(0.0, 0.0), p(1.0, 0.0), p(2.0, 0.0), p(3.0, 0.0)], 0.1).len(),
+            1
+        );
+        assert_eq!(arc_steps(10.0, 90.0, 100.0), 1);
+        assert!(arc_steps(10.0, 360.0, 0.01) > arc_steps(10.0, 360.0, 0.1));
+    }
+}
@@ -0,0 +1,595 @@
+use super::{Drawing, ImportError, ImportOptions, Segment};
+use crate::commands::Position;
+use crate::parsing::ScannerCommand;
+
+/// Group code and value, the lines of a DXF file going by two
+struct Pair<'a> {
+    code: i32,
+    value: &'a str,
+    /// Line of the value
+    line: usize,
+}
+
+impl<'a> Pair<'a> {
+    fn number(&self) -> Result<f64, ImportError> {
+        self.value.parse().map_err(|_| {
+            let message = format!(
+                "invalid value `{}` for group code {}",
+                self.value, self.code
+            );
+            ImportError::new(Some(self.line), message)
+        })
+    }
+}
+
+/// Entity from its `0` group to the next one
+struct Entity<'a> {
+    kind: &'a str,
+    line: usize,
+    pairs: &'a [Pair<'a>],
+}
+
+impl<'a> Entity<'a> {
+    fn value(&self, code: i32) -> Option<&'a Pair<'a>> {
+        self.pairs.iter().find(|pair| pair.code == code)
+    }
+
+    fn number(&self, code: i32) -> Result<f64, ImportError> {
+        self.value(code).map_or(Ok(0.0), Pair::number)
+    }
+
+    fn numbers(&self, code: i32) -> Result<Vec<f64>, ImportError> {
+        self.pairs
+            .iter()
+            .filter(|pair| pair.code == code)
+            .map(Pair::number)
+            .collect()
+    }
+
+    fn flags(&self) -> Result<u32, ImportError> {
+        Ok(self.number(70)? as u32)
+    }
+
+    fn point(&self, x: i32, y: i32) -> Result<Position, ImportError> {
+        Ok(Position::new(self.number(x)?, self.number(y)?))
+    }
+
+    /// Points given as `x` and `y` groups, each `x` starting a new one
+    fn points(&self, x: i32, y: i32) -> Result<Vec<Position>, ImportError> {
+        let mut points: Vec<Position> = Vec::new();
+        for pair in self.pairs {
+            if pair.code == x {
+                points.push(Position::new(pair.number()?, 0.0));
+            } else if let (true, Some(point)) = (pair.code == y, points.last_mut()) {
+                *point = Position::new(point.x(), pair.number()?);
+            }
+        }
+        Ok(points)
+    }
+
+    /// -1 if the extrusion direction points down, mirroring the x axis of the object
+    /// coordinate system of arcs, circles and polylines; 1 otherwise
+    fn mirror(&self) -> Result<f64, ImportError> {
+        let z = self.value(230).map_or(Ok(1.0), Pair::number)?;
+        Ok(if z < 0.0 { -1.0 } else { 1.0 })
+    }
+
+    fn error(&self, message: &str) -> ImportError {
+        ImportError::new(Some(self.line), format!("{}: {}", self.kind, message))
+    }
+}
+
+/// Converts the LINE, LWPOLYLINE, POLYLINE, ARC, CIRCLE and SPLINE entities of an ASCII
+/// DXF file into commands, in millimetres.
+///
+/// Drawings are scaled from the units of `$INSUNITS`, millimetres if unset. Polyline
+/// bulges and arcs become `ScannerCommand::Arc`s and circles `ScannerCommand::Circle`s;
+/// splines are flattened within the tolerance of `options`. Entities that follow each
+/// other on the same layer are marked without a jump. Figures take the layer of their
+/// entities, whose parameters are set from `options`. Other entities, such as text,
+/// hatches and block references, are ignored.
+pub fn import_dxf(text: &str, options: &ImportOptions) -> Result<Vec<ScannerCommand>, ImportError> {
+    let pairs = pairs(text)?;
+    let mut units = 1.0;
+    let mut entities = Vec::new();
+    for (name, section) in sections(&pairs) {
+        match name {
+            "HEADER" => units = header_units(section)?,
+            "ENTITIES" => entities = split(section),
+            _ => {}
+        }
+    }
+
+    let mut drawing = Drawing::new();
+    let tolerance = options.tolerance() / units;
+    let mut i = 0;
+    while i < entities.len() {
+        let entity = &entities[i];
+        drawing.set_layer(entity.value(8).map(|pair| pair.value));
+        match entity.kind {
+            "LINE" => {
+                drawing.continue_at(entity.point(10, 20)?);
+                drawing.line_to(entity.point(11, 21)?);
+            }
+            "LWPOLYLINE" => {
+                let points = entity.points(10, 20)?;
+                // the bulge of a vertex is the one of the segment starting there
+                let mut bulges = vec![0.0; points.len()];
+                let mut vertex = None;
+                for pair in entity.pairs {
+                    match pair.code {
+                        10 => vertex = Some(vertex.map_or(0, |v| v + 1)),
+                        42 => {
+                            if let Some(v) = vertex {
+                                bulges[v] = pair.number()?;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                let closed = entity.flags()? & 1 != 0;
+                polyline(&mut drawing, &points, &bulges, closed, entity.mirror()?);
+            }
+            "POLYLINE" => {
+                let (mut points, mut bulges) = (Vec::new(), Vec::new());
+                while i + 1 < entities.len() && entities[i + 1].kind == "VERTEX" {
+                    i += 1;
+                    let vertex = &entities[i];
+                    // spline frame control points are not on the curve
+                    if vertex.flags()? & 16 == 0 {
+                        points.push(vertex.point(10, 20)?);
+                        bulges.push(vertex.number(42)?);
+                    }
+                }
+                let flags = entity.flags()?;
+                // polygon and polyface meshes are not outlines
+                if flags & (16 | 64) == 0 {
+                    let closed = flags & 1 != 0;
+                    polyline(&mut drawing, &points, &bulges, closed, entity.mirror()?);
+                }
+            }
+            "ARC" | "CIRCLE" => {
+                let mirror = entity.mirror()?;
+                let center = entity.point(10, 20)?;
+                let radius = entity.number(40)?;
+                let at = |angle: f64| {
+                    let (sin, cos) = angle.to_radians().sin_cos();
+                    let (x, y) = (center.x() + radius * cos, center.y() + radius * sin);
+                    Position::new(x * mirror, y)
+                };
+                let (start, end) = match entity.kind {
+                    "ARC" => (entity.number(50)?, entity.number(51)?),
+                    _ => (0.0, 360.0),
+                };
+                let sweep = (end - start).rem_euclid(360.0);
+                drawing.continue_at(at(start));
+                if sweep < 1e-9 || entity.kind == "CIRCLE" {
+                    let center = Position::new(center.x() * mirror, center.y());
+                    drawing.add(Segment::Circle(center, 360.0 * mirror));
+                } else {
+                    let bulge = (sweep.to_radians() / 4.0).tan();
+                    drawing.add(Segment::Arc(at(end), bulge * mirror));
+                }
+            }
+            "SPLINE" => {
+                let points = Spline::from_entity(entity)?
+                    .map(|spline| spline.flatten(tolerance))
+                    .unwrap_or(entity.points(11, 21)?);
+                if points.len() < 2 {
+                    return Err(entity.error("no valid control or fit points"));
+                }
+                drawing.continue_at(points[0]);
+                for point in &points[1..] {
+                    drawing.line_to(*point);
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    drawing.scale(units, Position::new(0.0, 0.0));
+    drawing.to_commands(options)
+}
+
+fn pairs(text: &str) -> Result<Vec<Pair<'_>>, ImportError> {
+    let mut lines = text.lines().enumerate();
+    let mut pairs = Vec::new();
+    while let Some((i, code)) = lines.next() {
+        let code = code.trim();
+        if code.is_empty() {
+            continue;
+        }
+        let code = code
+            .parse()
+            .map_err(|_| ImportError::new(Some(i + 1), format!("invalid group code `{}`", code)))?;
+        let (j, value) = lines
+            .next()
+            .ok_or_else(|| ImportError::new(Some(i + 1), "group code without a value"))?;
+        let value = value.trim();
+        pairs.push(Pair {
+            code,
+            value,
+            line: j + 1,
+        });
+        if code == 0 && value == "EOF" {
+            break;
+        }
+    }
+    Ok(pairs)
+}
+
+/// Name and groups of each section
+fn sections<'a>(pairs: &'a [Pair<'a>]) -> Vec<(&'a str, &'a [Pair<'a>])> {
+    let mut sections = Vec::new();
+    let mut i = 0;
+    while i < pairs.len() {
+        if (pairs[i].code, pairs[i].value) == (0, "SECTION") && i + 1 < pairs.len() {
+            let start = i + 2;
+            let len = pairs[start..]
+                .iter()
+                .position(|pair| (pair.code, pair.value) == (0, "ENDSEC"))
+                .unwrap_or(pairs.len() - start);
+            sections.push((pairs[i + 1].value, &pairs[start..start + len]));
+            i = start + len;
+        }
+        i += 1;
+    }
+    sections
+}
+
+/// Millimetres per drawing unit, from the `$INSUNITS` header variable
+fn header_units(header: &[Pair]) -> Result<f64, ImportError> {
+    let variable = header
+        .iter()
+        .position(|pair| (pair.code, pair.value) == (9, "$INSUNITS"));
+    let code = match variable.and_then(|i| header.get(i + 1)) {
+        Some(pair) => pair.number()? as u32,
+        None => 0,
+    };
+    Ok(match code {
+        1 => 25.4,
+        2 => 304.8,
+        5 => 10.0,
+        6 => 1000.0,
+        9 => 0.0254,
+        10 => 914.4,
+        13 => 0.001,
+        14 => 100.0,
+        // unitless or millimetres, or too large or small to mark
+        _ => 1.0,
+    })
+}
+
+fn split<'a>(pairs: &'a [Pair<'a>]) -> Vec<Entity<'a>> {
+    let starts: Vec<usize> = (0..pairs.len()).filter(|i| pairs[*i].code == 0).collect();
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, start)| {
+            let end = starts.get(n + 1).copied().unwrap_or(pairs.len());
+            Entity {
+                kind: pairs[*start].value,
+                line: pairs[*start].line,
+                pairs: &pairs[start + 1..end],
+            }
+        })
+        .collect()
+}
+
+/// Draws polyline vertices, each segment an arc if the vertex it starts from has a bulge
+fn polyline(drawing: &mut Drawing, points: &[Position], bulges: &[f64], closed: bool, mirror: f64) {
+    let map = |p: &Position| Position::new(p.x() * mirror, p.y());
+    let first = match points.first() {
+        Some(first) => map(first),
+        None => return,
+    };
+    drawing.continue_at(first);
+    let back = if closed && points.len() > 1 {
+        Some(first)
+    } else {
+        None
+    };
+    for (end, bulge) in points[1..].iter().map(map).chain(back).zip(bulges) {
+        if *bulge == 0.0 {
+            drawing.line_to(end);
+        } else {
+            drawing.add(Segment::Arc(end, bulge * mirror));
+        }
+    }
+}
+
+/// Non-uniform rational B-spline
+struct Spline {
+    degree: usize,
+    knots: Vec<f64>,
+    /// Control points weighted by, and followed by, their weight
+    control: Vec<[f64; 3]>,
+}
+
+impl Spline {
+    /// None if the control points and knots do not make a spline
+    fn from_entity(entity: &Entity) -> Result<Option<Spline>, ImportError> {
+        let degree = entity.number(71)? as usize;
+        let knots = entity.numbers(40)?;
+        let points = entity.points(10, 20)?;
+        let mut weights = entity.numbers(41)?;
+        if weights.len() != points.len() {
+            weights = vec![1.0; points.len()];
+        }
+        if degree == 0 || points.len() <= degree || knots.len() != points.len() + degree + 1 {
+            return Ok(None);
+        }
+        let control = points
+            .iter()
+            .zip(weights)
+            .map(|(p, w)| [p.x() * w, p.y() * w, w])
+            .collect();
+        Ok(Some(Spline {
+            degree,
+            knots,
+            control,
+        }))
+    }
+
+    /// Point at parameter `t`, by de Boor's algorithm
+    fn point(&self, t: f64) -> Position {
+        let (p, n) = (self.degree, self.control.len());
+        let mut k = p;
+        while k < n - 1 && self.knots[k + 1] <= t {
+            k += 1;
+        }
+        let mut d: Vec<[f64; 3]> = self.control[k - p..=k].to_vec();
+        for r in 1..=p {
+            for j in (r..=p).rev() {
+                let (low, high) = (self.knots[j + k - p], self.knots[j + 1 + k - r]);
+                let alpha = if high > low {
+                    (t - low) / (high - low)
+                } else {
+                    0.0
+                };
+                let previous = d[j - 1];
+                for (c, previous) in d[j].iter_mut().zip(previous) {
+                    *c = (1.0 - alpha) * previous + alpha * *c;
+                }
+            }
+        }
+        Position::new(d[p][0] / d[p][2], d[p][1] / d[p][2])
+    }
+
+    /// Line ends along the spline, within `tolerance` of it
+    fn flatten(&self, tolerance: f64) -> Vec<Position> {
+        let (p, n) = (self.degree, self.control.len());
+        let mut points = vec![self.point(self.knots[p])];
+        for k in p..n {
+            let (t0, t1) = (self.knots[k], self.knots[k + 1]);
+            if t1 > t0 {
+                let (p0, p1) = (self.point(t0), self.point(t1));
+                self.subdivide((t0, p0), (t1, p1), 0, tolerance, &mut points);
+            }
+        }
+        points
+    }
+
+    /// Halves a span until its middle is within `tolerance` of the chord, splitting each
+    /// span at least in four not to miss its inflections
+    fn subdivide(
+        &self,
+        (t0, p0): (f64, Position),
+        (t1, p1): (f64, Position),
+        depth: u32,
+        tolerance: f64,
+        points: &mut Vec<Position>,
+    ) {
+        let t = (t0 + t1) / 2.0;
+        let middle = self.point(t);
+        if depth < 2 || (depth < 16 && chord_distance(&middle, &p0, &p1) > tolerance) {
+            self.subdivide((t0, p0), (t, middle), depth + 1, tolerance, points);
+            self.subdivide((t, middle), (t1, p1), depth + 1, tolerance, points);
+        } else {
+            points.push(p1);
+        }
+    }
+}
+
+/// Distance from `p` to the line through `a` and `b`
+fn chord_distance(p: &Position, a: &Position, b: &Position) -> f64 {
+    let (dx, dy) = (b.x() - a.x(), b.y() - a.y());
+    let (px, py) = (p.x() - a.x(), p.y() - a.y());
+    let length = dx.hypot(dy);
+    if length < 1e-12 {
+        return px.hypot(py);
+    }
+    (dx * py - dy * px).abs() / length
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::Parameters;
+    use crate::ScannerCommand::*;
+
+    fn dxf(header: &[(i32, &str)], entities: &[(i32, &str)]) -> String {
+        let mut pairs = vec![(0, "SECTION"), (2, "HEADER")];
+        pairs.extend(header);
+        pairs.extend(&[(0, "ENDSEC"), (0, "SECTION"), (2, "ENTITIES")]);
+        pairs.extend(entities);
+        pairs.extend(&[(0, "ENDSEC"), (0, "EOF")]);
+        pairs
+            .iter()
+            .map(|(code, value)| format!("{:>3}\n{}\n", code, value))
+            .collect()
+    }
+
+    fn import(entities: &[(i32, &str)]) -> Vec<ScannerCommand> {
+        import_dxf(&dxf(&[], entities), &ImportOptions::new()).unwrap()
+    }
+
+    fn p(x: f64, y: f64) -> Position {
+        Position::new(x, y)
+    }
+
+    #[test]
+    fn lines_and_polylines() {
+        // a line leading into a closed polyline with a half circle, on layer CUT
+        let entities = [
+            (0, "LINE"),
+            (8, "CUT"),
+            (10, "0"),
+            (20, "0"),
+            (11, "1"),
+            (21, "0"),
+            (0, "LWPOLYLINE"),
+            (8, "CUT"),
+            (90, "3"),
+            (70, "1"),
+            (10, "1"),
+            (20, "0"),
+            (10, "2"),
+            (20, "0"),
+            (42, "1"),
+            (10, "2"),
+            (20, "1"),
+            (0, "LINE"),
+            (8, "ENGRAVE"),
+            (10, "2"),
+            (20, "1"),
+            (11, "3"),
+            (21, "1"),
+        ];
+        let options = ImportOptions::new()
+            .with_speeds(100.0, 1000.0)
+            .with_layer("CUT", Parameters::new().with_speed(50.0))
+            .with_layer("ENGRAVE", Parameters::new().with_laser_times(10, 20));
+        let text = dxf(&[(9, "$INSUNITS"), (70, "1")], &entities);
+        let commands = import_dxf(&text, &options).unwrap();
+        let inch = |x, y| p(x * 25.4, y * 25.4);
+        assert_eq!(
+            commands,
+            vec![
+                SetJumpSpeed(1000.0),
+                SetSpeed(50.0),
+                Jump(inch(0.0, 0.0)),
+                Line(inch(1.0, 0.0)),
+                Line(inch(2.0, 0.0)),
+                Arc(inch(2.0, 1.0), 1.0),
+                Line(inch(1.0, 0.0)),
+                SetSpeed(100.0),
+                SetLaserTimes(10, 20),
+                Jump(inch(2.0, 1.0)),
+                Line(inch(3.0, 1.0)),
+            ]
+        );
+
+        let commands = import(&[
+            (0, "POLYLINE"),
+            (70, "1"),
+            (0, "VERTEX"),
+            (10, "0"),
+            (20, "0"),
+            (42, "-0.5"),
+            (0, "VERTEX"),
+            (10, "4"),
+            (20, "0"),
+            (0, "SEQEND"),
+        ]);
+        assert_eq!(
+            commands,
+            vec![Jump(p(0.0, 0.0)), Arc(p(4.0, 0.0), -0.5), Line(p(0.0, 0.0))]
+        );
+    }
+
+    #[test]
+    fn arcs_and_circles() {
+        let commands = import(&[
+            (0, "ARC"),
+            (10, "5"),
+            (20, "5"),
+            (40, "10"),
+            (50, "0"),
+            (51, "90"),
+            (0, "CIRCLE"),
+            (10, "5"),
+            (20, "5"),
+            (40, "2"),
+            // seen from below, x pointing left
+            (0, "CIRCLE"),
+            (10, "5"),
+            (20, "5"),
+            (40, "2"),
+            (230, "-1"),
+        ]);
+        assert_eq!(commands[0], Jump(p(15.0, 5.0)));
+        match commands[1] {
+            Arc(to, bulge) => {
+                assert!((to.x() - 5.0).abs() < 1e-9 && (to.y() - 15.0).abs() < 1e-9);
+                assert!((bulge as f64 - (22.5_f64).to_radians().tan()).abs() < 1e-6);
+            }
+            ref other => panic!("expected an arc, got {:?}", other),
+        }
+        assert_eq!(
+            commands[2..],
+            [
+                Jump(p(7.0, 5.0)),
+                Circle(p(5.0, 5.0), 360.0),
+                Jump(p(-7.0, 5.0)),
+                Circle(p(-5.0, 5.0), -360.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn splines() {
+        // a clamped quadratic spline, the parabola y = 2x - x² for x from 0 to 2
+        let commands = import(&[
+            (0, "SPLINE"),
+            (71, "2"),
+            (40, "0"),
+            (40, "0"),
+            (40, "0"),
+            (40, "1"),
+            (40, "1"),
+            (40, "1"),
+            (10, "0"),
+            (20, "0"),
+            (10, "1"),
+            (20, "2"),
+            (10, "2"),
+            (20, "0"),
+        ]);
+        assert_eq!(commands[0], Jump(p(0.0, 0.0)));
+        assert!(commands.len() > 8);
+        for command in &commands[1..] {
+            match command {
+                Line(q) => assert!((q.y() - (2.0 * q.x() - q.x() * q.x())).abs() < 1e-9),
+                other => panic!("expected lines, got {:?}", other),
+            }
+        }
+        assert_eq!(commands.last(), Some(&Line(p(2.0, 0.0))));
+
+        // without knots, a line through the fit points
+        let commands = import(&[
+            (0, "SPLINE"),
+            (71, "3"),
+            (11, "0"),
+            (21, "0"),
+            (11, "1"),
+            (21, "1"),
+        ]);
+        assert_eq!(commands, vec![Jump(p(0.0, 0.0)), Line(p(1.0, 1.0))]);
+    }
+
+    #[test]
+    fn errors() {
+        let options = ImportOptions::new();
+        let error = import_dxf("0\nSECTION\nx\nENTITIES\n", &options).unwrap_err();
+        assert_eq!(error.line, Some(3));
+        let text = dxf(&[], &[(0, "LINE"), (10, "one")]);
+        let error = import_dxf(&text, &options).unwrap_err();
+        assert_eq!(
+            (error.line, error.message.as_str()),
+            (Some(14), "invalid value `one` for group code 10")
+        );
+        let text = dxf(&[], &[(0, "SPLINE"), (71, "3")]);
+        assert!(import_dxf(&text, &options).is_err());
+    }
+}
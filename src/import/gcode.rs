@@ -0,0 +1,564 @@
+use super::{arc, end, fit, sample, ImportError, ImportOptions, Segment};
+use crate::commands::Position;
+use crate::parsing::ScannerCommand;
+use std::f64::consts::PI;
+
+/// Modal motion of G0 to G3
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Motion {
+    Rapid,
+    Linear,
+    Clockwise,
+    Counterclockwise,
+}
+
+/// Words and codes left out while translating
+#[derive(Debug, Default)]
+struct Importer {
+    warnings: Vec<ImportError>,
+}
+
+/// Machine state while reading the program
+struct State {
+    position: Position,
+    motion: Motion,
+    absolute: bool,
+    absolute_arcs: bool,
+    /// Millimetres per unit
+    unit: f64,
+    spindle: bool,
+    power: Option<f64>,
+    /// Last speed and laser state set
+    speed: Option<f32>,
+    laser: Option<bool>,
+}
+
+/// Words of a block, in the order they were given
+struct Block {
+    line: usize,
+    words: Vec<(char, f64)>,
+}
+
+impl Block {
+    fn value(&self, letter: char) -> Option<f64> {
+        self.words
+            .iter()
+            .find(|(l, _)| *l == letter)
+            .map(|(_, value)| *value)
+    }
+
+    /// G or M codes times ten, G90.1 giving 901
+    fn codes(&self, letter: char) -> impl Iterator<Item = i32> + '_ {
+        self.words
+            .iter()
+            .filter(move |(l, _)| *l == letter)
+            .map(|(_, value)| (value * 10.0).round() as i32)
+    }
+
+    fn error<M: Into<String>>(&self, message: M) -> ImportError {
+        ImportError::new(Some(self.line), message)
+    }
+}
+
+/// Translates G-code (an RS-274 subset) into commands, returning them with warnings about
+/// what it leaves out.
+///
+/// G0 becomes a Jump, G1 a Line and G2/G3, centred with I/J or sized with R, an Arc or a
+/// full Circle; with the laser off, G1 to G3 become a Move to their end instead. F sets
+/// the marking speed, converted from units/min to mm/s. M3/M4 and M5 switch the laser on
+/// and off with SetLaser, as does S with a null or positive power, the laser being off
+/// until then. G4 sleeps for P seconds. G90/G91 (and G90.1/G91.1 for arc centres) select
+/// absolute or relative coordinates, G20/G21 inches or millimetres; the XZ and YZ planes
+/// (G18, G19) are errors. Coordinates are kept as given, the scanner starting at the
+/// origin, unless `options` sets a field; Z and other words are reported and ignored.
+/// The speeds and default parameters of `options` come first, F overriding the speed.
+pub fn import_gcode(
+    text: &str,
+    options: &ImportOptions,
+) -> Result<(Vec<ScannerCommand>, Vec<ImportError>), ImportError> {
+    let mut importer = Importer::default();
+    let mut state = State {
+        position: Position::new(0.0, 0.0),
+        motion: Motion::Rapid,
+        absolute: true,
+        absolute_arcs: false,
+        unit: 1.0,
+        spindle: false,
+        power: None,
+        speed: None,
+        laser: None,
+    };
+    let mut commands: Vec<_> = options.jump_speed().into_iter().collect();
+    let defaults = options.defaults();
+    commands.extend(defaults.commands());
+    state.speed = defaults.speed;
+    for (i, line) in text.lines().enumerate() {
+        let block = match parse_block(i + 1, line)? {
+            Some(block) => block,
+            None => continue,
+        };
+        if !importer.block(&block, &mut state, &mut commands)? {
+            break;
+        }
+    }
+    if let Some(field) = options.field {
+        fit_commands(&mut commands, field);
+    }
+    Ok((commands, importer.warnings))
+}
+
+/// Centres the path of `commands` on the origin, scaling it down to fit `field` if larger
+fn fit_commands(commands: &mut [ScannerCommand], field: f64) {
+    let mut points = Vec::new();
+    let mut position = Position::new(0.0, 0.0);
+    for command in commands.iter() {
+        let segment = match *command {
+            ScannerCommand::Jump(to) | ScannerCommand::Move(to) => {
+                points.push(to);
+                position = to;
+                continue;
+            }
+            ScannerCommand::Line(to) => Segment::Line(to),
+            ScannerCommand::Arc(to, bulge) => Segment::Arc(to, bulge as f64),
+            ScannerCommand::Circle(center, angle) => Segment::Circle(center, angle as f64),
+            _ => continue,
+        };
+        points.extend(sample(&position, &segment));
+        position = end(position, &segment);
+    }
+    let (scale, offset) = match Position::bounds(&points) {
+        Some(bounds) => fit(field, bounds),
+        None => return,
+    };
+    let map = |p: Position| Position::new(p.x() * scale + offset.x(), p.y() * scale + offset.y());
+    for command in commands {
+        match command {
+            ScannerCommand::Jump(to)
+            | ScannerCommand::Move(to)
+            | ScannerCommand::Line(to)
+            | ScannerCommand::Arc(to, _) => *to = map(*to),
+            ScannerCommand::Circle(center, _) => *center = map(*center),
+            _ => {}
+        }
+    }
+}
+
+impl Importer {
+    fn warn(&mut self, block: &Block, message: String) {
+        self.warnings.push(block.error(message));
+    }
+
+    /// Executes a block, false at the end of the program
+    fn block(
+        &mut self,
+        block: &Block,
+        state: &mut State,
+        commands: &mut Vec<ScannerCommand>,
+    ) -> Result<bool, ImportError> {
+        for (letter, value) in &block.words {
+            match letter {
+                'G' | 'M' | 'X' | 'Y' | 'I' | 'J' | 'R' | 'P' | 'F' | 'S' | 'N' => {}
+                _ => self.warn(block, format!("unsupported word {}{}", letter, value)),
+            }
+        }
+        let mut motion = None;
+        let mut dwell = false;
+        for code in block.codes('G') {
+            match code {
+                0 => motion = Some(Motion::Rapid),
+                10 => motion = Some(Motion::Linear),
+                20 => motion = Some(Motion::Clockwise),
+                30 => motion = Some(Motion::Counterclockwise),
+                40 => dwell = true,
+                200 => state.unit = 25.4,
+                210 => state.unit = 1.0,
+                900 => state.absolute = true,
+                910 => state.absolute = false,
+                901 => state.absolute_arcs = true,
+                911 => state.absolute_arcs = false,
+                180 | 190 => {
+                    let message = format!("G{}: only the XY plane (G17) is supported", code / 10);
+                    return Err(block.error(message));
+                }
+                // XY plane, units per minute feed, no compensation or canned cycle, the
+                // default coordinate system and path control: nothing to do
+                170 | 940 | 400 | 490 | 540 | 610 | 640 | 800 => {}
+                _ => self.warn(block, format!("unsupported G{}", code as f64 / 10.0)),
+            }
+        }
+
+        let mut laser_set = false;
+        let mut end = false;
+        for code in block.codes('M') {
+            match code {
+                30 | 40 | 50 => {
+                    state.spindle = code != 50;
+                    laser_set = true;
+                }
+                20 | 300 => end = true,
+                _ => self.warn(block, format!("unsupported M{}", code as f64 / 10.0)),
+            }
+        }
+        if let Some(power) = block.value('S') {
+            state.power = Some(power);
+            laser_set = true;
+        }
+        if laser_set {
+            let laser = state.spindle && state.power.is_none_or(|power| power > 0.0);
+            if state.laser != Some(laser) {
+                commands.push(ScannerCommand::SetLaser(laser));
+                state.laser = Some(laser);
+            }
+        }
+
+        if let Some(feed) = block.value('F') {
+            if feed <= 0.0 {
+                return Err(block.error(format!("invalid feed F{}", feed)));
+            }
+            let speed = (feed * state.unit / 60.0) as f32;
+            if state.speed != Some(speed) {
+                commands.push(ScannerCommand::SetSpeed(speed));
+                state.speed = Some(speed);
+            }
+        }
+
+        if dwell {
+            let seconds = block
+                .value('P')
+                .ok_or_else(|| block.error("G4 without a P time"))?;
+            let mut ms = (seconds * 1e3).round().max(0.0) as u64;
+            while ms > 0 {
+                let sleep = ms.min(u16::MAX as u64);
+                commands.push(ScannerCommand::Sleep(sleep as u16));
+                ms -= sleep;
+            }
+        }
+
+        if let Some(motion) = motion {
+            state.motion = motion;
+        }
+        let moving = ['X', 'Y', 'I', 'J', 'R']
+            .iter()
+            .any(|letter| block.value(*letter).is_some());
+        if moving && !dwell {
+            self.motion(block, state, commands)?;
+        }
+        Ok(!end)
+    }
+
+    fn motion(
+        &mut self,
+        block: &Block,
+        state: &mut State,
+        commands: &mut Vec<ScannerCommand>,
+    ) -> Result<(), ImportError> {
+        let from = state.position;
+        let axis = |letter, current: f64| match block.value(letter) {
+            Some(value) if state.absolute => value * state.unit,
+            Some(value) => current + value * state.unit,
+            None => current,
+        };
+        let to = Position::new(axis('X', from.x()), axis('Y', from.y()));
+        let command = match state.motion {
+            Motion::Rapid => ScannerCommand::Jump(to),
+            Motion::Linear => ScannerCommand::Line(to),
+            Motion::Clockwise | Motion::Counterclockwise => {
+                let sign = match state.motion {
+                    Motion::Clockwise => -1.0,
+                    _ => 1.0,
+                };
+                let center = (block.value('I'), block.value('J'));
+                match (block.value('R'), center) {
+                    (Some(_), (Some(_), _)) | (Some(_), (_, Some(_))) => {
+                        return Err(block.error("arc with both R and I/J"))
+                    }
+                    (Some(radius), _) => radius_arc(block, &from, &to, radius * state.unit, sign)?,
+                    (None, (i, j)) => {
+                        let offset = |value: Option<f64>, current: f64| match value {
+                            Some(value) if state.absolute_arcs => Ok(value * state.unit),
+                            Some(value) => Ok(current + value * state.unit),
+                            None if state.absolute_arcs => {
+                                Err(block.error("absolute arc centre without I and J"))
+                            }
+                            None => Ok(current),
+                        };
+                        let center = Position::new(offset(i, from.x())?, offset(j, from.y())?);
+                        center_arc(block, &from, &to, &center, sign)?
+                    }
+                }
+            }
+        };
+        state.position = to;
+        match command {
+            ScannerCommand::Jump(_) => commands.push(command),
+            // with the laser off, only the end of the path matters
+            _ if state.laser != Some(true) => {
+                if from.distance(&to) >= 1e-9 {
+                    commands.push(ScannerCommand::Move(to));
+                }
+            }
+            _ => commands.push(command),
+        }
+        Ok(())
+    }
+}
+
+/// Arc from `from` to `to` around `center`, counterclockwise for a positive `sign`
+fn center_arc(
+    block: &Block,
+    from: &Position,
+    to: &Position,
+    center: &Position,
+    sign: f64,
+) -> Result<ScannerCommand, ImportError> {
+    let radius = from.distance(center);
+    if radius < 1e-9 {
+        return Err(block.error("arc centre on its start"));
+    }
+    if (to.distance(center) - radius).abs() > (1e-3 * radius).max(0.01) {
+        return Err(block.error("arc end not on the circle of its start"));
+    }
+    if from.distance(to) < 1e-9 {
+        return Ok(ScannerCommand::Circle(*center, (360.0 * sign) as f32));
+    }
+    let start = (from.y() - center.y()).atan2(from.x() - center.x());
+    let end = (to.y() - center.y()).atan2(to.x() - center.x());
+    let sweep = (sign * (end - start)).rem_euclid(2.0 * PI) * sign;
+    Ok(arc(*to, (sweep / 4.0).tan()))
+}
+
+/// Arc of `radius` from `from` to `to`, the shorter one for a positive radius
+fn radius_arc(
+    block: &Block,
+    from: &Position,
+    to: &Position,
+    radius: f64,
+    sign: f64,
+) -> Result<ScannerCommand, ImportError> {
+    let chord = from.distance(to);
+    if chord < 1e-9 {
+        return Err(block.error("R arc ending where it starts"));
+    }
+    let ratio = chord / (2.0 * radius.abs());
+    if ratio > 1.0 + 1e-6 {
+        return Err(block.error(format!("radius R{} too small for the arc", radius)));
+    }
+    let mut sweep = 2.0 * ratio.min(1.0).asin();
+    if radius < 0.0 {
+        sweep = 2.0 * PI - sweep;
+    }
+    Ok(arc(*to, (sign * sweep / 4.0).tan()))
+}
+
+/// Words of a line, None if it holds none. Spaces are ignored and letters case
+/// insensitive; comments go in parentheses or after a semicolon.
+fn parse_block(line: usize, text: &str) -> Result<Option<Block>, ImportError> {
+    let mut code = String::new();
+    let mut comment = false;
+    for c in text.chars() {
+        match c {
+            '(' => comment = true,
+            ')' => comment = false,
+            ';' if !comment => break,
+            // program delimiters and block delete
+            '%' | '/' if !comment && code.is_empty() => {}
+            c if !comment && !c.is_whitespace() => code.push(c.to_ascii_uppercase()),
+            _ => {}
+        }
+    }
+    let mut words = Vec::new();
+    let mut chars = code.char_indices().peekable();
+    while let Some((start, letter)) = chars.next() {
+        if !letter.is_ascii_alphabetic() {
+            let message = format!("unexpected `{}`", letter);
+            return Err(ImportError::new(Some(line), message));
+        }
+        let mut end = start + 1;
+        while let Some((i, c)) = chars.peek() {
+            if !(c.is_ascii_digit() || *c == '.' || *c == '-' || *c == '+') {
+                break;
+            }
+            end = i + 1;
+            chars.next();
+        }
+        let number = &code[start + 1..end];
+        let value = number.parse().map_err(|_| {
+            let message = match number {
+                "" => format!("{} without a value", letter),
+                _ => format!("invalid number `{}` after {}", number, letter),
+            };
+            ImportError::new(Some(line), message)
+        })?;
+        if words
+            .iter()
+            .any(|(l, _)| *l == letter && letter != 'G' && letter != 'M')
+        {
+            let message = format!("two {} words", letter);
+            return Err(ImportError::new(Some(line), message));
+        }
+        words.push((letter, value));
+    }
+    Ok(match words.is_empty() {
+        true => None,
+        false => Some(Block { line, words }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ScannerCommand::*;
+
+    fn import(text: &str) -> (Vec<ScannerCommand>, Vec<ImportError>) {
+        import_gcode(text, &ImportOptions::new()).unwrap()
+    }
+
+    fn error(text: &str) -> ImportError {
+        import_gcode(text, &ImportOptions::new()).unwrap_err()
+    }
+
+    fn p(x: f64, y: f64) -> Position {
+        Position::new(x, y)
+    }
+
+    #[test]
+    fn moves() {
+        let (commands, warnings) = import(
+            "%
+             G21 G90 (metric, absolute)
+             G0 X10 Y5
+             M3 S1000 ; laser on
+             G1 X20 F600
+             Y15
+             G91 G1 X-10 F1200
+             S0
+             G4 P0.25
+             G90 G1 X0
+             G20 G0 X1 Y-1
+             M5
+             M2
+             G0 X100",
+        );
+        assert!(warnings.is_empty());
+        assert_eq!(
+            commands,
+            vec![
+                Jump(p(10.0, 5.0)),
+                SetLaser(true),
+                SetSpeed(10.0),
+                Line(p(20.0, 5.0)),
+                Line(p(20.0, 15.0)),
+                SetSpeed(20.0),
+                Line(p(10.0, 15.0)),
+                SetLaser(false),
+                Sleep(250),
+                Move(p(0.0, 15.0)),
+                Jump(p(25.4, -25.4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn arcs() {
+        let (commands, _) = import(
+            "G0 X10 Y0
+             M3
+             G3 X0 Y10 I-10 J0
+             G2 X10 Y0 R-10
+             G2 I-10
+             G90.1 G3 X-10 Y0 I0 J0",
+        );
+        let arc = |command: &ScannerCommand, end: Position, degrees: f64| match command {
+            Arc(to, bulge) => {
+                assert_eq!(*to, end);
+                let expected = (degrees.to_radians() / 4.0).tan();
+                assert!(
+                    (*bulge as f64 - expected).abs() < 1e-6,
+                    "{} ≠ {}",
+                    bulge,
+                    expected
+                );
+            }
+            other => panic!("expected an arc, got {:?}", other),
+        };
+        assert_eq!(commands[1], SetLaser(true));
+        arc(&commands[2], p(0.0, 10.0), 90.0);
+        arc(&commands[3], p(10.0, 0.0), -270.0);
+        assert_eq!(commands[4], Circle(p(0.0, 0.0), -360.0));
+        arc(&commands[5], p(-10.0, 0.0), 180.0);
+
+        // arcs with the laser off are moves to their end, a full circle going nowhere
+        let (commands, _) = import(
+            "G0 X10 Y0
+G3 X0 Y10 I-10 J0
+G2 I-10
+M3 S0
+G2 X10 Y0 R10",
+        );
+        assert_eq!(
+            commands,
+            [
+                Jump(p(10.0, 0.0)),
+                Move(p(0.0, 10.0)),
+                SetLaser(false),
+                Move(p(10.0, 0.0)),
+            ]
+        );
+
+        // an arc too flat for the target to mark is a line
+        let (commands, _) = import("G0 X0 Y0\nM3\nG2 X10 Y0 R1000000000");
+        assert_eq!(commands[2], Line(p(10.0, 0.0)));
+
+        assert_eq!(error("G0 X0\nG2 X30 R10").line, Some(2));
+        error("G2 X10 I3");
+    }
+
+    #[test]
+    fn diagnostics() {
+        let (commands, warnings) = import("G0 X1 Z5\nT1 M6\nG64.1\nM3\nG1 X2 (Y3)");
+        assert_eq!(
+            commands,
+            vec![Jump(p(1.0, 0.0)), SetLaser(true), Line(p(2.0, 0.0))]
+        );
+        let warnings: Vec<String> = warnings.iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            [
+                "line 1: unsupported word Z5",
+                "line 2: unsupported word T1",
+                "line 2: unsupported M6",
+                "line 3: unsupported G64.1"
+            ]
+        );
+        assert_eq!(
+            error("G0 X1\nG18 G2 X0 I1").to_string(),
+            "line 2: G18: only the XY plane (G17) is supported"
+        );
+        assert_eq!(
+            error("G1 X1\nG1 X\n").to_string(),
+            "line 2: X without a value"
+        );
+        error("G1 X1 X2");
+        error("G1 X1 F0");
+        error("G4");
+    }
+
+    #[test]
+    fn options() {
+        let options = ImportOptions::new()
+            .with_field(10.0)
+            .with_speeds(100.0, 1000.0);
+        let (commands, _) = import_gcode("G0 X0 Y0\nM3\nG1 X20 Y10 F600", &options).unwrap();
+        assert_eq!(
+            commands,
+            [
+                SetJumpSpeed(1000.0),
+                SetSpeed(100.0),
+                Jump(p(-5.0, -2.5)),
+                SetLaser(true),
+                SetSpeed(10.0),
+                Line(p(5.0, 2.5)),
+            ]
+        );
+    }
+}
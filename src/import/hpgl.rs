@@ -0,0 +1,317 @@
+use super::{Drawing, ImportError, ImportOptions, Segment};
+use crate::commands::Position;
+use crate::parsing::ScannerCommand;
+
+/// Instruction of an HPGL file: its mnemonic, parameters and line
+struct Instruction<'a> {
+    mnemonic: String,
+    parameters: Vec<f64>,
+    line: usize,
+    text: &'a str,
+}
+
+impl<'a> Instruction<'a> {
+    fn error<M: Into<String>>(&self, message: M) -> ImportError {
+        let message = format!("{}{}: {}", self.mnemonic, self.text, message.into());
+        ImportError::new(Some(self.line), message)
+    }
+
+    fn expect(&self, min: usize, max: usize) -> Result<(), ImportError> {
+        let count = self.parameters.len();
+        if count < min || count > max {
+            return Err(self.error("wrong number of parameters"));
+        }
+        Ok(())
+    }
+}
+
+/// Plotter state while reading the file, in plotter units
+struct Plotter {
+    position: Position,
+    down: bool,
+    relative: bool,
+    /// Whether a pen is selected, SP0 putting it away
+    pen: bool,
+}
+
+/// Converts the PU, PD, PA, PR, CI, AA and SP instructions of an HPGL file into commands,
+/// in millimetres.
+///
+/// Coordinates are scaled by the plotter unit of `options`. Circles become
+/// `ScannerCommand::Circle`s and arcs `ScannerCommand::Arc`s. SP selects the layer of the
+/// following figures, named after the pen number, so that pens take their parameters from
+/// `options`. Labels and other instructions, such as IN, SC or VS, are ignored.
+pub fn import_hpgl(
+    text: &str,
+    options: &ImportOptions,
+) -> Result<Vec<ScannerCommand>, ImportError> {
+    let mut drawing = Drawing::new();
+    let mut plotter = Plotter {
+        position: Position::new(0.0, 0.0),
+        down: false,
+        relative: false,
+        pen: true,
+    };
+    for instruction in instructions(text)? {
+        let parameters = &instruction.parameters;
+        match instruction.mnemonic.as_str() {
+            "PU" | "PD" | "PA" | "PR" => {
+                match instruction.mnemonic.as_str() {
+                    "PU" => plotter.down = false,
+                    "PD" => plotter.down = true,
+                    "PA" => plotter.relative = false,
+                    _ => plotter.relative = true,
+                }
+                if parameters.len() % 2 != 0 {
+                    return Err(instruction.error("odd number of coordinates"));
+                }
+                for point in parameters.chunks(2) {
+                    let to = match plotter.relative {
+                        true => Position::new(
+                            plotter.position.x() + point[0],
+                            plotter.position.y() + point[1],
+                        ),
+                        false => Position::new(point[0], point[1]),
+                    };
+                    if plotter.down && plotter.pen {
+                        drawing.continue_at(plotter.position);
+                        drawing.line_to(to);
+                    }
+                    plotter.position = to;
+                }
+            }
+            "CI" => {
+                instruction.expect(1, 2)?;
+                // a negative radius starts the circle opposite
+                let center = plotter.position;
+                if plotter.pen && parameters[0] != 0.0 {
+                    drawing.move_to(Position::new(center.x() + parameters[0], center.y()));
+                    drawing.add(Segment::Circle(center, 360.0));
+                }
+            }
+            "AA" => {
+                instruction.expect(3, 4)?;
+                let center = Position::new(parameters[0], parameters[1]);
+                let sweep = parameters[2];
+                let end = plotter.position.rotate(&center, sweep);
+                if plotter.down && plotter.pen && sweep != 0.0 {
+                    drawing.continue_at(plotter.position);
+                    if sweep.abs() < 360.0 {
+                        drawing.add(Segment::Arc(end, (sweep.to_radians() / 4.0).tan()));
+                    } else {
+                        drawing.add(Segment::Circle(center, sweep));
+                    }
+                }
+                plotter.position = end;
+            }
+            "SP" => {
+                instruction.expect(0, 1)?;
+                let pen = parameters.first().copied().unwrap_or(0.0);
+                if pen < 0.0 || pen.fract() != 0.0 {
+                    return Err(instruction.error("invalid pen number"));
+                }
+                plotter.pen = pen != 0.0;
+                drawing.set_layer(Some(&(pen as u32).to_string()));
+            }
+            _ => {}
+        }
+    }
+    drawing.scale(options.plotter_unit, Position::new(0.0, 0.0));
+    drawing.to_commands(options)
+}
+
+/// Splits HPGL text into instructions, skipping the text of labels
+fn instructions(text: &str) -> Result<Vec<Instruction<'_>>, ImportError> {
+    let line_at = |offset: usize| text[..offset].matches('\n').count() + 1;
+    let bytes = text.as_bytes();
+    let mut instructions = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() || c == b';' {
+            i += 1;
+            continue;
+        }
+        if !c.is_ascii_alphabetic() || i + 1 >= bytes.len() || !bytes[i + 1].is_ascii_alphabetic() {
+            let found = text[i..].chars().next().unwrap_or_default();
+            let message = format!("unexpected `{}`", found);
+            return Err(ImportError::new(Some(line_at(i)), message));
+        }
+        let mnemonic = text[i..i + 2].to_ascii_uppercase();
+        let line = line_at(i);
+        i += 2;
+        if mnemonic == "LB" {
+            // the label runs up to the ETX terminator
+            i = text[i..]
+                .find('\u{3}')
+                .map_or(bytes.len(), |end| i + end + 1);
+            continue;
+        }
+        let start = i;
+        while i < bytes.len() && !bytes[i].is_ascii_alphabetic() && bytes[i] != b';' {
+            i += 1;
+        }
+        let parameters = &text[start..i];
+        let values = parameters
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|value| !value.is_empty())
+            .map(|value| value.parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|_| {
+                let message = format!("{}: invalid parameters `{}`", mnemonic, parameters.trim());
+                ImportError::new(Some(line), message)
+            })?;
+        instructions.push(Instruction {
+            mnemonic,
+            parameters: values,
+            line,
+            text: parameters.trim(),
+        });
+    }
+    Ok(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::Parameters;
+    use crate::ScannerCommand::*;
+
+    fn p(x: f64, y: f64) -> Position {
+        Position::new(x, y)
+    }
+
+    #[test]
+    fn plotting() {
+        let options = ImportOptions::new().with_plotter_unit(0.1).with_layer(
+            "2",
+            Parameters::new().with_speed(250.0).with_laser_times(50, 80),
+        );
+        let commands = import_hpgl(
+            "IN;SP1;PU100,0;PD200,0,200,100;PR-100,0;PU;LBlabel; PD\u{3};\n\
+             SP2;PA300,300;CI50;PD;AA300,250,-90;SP0;PD0,0;",
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            commands[..5],
+            [
+                Jump(p(10.0, 0.0)),
+                Line(p(20.0, 0.0)),
+                Line(p(20.0, 10.0)),
+                Line(p(10.0, 10.0)),
+                SetSpeed(250.0),
+            ]
+        );
+        assert_eq!(
+            commands[5..9],
+            [
+                SetLaserTimes(50, 80),
+                Jump(p(35.0, 30.0)),
+                Circle(p(30.0, 30.0), 360.0),
+                Jump(p(30.0, 30.0)),
+            ]
+        );
+        match commands[9] {
+            Arc(to, bulge) => {
+                assert!((to.x() - 35.0).abs() < 1e-9 && (to.y() - 25.0).abs() < 1e-9);
+                assert!((bulge as f64 + (22.5_f64).to_radians().tan()).abs() < 1e-6);
+            }
+            ref other => panic!("expected an arc, got {:?}", other),
+        }
+        assert_eq!(commands.len(), 10);
+    }
+
+    #[test]
+    fn pens() {
+        let text = "SP1;PA0,0;PD10,0;SP2;PU20,0;PD30,0;SP;PD40,0;";
+        let options = ImportOptions::new().with_plotter_unit(1.0).with_layer(
+            "1",
+            Parameters::new().with_speed(300.0).with_laser_times(5, 6),
+        );
+        // pen 2 sets nothing, and no default replaces the speed of pen 1
+        let error = import_hpgl(text, &options).unwrap_err();
+        assert_eq!(
+            error.message,
+            "no default speed for the figures on layer `2` after ones with their own"
+        );
+
+        let options = options
+            .with_speeds(100.0, 1000.0)
+            .with_defaults(Parameters::new().with_laser_times(1, 1));
+        assert_eq!(
+            import_hpgl(text, &options).unwrap(),
+            [
+                SetJumpSpeed(1000.0),
+                SetSpeed(300.0),
+                SetLaserTimes(5, 6),
+                Jump(p(0.0, 0.0)),
+                Line(p(10.0, 0.0)),
+                SetSpeed(100.0),
+                SetLaserTimes(1, 1),
+                Jump(p(20.0, 0.0)),
+                Line(p(30.0, 0.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn circles_and_arcs() {
+        let options = ImportOptions::new().with_plotter_unit(1.0);
+        // a negative radius starts the circle left of the centre
+        let commands = import_hpgl("PA50,50;CI-10;", &options).unwrap();
+        assert_eq!(
+            commands,
+            [Jump(p(40.0, 50.0)), Circle(p(50.0, 50.0), 360.0)]
+        );
+
+        // AA centres stay absolute after PR, which applies again after the arc
+        let commands = import_hpgl("PR10,0;PD;AA0,0,90;PR0,5;", &options).unwrap();
+        assert_eq!(commands[0], Jump(p(10.0, 0.0)));
+        match commands[1] {
+            Arc(to, bulge) => {
+                assert!(to.distance(&p(0.0, 10.0)) < 1e-9);
+                assert!((bulge as f64 - (22.5_f64).to_radians().tan()).abs() < 1e-6);
+            }
+            ref other => panic!("expected an arc, got {:?}", other),
+        }
+        match commands[2] {
+            Line(to) => assert!(to.distance(&p(0.0, 15.0)) < 1e-9),
+            ref other => panic!("expected a line, got {:?}", other),
+        }
+        assert_eq!(commands.len(), 3);
+
+        // a sweep of a turn or more is a circle, the figure going on from where it ends
+        let commands = import_hpgl("PA100,0;PD;AA90,0,450;PA90,20;", &options).unwrap();
+        assert_eq!(
+            commands,
+            [
+                Jump(p(100.0, 0.0)),
+                Circle(p(90.0, 0.0), 450.0),
+                Line(p(90.0, 20.0)),
+            ]
+        );
+
+        // SP without a number puts the pen away
+        let commands = import_hpgl("SP1;PD10,0;SP;PD20,0;CI5;", &options).unwrap();
+        assert_eq!(commands, [Jump(p(0.0, 0.0)), Line(p(10.0, 0.0))]);
+    }
+
+    #[test]
+    fn errors() {
+        let options = ImportOptions::new();
+        let error = import_hpgl("SP1;\nPD10,20,30;", &options).unwrap_err();
+        assert_eq!(
+            (error.line, error.message.as_str()),
+            (Some(2), "PD10,20,30: odd number of coordinates")
+        );
+        let error = import_hpgl("PA10,1.2.3;", &options).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 1: PA: invalid parameters `10,1.2.3`"
+        );
+        assert!(import_hpgl("CI;", &options).is_err());
+        let error = import_hpgl("PA10,10;\n#", &options).unwrap_err();
+        assert_eq!(error.to_string(), "line 2: unexpected `#`");
+    }
+}
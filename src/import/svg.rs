@@ -0,0 +1,548 @@
+use super::{
+    arc_steps, flatten_cubic, flatten_quadratic, Affine, Drawing, ImportError, ImportOptions,
+    Segment,
+};
+use crate::parsing::ScannerCommand;
+use roxmltree::{Document, Node};
+use std::f64::consts::PI;
+use svgtypes::{Length, LengthUnit, PathParser, PathSegment, PointsParser, Transform, ViewBox};
+
+/// Millimetres per CSS pixel, the SVG user unit when the document gives no size
+const MM_PER_PX: f64 = 25.4 / 96.0;
+
+/// Converts the shapes of an SVG document (paths, lines, polylines, polygons, rects,
+/// circles and ellipses) into commands, in millimetres with y pointing up.
+///
+/// Circular arcs stay arcs unless a transform skews or stretches them; Bézier curves and
+/// elliptical arcs are flattened into lines within the tolerance of `options`. Text,
+/// images, `<use>` references and anything under `<defs>` are ignored.
+pub fn import_svg(text: &str, options: &ImportOptions) -> Result<Vec<ScannerCommand>, ImportError> {
+    let document = Document::parse(text)
+        .map_err(|e| ImportError::new(Some(e.pos().row as usize), e.to_string()))?;
+    let root = document.root_element();
+    if root.tag_name().name() != "svg" {
+        return Err(ImportError::new(None, "not an SVG document"));
+    }
+    let mut importer = Importer {
+        drawing: Drawing::new(),
+        tolerance: options.tolerance(),
+    };
+    importer.group(root, &viewport(root)?)?;
+    importer.drawing.to_commands(options)
+}
+
+/// Transform from the user space of the root element to millimetres, y pointing up
+fn viewport(root: Node) -> Result<Affine, ImportError> {
+    let length = |name| -> Result<Option<f64>, ImportError> {
+        match root.attribute(name) {
+            Some(value) => Ok(millimetres(parse(root, name, value)?)),
+            None => Ok(None),
+        }
+    };
+    let (width, height) = (length("width")?, length("height")?);
+    let view_box = match root.attribute("viewBox") {
+        Some(value) => Some(parse::<ViewBox>(root, "viewBox", value)?),
+        None => None,
+    };
+    let (scale, x, y) = match view_box {
+        Some(vb) => {
+            let scale = match (width, height) {
+                (Some(width), _) if vb.w > 0.0 => width / vb.w,
+                (_, Some(height)) if vb.h > 0.0 => height / vb.h,
+                _ => MM_PER_PX,
+            };
+            (scale, vb.x, vb.y)
+        }
+        None => (MM_PER_PX, 0.0, 0.0),
+    };
+    Ok(Affine::translate(-x, -y).then(&Affine::scale(scale, -scale)))
+}
+
+/// Length in millimetres, None for relative units
+fn millimetres(length: Length) -> Option<f64> {
+    let scale = match length.unit {
+        LengthUnit::None | LengthUnit::Px => MM_PER_PX,
+        LengthUnit::In => 25.4,
+        LengthUnit::Cm => 10.0,
+        LengthUnit::Mm => 1.0,
+        LengthUnit::Pt => 25.4 / 72.0,
+        LengthUnit::Pc => 25.4 / 6.0,
+        LengthUnit::Em | LengthUnit::Ex | LengthUnit::Percent => return None,
+    };
+    Some(length.number * scale)
+}
+
+fn parse<T>(node: Node, name: &str, value: &str) -> Result<T, ImportError>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|e| {
+        let message = format!("invalid {} `{}`: {}", name, value, e);
+        ImportError::new(Some(line_of(node)), message)
+    })
+}
+
+fn line_of(node: Node) -> usize {
+    node.document().text_pos_at(node.range().start).row as usize
+}
+
+struct Importer {
+    drawing: Drawing,
+    tolerance: f64,
+}
+
+impl Importer {
+    fn group(&mut self, node: Node, transform: &Affine) -> Result<(), ImportError> {
+        for child in node.children().filter(Node::is_element) {
+            if child.attribute("display") == Some("none") {
+                continue;
+            }
+            let transform = match child.attribute("transform") {
+                Some(value) => {
+                    let t: Transform = parse(child, "transform", value)?;
+                    Affine::new(t.a, t.b, t.c, t.d, t.e, t.f).then(transform)
+                }
+                None => *transform,
+            };
+            self.element(child, &transform)?;
+        }
+        Ok(())
+    }
+
+    fn element(&mut self, node: Node, t: &Affine) -> Result<(), ImportError> {
+        let number = |name: &str| -> Result<f64, ImportError> {
+            match node.attribute(name) {
+                Some(value) => Ok(parse::<Length>(node, name, value)?.number),
+                None => Ok(0.0),
+            }
+        };
+        match node.tag_name().name() {
+            "g" | "svg" | "a" | "switch" => self.group(node, t)?,
+            "path" => {
+                if let Some(data) = node.attribute("d") {
+                    self.path(node, data, t)?;
+                }
+            }
+            "line" => {
+                self.drawing.move_to(t.apply(number("x1")?, number("y1")?));
+                self.drawing.line_to(t.apply(number("x2")?, number("y2")?));
+            }
+            name @ "polyline" | name @ "polygon" => {
+                let points = node.attribute("points").unwrap_or("");
+                for (i, (x, y)) in PointsParser::from(points).enumerate() {
+                    match i {
+                        0 => self.drawing.move_to(t.apply(x, y)),
+                        _ => self.drawing.line_to(t.apply(x, y)),
+                    }
+                }
+                if name == "polygon" {
+                    self.drawing.close();
+                }
+            }
+            "rect" => {
+                let (x, y) = (number("x")?, number("y")?);
+                let (w, h) = (number("width")?, number("height")?);
+                let rx = node.attribute("rx").map(|_| number("rx")).transpose()?;
+                let ry = node.attribute("ry").map(|_| number("ry")).transpose()?;
+                let (rx, ry) = match (rx, ry) {
+                    (Some(rx), Some(ry)) => (rx, ry),
+                    (Some(r), None) | (None, Some(r)) => (r, r),
+                    (None, None) => (0.0, 0.0),
+                };
+                let (rx, ry) = (rx.min(w / 2.0), ry.min(h / 2.0));
+                if w > 0.0 && h > 0.0 {
+                    let data = format!(
+                        "M {x0} {y} H {x1} A {rx} {ry} 0 0 1 {x2} {y0} V {y1} \
+                         A {rx} {ry} 0 0 1 {x1} {y2} H {x0} A {rx} {ry} 0 0 1 {x} {y1} \
+                         V {y0} A {rx} {ry} 0 0 1 {x0} {y} Z",
+                        x = x,
+                        y = y,
+                        rx = rx,
+                        ry = ry,
+                        x0 = x + rx,
+                        x1 = x + w - rx,
+                        x2 = x + w,
+                        y0 = y + ry,
+                        y1 = y + h - ry,
+                        y2 = y + h,
+                    );
+                    self.path(node, &data, t)?;
+                }
+            }
+            "circle" | "ellipse" => {
+                let (cx, cy) = (number("cx")?, number("cy")?);
+                let (rx, ry) = match node.tag_name().name() {
+                    "circle" => (number("r")?, number("r")?),
+                    _ => (number("rx")?, number("ry")?),
+                };
+                if rx > 0.0 && ry > 0.0 {
+                    self.ellipse(cx, cy, rx, ry, t);
+                }
+            }
+            // not drawn, or not shapes
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn ellipse(&mut self, cx: f64, cy: f64, rx: f64, ry: f64, t: &Affine) {
+        self.drawing.move_to(t.apply(cx + rx, cy));
+        if (rx - ry).abs() < 1e-9 * rx && t.is_similarity() {
+            // a mirroring transform reverses the direction of the positive angles of SVG
+            let angle = 360.0_f64.copysign(t.determinant());
+            self.drawing.add(Segment::Circle(t.apply(cx, cy), angle));
+        } else {
+            self.elliptical(cx, cy, rx, ry, 0.0, 0.0, 2.0 * PI, t);
+        }
+    }
+
+    fn path(&mut self, node: Node, data: &str, t: &Affine) -> Result<(), ImportError> {
+        // current point, start of the subpath and last control point, in user space
+        let (mut x, mut y) = (0.0, 0.0);
+        let (mut start_x, mut start_y) = (0.0, 0.0);
+        let mut control: Option<(f64, f64)> = None;
+        let mut quadratic_control: Option<(f64, f64)> = None;
+        for segment in PathParser::from(data) {
+            let segment = segment.map_err(|e| {
+                ImportError::new(Some(line_of(node)), format!("invalid path: {}", e))
+            })?;
+            let absolute = |abs: bool, px: f64, py: f64| {
+                if abs {
+                    (px, py)
+                } else {
+                    (x + px, y + py)
+                }
+            };
+            let (mut next_control, mut next_quadratic) = (None, None);
+            let (to_x, to_y) = match segment {
+                PathSegment::MoveTo { abs, x: px, y: py } => {
+                    let (px, py) = absolute(abs, px, py);
+                    self.drawing.move_to(t.apply(px, py));
+                    start_x = px;
+                    start_y = py;
+                    (px, py)
+                }
+                PathSegment::LineTo { abs, x: px, y: py } => {
+                    let (px, py) = absolute(abs, px, py);
+                    self.drawing.line_to(t.apply(px, py));
+                    (px, py)
+                }
+                PathSegment::HorizontalLineTo { abs, x: px } => {
+                    let px = if abs { px } else { x + px };
+                    self.drawing.line_to(t.apply(px, y));
+                    (px, y)
+                }
+                PathSegment::VerticalLineTo { abs, y: py } => {
+                    let py = if abs { py } else { y + py };
+                    self.drawing.line_to(t.apply(x, py));
+                    (x, py)
+                }
+                PathSegment::CurveTo {
+                    abs,
+                    x1,
+                    y1,
+                    x2,
+                    y2,
+                    x: px,
+                    y: py,
+                } => {
+                    let c1 = absolute(abs, x1, y1);
+                    let c2 = absolute(abs, x2, y2);
+                    let end = absolute(abs, px, py);
+                    self.cubic((x, y), c1, c2, end, t);
+                    next_control = Some(c2);
+                    end
+                }
+                PathSegment::SmoothCurveTo {
+                    abs,
+                    x2,
+                    y2,
+                    x: px,
+                    y: py,
+                } => {
+                    // the first control point mirrors the last one of a previous cubic
+                    let c1 = match control {
+                        Some((cx, cy)) => (2.0 * x - cx, 2.0 * y - cy),
+                        None => (x, y),
+                    };
+                    let c2 = absolute(abs, x2, y2);
+                    let end = absolute(abs, px, py);
+                    self.cubic((x, y), c1, c2, end, t);
+                    next_control = Some(c2);
+                    end
+                }
+                PathSegment::Quadratic {
+                    abs,
+                    x1,
+                    y1,
+                    x: px,
+                    y: py,
+                } => {
+                    let c = absolute(abs, x1, y1);
+                    let end = absolute(abs, px, py);
+                    self.quadratic((x, y), c, end, t);
+                    next_quadratic = Some(c);
+                    end
+                }
+                PathSegment::SmoothQuadratic { abs, x: px, y: py } => {
+                    let c = match quadratic_control {
+                        Some((cx, cy)) => (2.0 * x - cx, 2.0 * y - cy),
+                        None => (x, y),
+                    };
+                    let end = absolute(abs, px, py);
+                    self.quadratic((x, y), c, end, t);
+                    next_quadratic = Some(c);
+                    end
+                }
+                PathSegment::EllipticalArc {
+                    abs,
+                    rx,
+                    ry,
+                    x_axis_rotation,
+                    large_arc,
+                    sweep,
+                    x: px,
+                    y: py,
+                } => {
+                    let end = absolute(abs, px, py);
+                    self.arc((x, y), rx, ry, x_axis_rotation, large_arc, sweep, end, t);
+                    end
+                }
+                PathSegment::ClosePath { .. } => {
+                    self.drawing.close();
+                    // the next subpath starts where this one did
+                    self.drawing.move_to(t.apply(start_x, start_y));
+                    (start_x, start_y)
+                }
+            };
+            // smooth curves only mirror the control point of a curve of their own kind
+            control = next_control;
+            quadratic_control = next_quadratic;
+            x = to_x;
+            y = to_y;
+        }
+        Ok(())
+    }
+
+    fn cubic(
+        &mut self,
+        p0: (f64, f64),
+        p1: (f64, f64),
+        p2: (f64, f64),
+        p3: (f64, f64),
+        t: &Affine,
+    ) {
+        let map = |(x, y): (f64, f64)| t.apply(x, y);
+        let points = [map(p0), map(p1), map(p2), map(p3)];
+        for point in flatten_cubic(points, self.tolerance) {
+            self.drawing.line_to(point);
+        }
+    }
+
+    fn quadratic(&mut self, p0: (f64, f64), p1: (f64, f64), p2: (f64, f64), t: &Affine) {
+        let map = |(x, y): (f64, f64)| t.apply(x, y);
+        for point in flatten_quadratic([map(p0), map(p1), map(p2)], self.tolerance) {
+            self.drawing.line_to(point);
+        }
+    }
+
+    /// Elliptical arc from its endpoint parameters, see the SVG implementation notes
+    #[allow(clippy::too_many_arguments)]
+    fn arc(
+        &mut self,
+        (x1, y1): (f64, f64),
+        rx: f64,
+        ry: f64,
+        rotation: f64,
+        large_arc: bool,
+        sweep: bool,
+        (x2, y2): (f64, f64),
+        t: &Affine,
+    ) {
+        let (mut rx, mut ry) = (rx.abs(), ry.abs());
+        if (x1 - x2).abs() < 1e-12 && (y1 - y2).abs() < 1e-12 {
+            return;
+        }
+        if rx < 1e-12 || ry < 1e-12 {
+            self.drawing.line_to(t.apply(x2, y2));
+            return;
+        }
+        let (sin, cos) = rotation.to_radians().sin_cos();
+        let (dx, dy) = ((x1 - x2) / 2.0, (y1 - y2) / 2.0);
+        let (x1p, y1p) = (cos * dx + sin * dy, -sin * dx + cos * dy);
+        // radii too small to join the ends are scaled up
+        let lambda = (x1p / rx).powi(2) + (y1p / ry).powi(2);
+        if lambda > 1.0 {
+            rx *= lambda.sqrt();
+            ry *= lambda.sqrt();
+        }
+        let num = rx * rx * ry * ry - rx * rx * y1p * y1p - ry * ry * x1p * x1p;
+        let den = rx * rx * y1p * y1p + ry * ry * x1p * x1p;
+        let mut coef = (num / den).max(0.0).sqrt();
+        if large_arc == sweep {
+            coef = -coef;
+        }
+        let (cxp, cyp) = (coef * rx * y1p / ry, -coef * ry * x1p / rx);
+        let cx = cos * cxp - sin * cyp + (x1 + x2) / 2.0;
+        let cy = sin * cxp + cos * cyp + (y1 + y2) / 2.0;
+        let angle = |ux: f64, uy: f64| uy.atan2(ux);
+        let theta1 = angle((x1p - cxp) / rx, (y1p - cyp) / ry);
+        let mut delta = angle((-x1p - cxp) / rx, (-y1p - cyp) / ry) - theta1;
+        if sweep && delta < 0.0 {
+            delta += 2.0 * PI;
+        } else if !sweep && delta > 0.0 {
+            delta -= 2.0 * PI;
+        }
+
+        if (rx - ry).abs() < 1e-9 * rx && t.is_similarity() {
+            // a mirroring transform reverses the direction of the positive angles of SVG
+            let turn = delta * t.determinant().signum();
+            self.drawing
+                .add(Segment::Arc(t.apply(x2, y2), (turn / 4.0).tan()));
+        } else {
+            self.elliptical(cx, cy, rx, ry, rotation, theta1, delta, t);
+        }
+    }
+
+    /// Flattens the ellipse arc from angle `start` sweeping `delta` radians
+    #[allow(clippy::too_many_arguments)]
+    fn elliptical(
+        &mut self,
+        cx: f64,
+        cy: f64,
+        rx: f64,
+        ry: f64,
+        rotation: f64,
+        start: f64,
+        delta: f64,
+        t: &Affine,
+    ) {
+        let (sin, cos) = rotation.to_radians().sin_cos();
+        let radius = rx.max(ry) * t.a.hypot(t.b).max(t.c.hypot(t.d));
+        let steps = arc_steps(radius, delta.to_degrees(), self.tolerance);
+        for i in 1..=steps {
+            let angle = start + delta * i as f64 / steps as f64;
+            let (x, y) = (rx * angle.cos(), ry * angle.sin());
+            self.drawing
+                .line_to(t.apply(cx + cos * x - sin * y, cy + sin * x + cos * y));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::Position;
+    use crate::ScannerCommand::*;
+
+    fn import(body: &str) -> Vec<ScannerCommand> {
+        let svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"100mm\" height=\"50mm\" \
+             viewBox=\"0 0 200 100\">{}</svg>",
+            body
+        );
+        import_svg(&svg, &ImportOptions::new()).unwrap()
+    }
+
+    fn p(x: f64, y: f64) -> Position {
+        Position::new(x, y)
+    }
+
+    #[test]
+    fn shapes() {
+        assert_eq!(
+            import("<line x1=\"0\" y1=\"0\" x2=\"20\" y2=\"10\"/>"),
+            vec![Jump(p(0.0, 0.0)), Line(p(10.0, -5.0))]
+        );
+        assert_eq!(
+            import("<g transform=\"translate(10 0)\"><polygon points=\"0,0 10,0 10,10\"/></g>"),
+            vec![
+                Jump(p(5.0, 0.0)),
+                Line(p(10.0, 0.0)),
+                Line(p(10.0, -5.0)),
+                Line(p(5.0, 0.0))
+            ]
+        );
+        assert_eq!(
+            import("<rect x=\"2\" y=\"2\" width=\"4\" height=\"2\"/>"),
+            vec![
+                Jump(p(1.0, -1.0)),
+                Line(p(3.0, -1.0)),
+                Line(p(3.0, -2.0)),
+                Line(p(1.0, -2.0)),
+                Line(p(1.0, -1.0))
+            ]
+        );
+        // clockwise on the page, y pointing down, is clockwise with y up once mirrored
+        assert_eq!(
+            import("<circle cx=\"10\" cy=\"10\" r=\"4\"/>"),
+            vec![Jump(p(7.0, -5.0)), Circle(p(5.0, -5.0), -360.0)]
+        );
+        assert!(import("<defs><circle r=\"4\"/></defs><text>x</text>").is_empty());
+    }
+
+    #[test]
+    fn arcs() {
+        // a clockwise half circle on the page, over the top
+        let commands = import("<path d=\"M 0 10 A 10 10 0 0 1 20 10\"/>");
+        assert_eq!(commands[0], Jump(p(0.0, -5.0)));
+        match commands[1] {
+            Arc(to, bulge) => {
+                assert_eq!(to, p(10.0, -5.0));
+                assert!((bulge + 1.0).abs() < 1e-6);
+            }
+            ref other => panic!("expected an arc, got {:?}", other),
+        }
+        // a stretched arc is flattened, its points staying on the ellipse
+        let commands = import("<path d=\"M 0 10 a 20 10 0 0 0 40 0\"/>");
+        assert!(commands.len() > 10);
+        for command in &commands[1..] {
+            match command {
+                Line(q) => {
+                    let (x, y) = ((q.x() - 10.0) / 10.0, (q.y() + 5.0) / 5.0);
+                    assert!((x.hypot(y) - 1.0).abs() < 1e-9, "{:?} off the ellipse", q);
+                    assert!(q.y() <= -5.0 + 1e-9);
+                }
+                other => panic!("expected lines, got {:?}", other),
+            }
+        }
+        let skewed = import("<circle cx=\"0\" cy=\"0\" r=\"4\" transform=\"skewX(30)\"/>");
+        assert!(skewed[1..].iter().all(|c| matches!(c, Line(_))));
+    }
+
+    #[test]
+    fn curves() {
+        let commands = import("<path d=\"M0 0 C 0 20 20 20 20 0 S 40 -20 40 0 Z m 5 5 h 1 v 1\"/>");
+        assert_eq!(commands[0], Jump(p(0.0, 0.0)));
+        let last_line = commands.iter().rposition(|c| matches!(c, Jump(_))).unwrap();
+        // closing line back to the start, then a relative subpath from there
+        assert_eq!(commands[last_line - 1], Line(p(0.0, 0.0)));
+        assert_eq!(
+            commands[last_line..],
+            [Jump(p(2.5, -2.5)), Line(p(3.0, -2.5)), Line(p(3.0, -3.0))]
+        );
+        // the smooth curve mirrors the first one below the axis
+        let lowest = commands.iter().fold(0.0_f64, |low, c| match c {
+            Line(q) => low.min(q.y()),
+            _ => low,
+        });
+        let highest = commands.iter().fold(0.0_f64, |high, c| match c {
+            Line(q) => high.max(q.y()),
+            _ => high,
+        });
+        assert!((lowest + 7.5).abs() < 0.01 && (highest - 7.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn errors() {
+        let options = ImportOptions::new();
+        assert!(import_svg("<svg", &options).is_err());
+        assert_eq!(
+            import_svg("<html/>", &options).unwrap_err().message,
+            "not an SVG document"
+        );
+        let error = import_svg("<svg>\n<path d=\"M 0 0 L x\"/></svg>", &options).unwrap_err();
+        assert_eq!(error.line, Some(2));
+    }
+}
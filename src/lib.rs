@@ -1,64 +1,312 @@
 use bincode::Options;
 use std::fmt;
-use std::fs::File;
 use std::io::prelude::*;
-use std::io::{BufRead, BufReader};
+use std::io::ErrorKind;
 use std::net::TcpStream;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, PartialEq)]
+/// Any error of the library, from reading a script to talking to the target
+#[derive(Debug)]
 pub enum AppError {
-    ParseError,
-    FileError,
+    /// Connection or file error
+    Io(std::io::Error),
+    /// The target did not answer before the deadline
+    Timeout,
+    /// The connection closed after `got` of the `expected` reply bytes
+    ShortReply { expected: usize, got: usize },
+    /// A reply could not be decoded
+    Decode(bincode::Error),
+    /// A reply answers another request than the one sent
+    UnexpectedOpcode {
+        expected: commands::CMD3G_OPCODE,
+        got: u8,
+    },
+    /// Script errors, each with its file, line and column
+    Parse(ParseErrors),
+    /// Script whose blocks do not nest
+    Compile(CompileError),
+}
+
+impl From<ParseErrors> for AppError {
+    fn from(e: ParseErrors) -> AppError {
+        AppError::Parse(e)
+    }
+}
+
+impl From<CompileError> for AppError {
+    fn from(e: CompileError) -> AppError {
+        AppError::Compile(e)
+    }
+}
+
+impl From<bincode::Error> for AppError {
+    fn from(e: bincode::Error) -> AppError {
+        AppError::Decode(e)
+    }
 }
 
 impl From<std::io::Error> for AppError {
-    fn from(_e: std::io::Error) -> AppError {
-        AppError::FileError
+    fn from(e: std::io::Error) -> AppError {
+        match e.kind() {
+            // a read timeout surfaces as WouldBlock on Unix and TimedOut on Windows
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => AppError::Timeout,
+            _ => AppError::Io(e),
+        }
+    }
+}
+
+impl std::error::Error for AppError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AppError::Io(e) => Some(e),
+            AppError::Decode(e) => Some(e),
+            AppError::Parse(e) => Some(e),
+            AppError::Compile(e) => Some(e),
+            _ => None,
+        }
     }
 }
-impl std::error::Error for AppError {}
 
 impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            AppError::ParseError => write!(f, "Parse Error"),
-            AppError::FileError => write!(f, "File Error"),
+            AppError::Io(e) => write!(f, "{}", e),
+            AppError::Timeout => write!(f, "timed out waiting for the target"),
+            AppError::ShortReply { expected, got } => {
+                write!(f, "short reply: expected {} bytes, got {}", expected, got)
+            }
+            AppError::Decode(e) => write!(f, "invalid reply: {}", e),
+            AppError::UnexpectedOpcode { expected, got } => {
+                write!(f, "unexpected reply opcode {:#04x} to {:?}", got, expected)
+            }
+            AppError::Parse(errors) => write!(f, "{}", errors),
+            AppError::Compile(e) => write!(f, "{}", e),
         }
     }
 }
 
+#[cfg(feature = "async")]
+pub mod async_client;
 pub mod commands;
+pub mod compiler;
+pub mod controller;
+pub mod disasm;
+pub mod estimate;
+pub mod import;
+pub mod mock;
 mod parsing;
-pub use crate::commands::TgtStatus;
+pub mod preview;
+pub mod watch;
+#[cfg(feature = "async")]
+pub use crate::async_client::AsyncClient;
 pub use crate::commands::CMD3G;
+pub use crate::commands::{IoPins, IpConfig, SystemState, TargetId, TgtStatus};
+pub use crate::compiler::{CompileError, Compiler, Warning};
+pub use crate::controller::{ConnectOptions, Controller};
+pub use crate::estimate::{Estimate, Estimator};
+pub use crate::mock::MockController;
 pub use crate::parsing::ScannerCommand;
+pub use crate::parsing::{parse_line, parse_script};
+pub use crate::parsing::{print_script, ScriptWriter};
+pub use crate::parsing::{ParseError, ParseErrorKind, ParseErrors, Span};
+pub use crate::preview::{Preview, PreviewOptions};
+
+/// Longest wait for a complete reply
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
 
 /// Queries Newson target for status (positions, inputs/outputs state, etc.)
-pub fn get_status(stream: &mut TcpStream) -> std::io::Result<TgtStatus> {
-    let reply = exchange(&commands::get_status(), stream).unwrap();
-    let status: TgtStatus = bincode::deserialize(&reply).unwrap();
-    Ok(status)
+pub fn get_status<S: ReplyStream>(stream: &mut S) -> Result<TgtStatus, AppError> {
+    let reply = exchange(&commands::get_status(), stream)?;
+    Ok(bincode::deserialize(&reply)?)
+}
+
+/// Identifies `target`: its type, model, serial number and firmware version
+pub fn target_id<S: ReplyStream>(target: u8, stream: &mut S) -> Result<TargetId, AppError> {
+    let reply = exchange(&commands::get_target_id(target), stream)?;
+    Ok(bincode::deserialize(&reply)?)
+}
+
+/// Queries the network configuration of the controller
+pub fn get_ip<S: ReplyStream>(stream: &mut S) -> Result<IpConfig, AppError> {
+    let reply = exchange(&commands::get_ip(), stream)?;
+    Ok(bincode::deserialize(&reply)?)
+}
+
+/// Polls the target status every `interval`, yielding only the snapshots that changed
+pub fn watch_status<S: ReplyStream>(
+    stream: &mut S,
+    interval: Duration,
+) -> watch::StatusSubscription<'_, S> {
+    watch::StatusSubscription::new(stream, interval)
 }
 
-/// Sends a query and reads a reply
-pub fn exchange(queries: &[CMD3G], stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+/// Sends queries and reads their replies, concatenated in the order of the queries.
+/// The length of each reply depends on its query, see `commands::reply_len`.
+pub fn exchange<S: ReplyStream>(queries: &[CMD3G], stream: &mut S) -> Result<Vec<u8>, AppError> {
+    exchange_within(queries, stream, REPLY_TIMEOUT)
+}
+
+/// `exchange` waiting at most `timeout` for the replies
+pub(crate) fn exchange_within<S: ReplyStream>(
+    queries: &[CMD3G],
+    stream: &mut S,
+    timeout: Duration,
+) -> Result<Vec<u8>, AppError> {
     send(queries, stream)?;
-    let mut reply = [0_u8; 128];
-    let n = stream.read(&mut reply)?;
-    Ok(reply[..n].to_vec())
+    let len = queries.iter().filter_map(commands::reply_len).sum();
+    read_reply(stream, len, Instant::now() + timeout)
+}
+
+/// Stream replies are read from, whose reads can be bounded in time
+pub trait ReplyStream: Read + Write {
+    /// Reads into `buf`, blocking for at most `timeout`
+    fn read_within(&mut self, buf: &mut [u8], timeout: Duration) -> std::io::Result<usize>;
+}
+
+/// Lowers the socket read timeout to `timeout` for the read, restoring it afterwards
+impl ReplyStream for TcpStream {
+    fn read_within(&mut self, buf: &mut [u8], timeout: Duration) -> std::io::Result<usize> {
+        let previous = self.read_timeout()?;
+        self.set_read_timeout(Some(previous.map_or(timeout, |t| t.min(timeout))))?;
+        let result = self.read(buf);
+        self.set_read_timeout(previous)?;
+        result
+    }
+}
+
+/// Reads exactly `len` bytes, however they are split over reads, unless `deadline` passes
+fn read_reply<S: ReplyStream>(
+    stream: &mut S,
+    len: usize,
+    deadline: Instant,
+) -> Result<Vec<u8>, AppError> {
+    let mut reply = vec![0_u8; len];
+    let mut got = 0;
+    while got < len {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(AppError::Timeout);
+        }
+        match stream.read_within(&mut reply[got..], deadline - now) {
+            Ok(0) => return Err(AppError::ShortReply { expected: len, got }),
+            Ok(n) => got += n,
+            // the deadline decides, not the read timeout
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => continue,
+                _ => return Err(e.into()),
+            },
+        }
+    }
+    Ok(reply)
 }
 
 /// Sends commands to Newson target
-pub fn send(commands: &[CMD3G], stream: &mut TcpStream) -> std::io::Result<()> {
-    let mut buffer = serialize_commands(commands);
-    let remainder = buffer.len() % 512;
-    buffer.resize(buffer.len() + 512 - remainder, 0);
-    stream.write_all(&buffer)?;
+pub fn send<S: Write>(commands: &[CMD3G], stream: &mut S) -> Result<(), AppError> {
+    stream.write_all(&serialize_blocks(commands))?;
     Ok(())
 }
 
+/// Starts the list stored on Newson target, e.g. one compiled with ListMode::CompileAndStore
+pub fn start_list<S: Write>(target: u8, stream: &mut S) -> Result<(), AppError> {
+    send(&commands::start_list(target), stream)
+}
+
+/// Stops whatever `target` is executing
+pub fn abort<S: Write>(target: u8, stream: &mut S) -> Result<(), AppError> {
+    send(&commands::abort(target), stream)
+}
+
+/// Pauses the execution of `target`
+pub fn suspend<S: Write>(target: u8, stream: &mut S) -> Result<(), AppError> {
+    send(&commands::suspend(target), stream)
+}
+
+/// Resumes the execution of `target` after `suspend`
+pub fn resume<S: Write>(target: u8, stream: &mut S) -> Result<(), AppError> {
+    send(&commands::resume(target), stream)
+}
+
+/// Sets the run mode of `target`, e.g. `commands::RUNMODE_START`
+pub fn run_mode<S: Write>(target: u8, mode: u16, stream: &mut S) -> Result<(), AppError> {
+    send(&commands::run_mode(target, mode), stream)
+}
+
+/// Waits up to `timeout` for `target` to be idle, returning its status then.
+/// After a Timeout the late reply is still to come: the stream should be reopened.
+pub fn wait_idle<S: ReplyStream>(
+    target: u8,
+    timeout: Duration,
+    stream: &mut S,
+) -> Result<TgtStatus, AppError> {
+    acknowledged(commands::wait_idle(target), target, timeout, stream)
+}
+
+/// Waits up to `timeout` for the inputs of `target` to match `value` under `mask`,
+/// returning its status then. After a Timeout the stream should be reopened.
+pub fn wait_io<S: ReplyStream>(
+    target: u8,
+    value: u16,
+    mask: u16,
+    timeout: Duration,
+    stream: &mut S,
+) -> Result<TgtStatus, AppError> {
+    acknowledged(
+        commands::wait_io(target, value, mask),
+        target,
+        timeout,
+        stream,
+    )
+}
+
+/// Sends a waiting interrupt followed by the status request acknowledging it
+pub(crate) fn acknowledged<S: ReplyStream>(
+    mut queries: Vec<CMD3G>,
+    target: u8,
+    timeout: Duration,
+    stream: &mut S,
+) -> Result<TgtStatus, AppError> {
+    queries.extend(commands::retarget(&commands::get_status(), target));
+    let reply = exchange_within(&queries, stream, timeout)?;
+    Ok(bincode::deserialize(&reply)?)
+}
+
+/// Scans the controller bus and returns the IDs of the targets found. The reply layout is
+/// unverified, see `commands::SCAN_REPLY_LEN`; `exchange` with `commands::scan_targets`
+/// gives the raw reply.
+pub fn scan_targets<S: ReplyStream>(stream: &mut S) -> Result<Vec<u8>, AppError> {
+    let reply = exchange(&commands::scan_targets(), stream)?;
+    Ok(commands::parse_scan_reply(&reply))
+}
+
+/// Assigns the target ID `target` to the deflection head connected on `port`, see
+/// `commands::map_target`
+pub fn map_target<S: Write>(port: u8, target: u8, stream: &mut S) -> Result<(), AppError> {
+    send(&commands::map_target(port, target), stream)
+}
+
+/// Sends commands to every target at once
+pub fn broadcast<S: Write>(commands: &[CMD3G], stream: &mut S) -> Result<(), AppError> {
+    send(&commands::retarget(commands, commands::TGTALL), stream)
+}
+
+/// Decodes a CMD3G byte stream, e.g. a binary job dump, back into scanner commands
+pub fn disassemble(bytes: &[u8]) -> Result<Vec<disasm::Instruction>, disasm::DecodeError> {
+    Ok(disasm::disassemble(&disasm::decode_frames(bytes)?))
+}
+
+/// Size of the blocks the target reads commands by
+pub const BLOCK_LEN: usize = 512;
+
+/// Serializes commands padded with NOPs to whole blocks, as sent to the target
+pub(crate) fn serialize_blocks(commands: &[CMD3G]) -> Vec<u8> {
+    let mut buffer = serialize_commands(commands);
+    let remainder = buffer.len() % BLOCK_LEN;
+    buffer.resize(buffer.len() + BLOCK_LEN - remainder, 0);
+    buffer
+}
+
 /// Turns a vector of commands into a vector of bytes to be sent
-fn serialize_commands(commands: &[CMD3G]) -> Vec<u8> {
+pub(crate) fn serialize_commands(commands: &[CMD3G]) -> Vec<u8> {
     let mut buffer = Vec::new();
     commands.iter().for_each(|q| {
         buffer.append(
@@ -71,16 +319,10 @@ fn serialize_commands(commands: &[CMD3G]) -> Vec<u8> {
     buffer
 }
 
-/// Creates a vector of commands from a text file
+/// Creates a vector of commands from a text file, reporting every parse error found in it
 pub fn parse_command_file(path: &str) -> Result<Vec<ScannerCommand>, AppError> {
-    let file = File::open(path)?;
-    let mut commands = Vec::new();
-    for line in BufReader::new(file).lines() {
-        if let Some(val) = parsing::parse_line(line.unwrap().as_str())? {
-            commands.push(val)
-        }
-    }
-    Ok(commands)
+    let source = std::fs::read_to_string(path)?;
+    parsing::parse_script(&source).map_err(|e| AppError::Parse(e.in_file(path)))
 }
 
 #[cfg(test)]
@@ -92,7 +334,7 @@ mod tests {
     fn parse_file() {
         let commands = parse_command_file("resources/commands.txt").unwrap();
         let want = vec![
-            ScannerCommand::ListOpen(4),
+            ScannerCommand::ListOpen(commands::ListMode::CompileAndStart),
             ScannerCommand::SetJumpSpeed(1200.0),
             ScannerCommand::SetSpeed(1200.0),
             ScannerCommand::Jump(Position::new(-6.0, -6.0)),
@@ -116,4 +358,150 @@ mod tests {
             .zip(want.iter())
             .for_each(|(got, want)| assert_eq!(got, want));
     }
+
+    /// Stream delivering `reply` a few bytes per read, then `WouldBlock` or end of file
+    struct FakeStream {
+        reply: Vec<u8>,
+        chunk: usize,
+        open: bool,
+        written: Vec<u8>,
+    }
+    impl FakeStream {
+        fn new(reply: &[u8], chunk: usize) -> FakeStream {
+            FakeStream {
+                reply: reply.to_vec(),
+                chunk,
+                open: false,
+                written: Vec::new(),
+            }
+        }
+    }
+    impl Read for FakeStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.reply.is_empty() {
+                return match self.open {
+                    true => Err(ErrorKind::WouldBlock.into()),
+                    false => Ok(0),
+                };
+            }
+            let n = self.chunk.min(buf.len()).min(self.reply.len());
+            buf[..n].copy_from_slice(&self.reply[..n]);
+            self.reply.drain(..n);
+            Ok(n)
+        }
+    }
+    impl ReplyStream for FakeStream {
+        fn read_within(&mut self, buf: &mut [u8], _: Duration) -> std::io::Result<usize> {
+            self.read(buf)
+        }
+    }
+    impl Write for FakeStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn status_reply(inputs: u16) -> Vec<u8> {
+        let mut reply = vec![0_u8; commands::STATUS_REPLY_LEN];
+        reply[..2].copy_from_slice(&inputs.to_le_bytes());
+        reply
+    }
+
+    #[test]
+    fn framed_replies() {
+        // two replies in a row, byte by byte: each query gets its own
+        let mut replies = status_reply(0x0005);
+        replies.extend(status_reply(0x0102));
+        let mut stream = FakeStream::new(&replies, 1);
+        assert_eq!(get_status(&mut stream).unwrap().inputs().bits(), 0x0005);
+        assert_eq!(stream.written.len(), 512);
+        assert_eq!(stream.written[6], 0xC0);
+        assert_eq!(get_status(&mut stream).unwrap().inputs().bits(), 0x0102);
+
+        let mut scan = [0_u8; commands::SCAN_REPLY_LEN];
+        scan[0] = 0b0000_0010;
+        let mut stream = FakeStream::new(&scan, 3);
+        assert_eq!(scan_targets(&mut stream).unwrap(), vec![1]);
+
+        let mut stream = FakeStream::new(&status_reply(0)[..20], 1);
+        match get_status(&mut stream) {
+            Err(AppError::ShortReply { expected, got }) => {
+                assert_eq!((expected, got), (commands::STATUS_REPLY_LEN, 20))
+            }
+            other => panic!("expected a short reply, got {:?}", other),
+        }
+
+        let mut stream = FakeStream::new(&status_reply(0)[..20], 1);
+        stream.open = true;
+        let deadline = Instant::now() + Duration::from_millis(20);
+        assert!(matches!(
+            read_reply(&mut stream, commands::STATUS_REPLY_LEN, deadline),
+            Err(AppError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn identification() {
+        // complete replies, as the layouts of TargetId and IpConfig have them
+        let id = [2, 0, 0x10, 0, 0x39, 0x30, 0, 0, 1, 0, 4, 2, 0, 17, 3, 0];
+        let mut stream = FakeStream::new(&id, 5);
+        let id = target_id(3, &mut stream).unwrap();
+        assert_eq!(
+            (id.kind(), id.serial(), id.firmware()),
+            (2, 12345, (4, 2, 0, 17))
+        );
+        assert_eq!(id.id(), 3);
+
+        let ip = [
+            192, 168, 0, 6, 255, 255, 255, 0, 192, 168, 0, 1, 0, 0x50, 0xC2, 1, 2, 3,
+        ];
+        let mut stream = FakeStream::new(&ip, 5);
+        let ip = get_ip(&mut stream).unwrap();
+        assert_eq!(ip.ip(), std::net::Ipv4Addr::new(192, 168, 0, 6));
+        assert_eq!(ip.mac(), [0, 0x50, 0xC2, 1, 2, 3]);
+    }
+
+    #[test]
+    fn errors() {
+        use std::net::TcpListener;
+
+        let timeout = std::io::Error::from(ErrorKind::WouldBlock);
+        assert!(matches!(AppError::from(timeout), AppError::Timeout));
+        let refused = std::io::Error::from(ErrorKind::ConnectionRefused);
+        assert!(matches!(AppError::from(refused), AppError::Io(_)));
+
+        // a target answering INTSTATUS with a truncated status
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let target = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut block = [0_u8; 512];
+            stream.read_exact(&mut block).unwrap();
+            stream.write_all(&[0_u8; 10]).unwrap();
+        });
+        let mut stream = TcpStream::connect(address).unwrap();
+        match get_status(&mut stream) {
+            Err(AppError::ShortReply { expected, got }) => {
+                assert_eq!((expected, got), (commands::STATUS_REPLY_LEN, 10))
+            }
+            other => panic!("expected a short reply, got {:?}", other),
+        }
+        target.join().unwrap();
+
+        // a silent target times out at the deadline, without any socket read timeout
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let _silent = listener.accept().unwrap();
+        assert_eq!(stream.read_timeout().unwrap(), None);
+        let start = Instant::now();
+        let timeout = Duration::from_millis(50);
+        let result = exchange_within(&commands::get_status(), &mut stream, timeout);
+        assert!(matches!(result, Err(AppError::Timeout)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(stream.read_timeout().unwrap(), None);
+    }
 }
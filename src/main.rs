@@ -1,25 +1,44 @@
-use clap::{crate_version, App, Arg};
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::error::Error;
+use std::io::IsTerminal;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::SocketAddr;
-use std::net::{Shutdown, TcpStream};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use sthathor::commands;
+use sthathor::commands::ListMode;
+use sthathor::controller;
+use sthathor::import::{self, ImportOptions};
 use sthathor::*;
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() {
+    // errors are reported with their Display, e.g. the script location of a parse error
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
     let matches = App::new("sthathor")
         .version(crate_version!())
         .author("bwatelet")
         .about("A CLI app to control Newson™ scanners")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("IP_ADDRESS")
                 .help("Scanners IP address, e.g. 192.168.0.6")
                 .index(1)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("PORT")
+                .short("p")
+                .long("port")
+                .value_name("PORT")
+                .help("Port the scanner listens on, 10002 unless told otherwise")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("COMMANDS_FILE")
                 .short("f")
@@ -28,25 +47,794 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .help("Load a rhothor™ commands file")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("START_LIST")
+                .short("s")
+                .long("start-list")
+                .help("Start the list stored on the scanner, after sending FILE if any"),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Decode a binary CMD3G dump back into rhothor™ commands")
+                .arg(
+                    Arg::with_name("BINARY_FILE")
+                        .help("Captured traffic or job dump")
+                        .index(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("estimate")
+                .about("Estimate how long a commands file takes to execute")
+                .arg(
+                    Arg::with_name("COMMANDS_FILE")
+                        .short("f")
+                        .long("file")
+                        .value_name("FILE")
+                        .help("rhothor™ commands file")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("JSON")
+                        .long("json")
+                        .help("Print a JSON object instead of a list"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("preview")
+                .about("Draw what a commands file marks as an SVG or PNG picture")
+                .arg(
+                    Arg::with_name("COMMANDS_FILE")
+                        .short("f")
+                        .long("file")
+                        .value_name("FILE")
+                        .help("rhothor™ commands file")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .short("o")
+                        .long("output")
+                        .value_name("PICTURE")
+                        .help("Output file, a PNG if it ends in .png and an SVG otherwise")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("SIZE")
+                        .long("size")
+                        .value_name("PIXELS")
+                        .help("Longest side of the picture")
+                        .default_value("1024")
+                        .validator(|s| {
+                            s.parse::<u32>()
+                                .ok()
+                                .filter(|n| (1..=preview::MAX_SIZE).contains(n))
+                                .map(|_| ())
+                                .ok_or_else(|| {
+                                    format!("invalid size `{}`, from 1 to {}", s, preview::MAX_SIZE)
+                                })
+                        }),
+                )
+                .arg(
+                    Arg::with_name("COLOR_BY_SPEED")
+                        .long("color-by-speed")
+                        .help("Colour marks from blue for the slowest to red for the fastest"),
+                )
+                .arg(
+                    Arg::with_name("NO_JUMPS")
+                        .long("no-jumps")
+                        .help("Leave jumps and moves out"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Convert a drawing into rhothor™ commands")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(drawing_args(
+                    SubCommand::with_name("svg").about("Import the shapes of an SVG file"),
+                ))
+                .subcommand(
+                    drawing_args(
+                        SubCommand::with_name("dxf").about("Import the geometry of a DXF file"),
+                    )
+                    .arg(parameters_arg(
+                        "LAYER",
+                        "layer",
+                        "NAME=[SPEED][,ON_DELAY,OFF_DELAY]",
+                    )),
+                )
+                .subcommand(
+                    drawing_args(
+                        SubCommand::with_name("hpgl")
+                            .about("Import the plot of an HPGL file")
+                            .alias("plt"),
+                    )
+                    .arg(
+                        Arg::with_name("UNIT")
+                            .long("unit")
+                            .value_name("MM")
+                            .help("Millimetres per plotter unit")
+                            .default_value("0.025")
+                            .validator(|s| {
+                                s.parse::<f64>()
+                                    .ok()
+                                    .filter(|n| *n > 0.0)
+                                    .map(|_| ())
+                                    .ok_or_else(|| format!("invalid length `{}`", s))
+                            }),
+                    )
+                    .arg(parameters_arg(
+                        "PEN",
+                        "pen",
+                        "NUMBER=[SPEED][,ON_DELAY,OFF_DELAY]",
+                    )),
+                )
+                .subcommand(import_args(SubCommand::with_name("gcode").about(
+                    "Translate a G-code program, warning about what it leaves out",
+                ))),
+        )
+        .subcommand(
+            SubCommand::with_name("mock")
+                .about("Simulate a scanner, printing the commands it receives")
+                .arg(
+                    Arg::with_name("LISTEN")
+                        .short("l")
+                        .long("listen")
+                        .value_name("ADDRESS")
+                        .help("Address to listen on")
+                        .default_value("127.0.0.1:10002"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Poll the scanner status and print every change")
+                .arg(
+                    Arg::with_name("INTERVAL")
+                        .short("i")
+                        .long("interval")
+                        .value_name("DURATION")
+                        .help("Polling interval, e.g. 100ms or 2s")
+                        .default_value("100ms")
+                        .validator(|s| parse_duration(&s).map(|_| ())),
+                )
+                .arg(
+                    Arg::with_name("JSON")
+                        .long("json")
+                        .help("Print JSON lines instead of a table"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Identify the scanner and print its network configuration")
+                .arg(
+                    Arg::with_name("JSON")
+                        .long("json")
+                        .help("Print a JSON object instead of a list"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("abort").about("Stop the job running on the scanner"))
+        .subcommand(SubCommand::with_name("pause").about("Suspend the job running on the scanner"))
+        .subcommand(SubCommand::with_name("resume").about("Resume a paused job"))
+        .subcommand(
+            SubCommand::with_name("wait-idle")
+                .about("Wait until the scanner is done with its job")
+                .arg(
+                    Arg::with_name("TIMEOUT")
+                        .short("t")
+                        .long("timeout")
+                        .value_name("DURATION")
+                        .help("Longest wait, e.g. 30s")
+                        .default_value("60s")
+                        .validator(|s| parse_duration(&s).map(|_| ())),
+                ),
+        )
         .get_matches();
 
-    let port = 10002;
-    let ip_address = match matches.value_of("IP_ADDRESS").unwrap().parse::<Ipv4Addr>() {
+    if let Some(matches) = matches.subcommand_matches("disasm") {
+        let path = matches.value_of("BINARY_FILE").unwrap();
+        let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let instructions = disassemble(&bytes).map_err(|e| format!("{}: {}", path, e))?;
+        // the listing is a valid script, frames and offsets going into comments
+        let stdout = std::io::stdout();
+        let mut writer = ScriptWriter::new(stdout.lock());
+        for instruction in instructions {
+            let start = instruction.index * disasm::FRAME_LEN;
+            let end = start + instruction.len * disasm::FRAME_LEN;
+            let hex = bytes[start..end]
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<String>>()
+                .join(" ");
+            let comment = format!("{:06x}: {}", start, hex);
+            match instruction.decoded {
+                disasm::Decoded::Command(command) => {
+                    writer.command_with_comment(&command, &comment)?
+                }
+                disasm::Decoded::Raw(frame) => {
+                    writer.comment(&format!("{} {:?}", comment, frame))?
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("estimate") {
+        let path = matches.value_of("COMMANDS_FILE").unwrap();
+        let estimate = Estimator::new().estimate(&load_script(path)?);
+        if estimate.untimed > 0 {
+            eprintln!(
+                "{}: warning: {} moves made before any speed is set are not timed",
+                path, estimate.untimed
+            );
+        }
+        if estimate.input_dependent > 0 {
+            eprintln!(
+                "{}: warning: {} waits and loops depend on the inputs, counted as immediate or running once",
+                path, estimate.input_dependent
+            );
+        }
+        if matches.is_present("JSON") {
+            println!("{}", estimate_json(&estimate));
+        } else {
+            print!("{}", estimate_list(&estimate));
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("preview") {
+        let path = matches.value_of("COMMANDS_FILE").unwrap();
+        let output = matches.value_of("OUTPUT").unwrap();
+        let preview = Preview::new(&load_script(path)?);
+        let options = PreviewOptions::new()
+            .with_size(matches.value_of("SIZE").unwrap().parse()?)
+            .with_color_by_speed(matches.is_present("COLOR_BY_SPEED"))
+            .with_jumps(!matches.is_present("NO_JUMPS"));
+        let picture = if output.to_lowercase().ends_with(".png") {
+            png(&preview, &options)?
+        } else {
+            preview.to_svg(&options).into_bytes()
+        };
+        std::fs::write(output, picture).map_err(|e| format!("{}: {}", output, e))?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("import") {
+        let (format, matches) = match matches.subcommand() {
+            (format, Some(matches)) => (format, matches),
+            _ => unreachable!(),
+        };
+        let path = matches.value_of("DRAWING").unwrap();
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut options = ImportOptions::new();
+        if let Some(tolerance) = matches.value_of("TOLERANCE") {
+            options = options.with_tolerance(tolerance.parse()?);
+        }
+        if let Some(field) = matches.value_of("FIELD") {
+            options = options.with_field(field.parse()?);
+        }
+        if let Some(unit) = matches.value_of("UNIT") {
+            options = options.with_plotter_unit(unit.parse()?);
+        }
+        if let (Some(speed), Some(jump_speed)) =
+            (matches.value_of("SPEED"), matches.value_of("JUMP_SPEED"))
+        {
+            options = options.with_speeds(speed.parse()?, jump_speed.parse()?);
+        }
+        let layers = matches.values_of("LAYER").into_iter().flatten();
+        for layer in layers.chain(matches.values_of("PEN").into_iter().flatten()) {
+            let (name, parameters) = parse_parameters(layer)?;
+            options = options.with_layer(name, parameters);
+        }
+        let imported = match format {
+            "svg" => import::import_svg(&text, &options),
+            "dxf" => import::import_dxf(&text, &options),
+            "hpgl" => import::import_hpgl(&text, &options),
+            "gcode" => import::import_gcode(&text, &options).map(|(commands, warnings)| {
+                for warning in warnings {
+                    eprintln!("{}: warning: {}", path, warning);
+                }
+                commands
+            }),
+            _ => unreachable!(),
+        };
+        let mut commands = vec![ScannerCommand::ListOpen(ListMode::CompileAndStart)];
+        commands.extend(imported.map_err(|e| format!("{}: {}", path, e))?);
+        commands.push(ScannerCommand::ListClose);
+
+        match (matches.value_of("SEND"), matches.value_of("OUTPUT")) {
+            (Some(ip), _) => {
+                let ip_address = ip
+                    .parse::<Ipv4Addr>()
+                    .map_err(|_| "Failed to parse IP address")?;
+                let mut compiler = Compiler::new();
+                let compiled = compiler.compile(&commands);
+                for warning in compiler.warnings() {
+                    eprintln!("{}: warning: {}", path, warning);
+                }
+                let command_list = compiled.map_err(|e| format!("{}: {}", path, e))?;
+                let socket_address = SocketAddr::new(IpAddr::V4(ip_address), port(matches)?);
+                let mut controller = Controller::connect(socket_address, ConnectOptions::new())
+                    .map_err(|e| format!("{}: {}", socket_address, e))?;
+                controller.send(&command_list)?;
+            }
+            (None, Some(output)) => std::fs::write(output, print_script(&commands))
+                .map_err(|e| format!("{}: {}", output, e))?,
+            (None, None) => print!("{}", print_script(&commands)),
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("mock") {
+        let address = matches.value_of("LISTEN").unwrap();
+        let mock = MockController::bind(address).map_err(|e| format!("{}: {}", address, e))?;
+        eprintln!("listening on {}", mock.address());
+        mock.state().record = true;
+        let stdout = std::io::stdout();
+        let mut writer = ScriptWriter::new(stdout.lock());
+        loop {
+            let frames = std::mem::take(&mut mock.state().received);
+            for instruction in disasm::disassemble(&frames) {
+                match instruction.decoded {
+                    disasm::Decoded::Command(command) => writer.command(&command)?,
+                    disasm::Decoded::Raw(frame) => writer.comment(&format!("{:?}", frame))?,
+                }
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    let ip_address = match matches
+        .value_of("IP_ADDRESS")
+        .ok_or("Missing IP address")?
+        .parse::<Ipv4Addr>()
+    {
         Ok(addr) => addr,
         Err(_) => return Err(From::from("Failed to parse IP address")),
     };
 
-    let socket_address = SocketAddr::new(IpAddr::V4(ip_address), port);
-    let mut stream = TcpStream::connect_timeout(&socket_address, Duration::new(5, 0))?;
+    // Parse the commands file before connecting so that script errors are reported first
+    let command_list = match matches.value_of("COMMANDS_FILE") {
+        Some(path) => {
+            let commands = load_script(path)?;
+            let mut compiler = Compiler::new();
+            let compiled = compiler.compile(&commands);
+            for warning in compiler.warnings() {
+                eprintln!("{}: warning: {}", path, warning);
+            }
+            Some(compiled.map_err(|e| format!("{}: {}", path, e))?)
+        }
+        None => None,
+    };
+
+    let socket_address = SocketAddr::new(IpAddr::V4(ip_address), port(&matches)?);
+    let mut controller = Controller::connect(socket_address, ConnectOptions::new())
+        .map_err(|e| format!("{}: {}", socket_address, e))?;
 
-    if matches.is_present("COMMANDS_FILE") {
-        let command_list = commands::build_commandlist(&parse_command_file(
-            matches.value_of("COMMANDS_FILE").unwrap(),
-        )?);
-        send(&command_list, &mut stream)?;
+    if let Some(command_list) = command_list {
+        controller.send(&command_list)?;
+    }
+    if matches.is_present("START_LIST") {
+        controller.start_list()?;
+    }
+    match matches.subcommand() {
+        ("info", Some(info_matches)) => {
+            // the reply layouts are unchecked against a card, so the raw bytes come first
+            let id_reply = controller.query(&commands::get_target_id(commands::TARGET))?;
+            let ip_reply = controller.query(&commands::get_ip())?;
+            let id: TargetId = bincode::deserialize(&id_reply).map_err(AppError::from)?;
+            let ip: IpConfig = bincode::deserialize(&ip_reply).map_err(AppError::from)?;
+            if info_matches.is_present("JSON") {
+                println!("{}", info_json(&id_reply, &ip_reply, &id, &ip));
+            } else {
+                print!("{}", info_list(&id_reply, &ip_reply, &id, &ip));
+            }
+        }
+        ("abort", _) => controller.abort()?,
+        ("pause", _) => controller.suspend()?,
+        ("resume", _) => controller.resume()?,
+        ("wait-idle", Some(wait_matches)) => {
+            let timeout = parse_duration(wait_matches.value_of("TIMEOUT").unwrap())?;
+            controller.wait_idle(timeout)?;
+        }
+        ("watch", Some(watch_matches)) => {
+            let interval = parse_duration(watch_matches.value_of("INTERVAL").unwrap())?;
+            watch(&mut controller, interval, watch_matches.is_present("JSON"))?;
+        }
+        _ => {}
     }
 
-    stream.shutdown(Shutdown::Both)?;
+    Ok(())
+}
 
+/// Arguments shared by the `import` subcommands
+fn import_args<'a, 'b>(subcommand: App<'a, 'b>) -> App<'a, 'b> {
+    let speed = |s: String| {
+        s.parse::<f32>()
+            .ok()
+            .filter(|n| *n > 0.0)
+            .map(|_| ())
+            .ok_or_else(|| format!("invalid speed `{}`", s))
+    };
+    subcommand
+        .arg(
+            Arg::with_name("DRAWING")
+                .help("File to import")
+                .index(1)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("OUTPUT")
+                .short("o")
+                .long("output")
+                .value_name("SCRIPT")
+                .help("Write the commands to SCRIPT rather than to the standard output")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("SEND")
+                .long("send")
+                .value_name("IP_ADDRESS")
+                .help("Send the commands to the scanner, which runs them")
+                .takes_value(true)
+                .conflicts_with("OUTPUT")
+                .requires_all(&["SPEED", "JUMP_SPEED"]),
+        )
+        .arg(
+            Arg::with_name("PORT")
+                .long("port")
+                .value_name("PORT")
+                .help("Port the scanner listens on with --send, 10002 unless told otherwise")
+                .takes_value(true)
+                .requires("SEND"),
+        )
+        .arg(
+            Arg::with_name("SPEED")
+                .long("speed")
+                .value_name("MM_S")
+                .help("Marking speed of the figures given none of their own")
+                .takes_value(true)
+                .requires("JUMP_SPEED")
+                .validator(speed),
+        )
+        .arg(
+            Arg::with_name("JUMP_SPEED")
+                .long("jump-speed")
+                .value_name("MM_S")
+                .help("Speed of the jumps between figures")
+                .takes_value(true)
+                .requires("SPEED")
+                .validator(speed),
+        )
+        .arg(
+            Arg::with_name("FIELD")
+                .long("field")
+                .value_name("MM")
+                .help("Scale the drawing down to fit a square field centred on the origin")
+                .takes_value(true)
+                .validator(length),
+        )
+}
+
+/// Arguments of the `import` subcommands reading drawings rather than toolpaths
+fn drawing_args<'a, 'b>(subcommand: App<'a, 'b>) -> App<'a, 'b> {
+    import_args(subcommand).arg(
+        Arg::with_name("TOLERANCE")
+            .long("tolerance")
+            .value_name("MM")
+            .help("Largest deviation of the lines replacing curves")
+            .default_value("0.01")
+            .validator(length),
+    )
+}
+
+/// Validates a positive length in millimetres
+fn length(s: String) -> Result<(), String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|n| *n > 0.0)
+        .map(|_| ())
+        .ok_or_else(|| format!("invalid length `{}`", s))
+}
+
+#[cfg(feature = "png")]
+fn png(preview: &Preview, options: &PreviewOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(preview.to_png(options)?)
+}
+
+#[cfg(not(feature = "png"))]
+fn png(_: &Preview, _: &PreviewOptions) -> Result<Vec<u8>, Box<dyn Error>> {
+    Err("PNG previews need sthathor built with `--features png`".into())
+}
+
+/// Parses a commands file, naming the file in I/O errors
+fn load_script(path: &str) -> Result<Vec<ScannerCommand>, Box<dyn Error>> {
+    match parse_command_file(path) {
+        Err(AppError::Io(e)) => Err(format!("{}: {}", path, e).into()),
+        result => Ok(result?),
+    }
+}
+
+/// Repeatable argument giving the marking parameters of a layer or pen
+fn parameters_arg<'a>(name: &'a str, long: &'a str, value_name: &'a str) -> Arg<'a, 'a> {
+    Arg::with_name(name)
+        .long(long)
+        .value_name(value_name)
+        .help("Marking speed in mm/s and laser delays in µs of the figures drawn with it")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .validator(|s| parse_parameters(&s).map(|_| ()))
+}
+
+/// Parses layer parameters such as `CUT=200`, `CUT=200,100,150` or `CUT=,100,150`
+fn parse_parameters(s: &str) -> Result<(&str, import::Parameters), String> {
+    let invalid = || format!("invalid parameters `{}`", s);
+    let (name, values) = s.split_once('=').ok_or_else(invalid)?;
+    let values: Vec<&str> = values.split(',').map(str::trim).collect();
+    let mut parameters = import::Parameters::new();
+    match values[0] {
+        "" => {}
+        speed => parameters = parameters.with_speed(speed.parse().map_err(|_| invalid())?),
+    }
+    match values[1..] {
+        [] => {}
+        [on, off] => {
+            let on = on.parse().map_err(|_| invalid())?;
+            let off = off.parse().map_err(|_| invalid())?;
+            parameters = parameters.with_laser_times(on, off);
+        }
+        _ => return Err(invalid()),
+    }
+    Ok((name, parameters))
+}
+
+/// Parses a duration such as `100ms`, `2s` or `250` (milliseconds)
+/// Port given with --port, the controller's default otherwise
+fn port(matches: &ArgMatches) -> Result<u16, String> {
+    match matches.value_of("PORT") {
+        Some(port) => port
+            .parse()
+            .map_err(|_| format!("Failed to parse port {}", port)),
+        None => Ok(controller::DEFAULT_PORT),
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, scale) = if let Some(ms) = s.strip_suffix("ms") {
+        (ms, 1e-3)
+    } else if let Some(secs) = s.strip_suffix('s') {
+        (secs, 1.0)
+    } else {
+        (s, 1e-3)
+    };
+    match value.trim().parse::<f64>() {
+        Ok(value) if value.is_finite() && value >= 0.0 => {
+            Ok(Duration::from_secs_f64(value * scale))
+        }
+        _ => Err(format!(
+            "invalid duration `{}`, expected e.g. 100ms or 2s",
+            s
+        )),
+    }
+}
+
+fn estimate_list(estimate: &Estimate) -> String {
+    format!(
+        "total:    {:.3} s\nmarking:  {:.3} s, {:.3} mm\njumps:    {:.3} s, {:.3} mm\n\
+         waits:    {:.3} s\nlaser on: {:.3} s\n",
+        estimate.total.as_secs_f64(),
+        estimate.mark_time.as_secs_f64(),
+        estimate.mark_length,
+        estimate.jump_time.as_secs_f64(),
+        estimate.jump_length,
+        estimate.wait_time.as_secs_f64(),
+        estimate.laser_on.as_secs_f64()
+    )
+}
+
+fn estimate_json(estimate: &Estimate) -> String {
+    format!(
+        "{{\"total\":{:.6},\"mark_time\":{:.6},\"jump_time\":{:.6},\"wait_time\":{:.6},\
+         \"laser_on\":{:.6},\"mark_length\":{:.6},\"jump_length\":{:.6},\"untimed\":{},\
+         \"input_dependent\":{}}}",
+        estimate.total.as_secs_f64(),
+        estimate.mark_time.as_secs_f64(),
+        estimate.jump_time.as_secs_f64(),
+        estimate.wait_time.as_secs_f64(),
+        estimate.laser_on.as_secs_f64(),
+        estimate.mark_length,
+        estimate.jump_length,
+        estimate.untimed,
+        estimate.input_dependent
+    )
+}
+
+fn firmware(id: &TargetId) -> String {
+    let (major, minor, patch, build) = id.firmware();
+    format!("{}.{}.{}+{}", major, minor, patch, build)
+}
+
+fn mac(ip: &IpConfig) -> String {
+    let bytes: Vec<String> = ip.mac().iter().map(|b| format!("{:02x}", b)).collect();
+    bytes.join(":")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    bytes.join(" ")
+}
+
+fn info_list(id_reply: &[u8], ip_reply: &[u8], id: &TargetId, ip: &IpConfig) -> String {
+    format!(
+        "id reply: {}\nip reply: {}\ndecoded, unverified against a card:\n\
+         target:   {}\ntype:     {:#06x}\nmodel:    {:#06x}\nserial:   {}\nhardware: {}\n\
+         firmware: {}\nip:       {}\nmask:     {}\ngateway:  {}\nmac:      {}\n",
+        hex(id_reply),
+        hex(ip_reply),
+        id.id(),
+        id.kind(),
+        id.model(),
+        id.serial(),
+        id.hardware(),
+        firmware(id),
+        ip.ip(),
+        ip.mask(),
+        ip.gateway(),
+        mac(ip)
+    )
+}
+
+fn info_json(id_reply: &[u8], ip_reply: &[u8], id: &TargetId, ip: &IpConfig) -> String {
+    format!(
+        "{{\"id_reply\":\"{}\",\"ip_reply\":\"{}\",\
+         \"unverified\":{{\"target\":{},\"type\":{},\"model\":{},\"serial\":{},\"hardware\":{},\
+         \"firmware\":\"{}\",\"ip\":\"{}\",\"mask\":\"{}\",\"gateway\":\"{}\",\"mac\":\"{}\"}}}}",
+        hex(id_reply),
+        hex(ip_reply),
+        id.id(),
+        id.kind(),
+        id.model(),
+        id.serial(),
+        id.hardware(),
+        firmware(id),
+        ip.ip(),
+        ip.mask(),
+        ip.gateway(),
+        mac(ip)
+    )
+}
+
+/// Prints every status change until a call fails
+fn watch(controller: &mut Controller, interval: Duration, json: bool) -> Result<(), AppError> {
+    let start = Instant::now();
+    let highlight = std::io::stdout().is_terminal();
+    if !json {
+        println!("{}", table_header(highlight));
+    }
+    for update in controller.watch_status(interval) {
+        let update = update?;
+        let elapsed = start.elapsed().as_millis();
+        if json {
+            println!("{}", json_line(elapsed, &update));
+        } else {
+            println!("{}", table_row(elapsed, &update, highlight));
+        }
+    }
     Ok(())
 }
+
+fn json_line(elapsed: u128, update: &watch::StatusUpdate) -> String {
+    let status = &update.status;
+    let analog = status.analogs();
+    let (otf, table, position) = (status.otf(), status.table(), status.deflection());
+    let changed = update
+        .diff
+        .fields()
+        .iter()
+        .map(|field| format!("\"{}\"", field))
+        .collect::<Vec<String>>();
+    format!(
+        "{{\"time_ms\":{},\"inputs\":{},\"outputs\":{},\"state\":{},\"idle\":{},\"return_value\":{},\"counter\":{},\"analog\":[{},{},{},{}],\"otf\":[{},{}],\"table\":[{},{},{}],\"deflection\":[{},{},{}],\"changed\":[{}],\"changed_inputs\":{},\"changed_outputs\":{}}}",
+        elapsed,
+        status.inputs().bits(),
+        status.outputs().bits(),
+        status.system_state().bits(),
+        status.system_state().is_idle(),
+        status.return_value(),
+        status.counter(),
+        analog[0],
+        analog[1],
+        analog[2],
+        analog[3],
+        otf.0,
+        otf.1,
+        table.0,
+        table.1,
+        table.2,
+        position.x(),
+        position.y(),
+        status.deflection_z(),
+        changed.join(","),
+        update.diff.inputs,
+        update.diff.outputs,
+    )
+}
+
+/// Column titles, padded like the rows printed with the same `highlight`
+fn table_header(highlight: bool) -> String {
+    let pad = if highlight { "" } else { " " };
+    format!(
+        "{:>8}  {:<16}{pad}  {:<16}{pad}  {:>4}{pad}  {:>5}{pad}  {:>15}{pad}  {:>32}{pad}",
+        "time ms",
+        "inputs 15..0",
+        "outputs 15..0",
+        "idle",
+        "count",
+        "analog",
+        "x mm        y mm      z mm",
+        pad = pad
+    )
+}
+
+fn table_row(elapsed: u128, update: &watch::StatusUpdate, highlight: bool) -> String {
+    let status = &update.status;
+    // the first snapshot has nothing to be compared with
+    let diff = if update.diff == watch::StatusDiff::all() {
+        watch::StatusDiff::default()
+    } else {
+        update.diff.clone()
+    };
+    // changed values are shown in reverse video on a terminal, fields are followed by
+    // `*` when changed otherwise
+    let invert = |text: String, changed: bool| match changed && highlight {
+        true => format!("\x1b[7m{}\x1b[0m", text),
+        false => text,
+    };
+    let field = |text: String, changed: bool| match (highlight, changed) {
+        (true, _) => invert(text, changed),
+        (false, true) => format!("{}*", text),
+        (false, false) => format!("{} ", text),
+    };
+    let pins = |pins: IoPins, changed: u16| {
+        let bits = (0..16)
+            .rev()
+            .map(|pin| {
+                let bit = if pins.pin(pin) { "1" } else { "0" };
+                invert(bit.to_string(), changed & (1 << pin) != 0)
+            })
+            .collect::<String>();
+        field(bits, !highlight && changed != 0)
+    };
+    let analog = status
+        .analogs()
+        .iter()
+        .zip(diff.analog.iter())
+        .map(|(value, changed)| invert(format!("{:>3}", value), *changed))
+        .collect::<Vec<String>>()
+        .join(" ");
+    let position = status.deflection();
+    format!(
+        "{:>8}  {}  {}  {}  {}  {}  {}",
+        elapsed,
+        pins(status.inputs(), diff.inputs),
+        pins(status.outputs(), diff.outputs),
+        field(
+            format!("{:>4}", status.system_state().is_idle()),
+            diff.system_state
+        ),
+        field(format!("{:>5}", status.counter()), diff.counter),
+        field(analog, !highlight && diff.analog.iter().any(|c| *c)),
+        field(
+            format!(
+                "{:>10.3}  {:>10.3}  {:>8.3}",
+                position.x(),
+                position.y(),
+                status.deflection_z()
+            ),
+            diff.deflection
+        ),
+    )
+}
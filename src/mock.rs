@@ -0,0 +1,664 @@
+use crate::commands::{
+    self, IoPins, ListMode, Position, CMD3G, CMD3G_OPCODE, RUNMODE_START, SCAN_REPLY_LEN, SYSIDLE,
+    TARGET, TGTALL,
+};
+use crate::disasm::{self, Decoded, FRAME_LEN};
+use crate::estimate::Loops;
+use crate::parsing::ScannerCommand;
+use crate::{AppError, BLOCK_LEN};
+use std::convert::TryFrom;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Target type reported by the mock in its INTGTID reply
+pub const MOCK_KIND: u16 = 0x4D4B;
+
+/// Simulated state of a target. Commands take effect as soon as they are executed; only
+/// sleeps, bursts and unmatched WaitIOs take time, keeping the target busy.
+#[derive(Debug, Clone)]
+pub struct MockState {
+    /// ID the target answers to, besides TGTALL
+    pub target: u8,
+    pub position: Position,
+    pub inputs: u16,
+    pub outputs: u16,
+    /// Analog outputs set by SetAnalog
+    pub analog_outputs: u16,
+    /// Analog inputs reported in the status
+    pub analog: [u8; 4],
+    pub speed: f32,
+    pub jump_speed: f32,
+    pub laser: bool,
+    /// Frames of the list stored on the target
+    pub list: Vec<CMD3G>,
+    /// Mode of the list being received, None outside ListOpen/ListClose
+    pub list_mode: Option<ListMode>,
+    /// Number of lists started
+    pub counter: u16,
+    pub serial: u32,
+    /// Firmware version as (major, minor, patch, build)
+    pub firmware: [u8; 4],
+    pub ip: Ipv4Addr,
+    pub mask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub mac: [u8; 6],
+    /// Whether to keep the frames received in `received`, off by default
+    pub record: bool,
+    /// Every frame received while `record` is on, in order, interrupts and padding included
+    pub received: Vec<CMD3G>,
+    run: Run,
+}
+
+/// Execution unit of the target
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Run {
+    Idle,
+    /// Busy until the given time, or until aborted
+    Busy(Option<Instant>),
+    /// Suspended with the given time left, None for a run that never ends
+    Suspended(Option<Duration>),
+}
+
+/// What a frame asks of the connection that received it
+#[derive(Debug, PartialEq)]
+enum Action {
+    None,
+    Reply(Vec<u8>),
+    /// Hold back the following frames until the target is idle
+    WaitIdle,
+    /// Hold back the following frames until the inputs match (value, mask)
+    WaitIo(u16, u16),
+}
+
+impl MockState {
+    pub fn new(target: u8) -> MockState {
+        MockState {
+            target,
+            position: Position::new(0.0, 0.0),
+            inputs: 0,
+            outputs: 0,
+            analog_outputs: 0,
+            analog: [0; 4],
+            speed: 0.0,
+            jump_speed: 0.0,
+            laser: false,
+            list: Vec::new(),
+            list_mode: None,
+            counter: 0,
+            serial: 1,
+            firmware: [1, 0, 0, 0],
+            ip: Ipv4Addr::LOCALHOST,
+            mask: Ipv4Addr::new(255, 255, 255, 0),
+            gateway: Ipv4Addr::UNSPECIFIED,
+            mac: [0x02, 0, 0, 0, 0, target],
+            record: false,
+            received: Vec::new(),
+            run: Run::Idle,
+        }
+    }
+
+    /// Whether the target is done executing commands
+    pub fn is_idle(&mut self) -> bool {
+        if let Run::Busy(Some(until)) = self.run {
+            if Instant::now() >= until {
+                self.run = Run::Idle;
+            }
+        }
+        self.run == Run::Idle
+    }
+
+    /// Serialized TgtStatus, as replied to INTSTATUS
+    pub fn status(&mut self) -> Vec<u8> {
+        let me = if self.is_idle() { SYSIDLE } else { 0 };
+        let mut reply = Vec::with_capacity(commands::STATUS_REPLY_LEN);
+        reply.extend_from_slice(&self.inputs.to_le_bytes());
+        reply.extend_from_slice(&self.outputs.to_le_bytes());
+        reply.extend_from_slice(&[me, 0]);
+        reply.extend_from_slice(&self.counter.to_le_bytes());
+        reply.extend_from_slice(&self.analog);
+        // on-the-fly and table positions are not simulated
+        reply.extend_from_slice(&[0; 5 * 4]);
+        let deflection = [self.position.x(), self.position.y(), 0.0];
+        for value in &deflection {
+            reply.extend_from_slice(&((value * 1000.0).round() as i32).to_le_bytes());
+        }
+        reply
+    }
+
+    /// Serialized TargetId, as replied to INTGTID
+    pub fn target_id(&self) -> Vec<u8> {
+        let mut reply = Vec::with_capacity(commands::TARGET_ID_REPLY_LEN);
+        reply.extend_from_slice(&MOCK_KIND.to_le_bytes());
+        reply.extend_from_slice(&1_u16.to_le_bytes());
+        reply.extend_from_slice(&self.serial.to_le_bytes());
+        reply.extend_from_slice(&1_u16.to_le_bytes());
+        reply.extend_from_slice(&self.firmware);
+        reply.extend_from_slice(&[self.target, 0]);
+        reply
+    }
+
+    /// Serialized IpConfig, as replied to INTGETIP
+    pub fn ip_config(&self) -> Vec<u8> {
+        let mut reply = Vec::with_capacity(commands::IP_REPLY_LEN);
+        reply.extend_from_slice(&self.ip.octets());
+        reply.extend_from_slice(&self.mask.octets());
+        reply.extend_from_slice(&self.gateway.octets());
+        reply.extend_from_slice(&self.mac);
+        reply
+    }
+
+    /// Executes frames as the target would outside of a list
+    pub fn execute(&mut self, frames: &[CMD3G]) {
+        let time = self.run_frames(frames);
+        let start = match self.run {
+            Run::Busy(Some(until)) if until > Instant::now() => until,
+            _ => Instant::now(),
+        };
+        self.run = match (self.run, time) {
+            (Run::Busy(None), _) | (_, None) => Run::Busy(None),
+            (Run::Suspended(left), Some(time)) => Run::Suspended(left.map(|left| left + time)),
+            (_, Some(time)) => Run::Busy(Some(start + time)),
+        };
+    }
+
+    /// Interprets frames, returning the time they take, None if they never end
+    fn run_frames(&mut self, frames: &[CMD3G]) -> Option<Duration> {
+        let instructions: Vec<ScannerCommand> = disasm::disassemble(frames)
+            .into_iter()
+            .filter_map(|instruction| match instruction.decoded {
+                Decoded::Command(command) => Some(command),
+                Decoded::Raw(_) => None,
+            })
+            .collect();
+        let mut time = Some(Duration::from_secs(0));
+        let mut loops = Loops::default();
+        let mut i = 0;
+        while i < instructions.len() {
+            match &instructions[i] {
+                ScannerCommand::SetLoop(_) | ScannerCommand::DoLoop => {}
+                // the inputs do not change during a run, a loop entered runs once
+                ScannerCommand::WhileIO(value, mask) => {
+                    if !IoPins(self.inputs).matches(*value, *mask) {
+                        i = end_of_while(&instructions, i);
+                    }
+                }
+                command => {
+                    let taken = self.apply(command);
+                    time = time.and_then(|time| Some(time + taken?));
+                }
+            }
+            i = loops.next(&instructions, i);
+        }
+        time
+    }
+
+    /// Applies a single command, returning the time it takes
+    fn apply(&mut self, command: &ScannerCommand) -> Option<Duration> {
+        match command {
+            ScannerCommand::Jump(position)
+            | ScannerCommand::Move(position)
+            | ScannerCommand::Line(position)
+            | ScannerCommand::Arc(position, _) => self.position = *position,
+            ScannerCommand::Circle(center, angle) => {
+                self.position = self.position.rotate(center, *angle as f64)
+            }
+            ScannerCommand::SetIO(value, mask) => {
+                self.outputs = self.outputs & !mask | value & mask
+            }
+            ScannerCommand::SetAnalog(value, mask) => {
+                self.analog_outputs = self.analog_outputs & !mask | value & mask
+            }
+            ScannerCommand::SetSpeed(speed) => self.speed = *speed,
+            ScannerCommand::SetJumpSpeed(speed) => self.jump_speed = *speed,
+            ScannerCommand::SetLaser(on) => self.laser = *on,
+            ScannerCommand::Sleep(ms) | ScannerCommand::Burst(ms) => {
+                return Some(Duration::from_millis(*ms as u64))
+            }
+            ScannerCommand::WaitIO(value, mask, timeout) => {
+                if IoPins(self.inputs).matches(*value, *mask) {
+                    return Some(Duration::from_secs(0));
+                }
+                // a zero timeout waits forever
+                return match timeout {
+                    0 => None,
+                    ms => Some(Duration::from_millis(*ms as u64)),
+                };
+            }
+            _ => {}
+        }
+        Some(Duration::from_secs(0))
+    }
+
+    /// Handles an interrupt frame
+    fn interrupt(&mut self, frame: &CMD3G) -> Action {
+        let len = commands::reply_len(frame).unwrap_or(0);
+        let mut reply = match frame.op_code() {
+            CMD3G_OPCODE::INTSTATUS => self.status(),
+            CMD3G_OPCODE::INTGTID => self.target_id(),
+            CMD3G_OPCODE::INTGETIP => self.ip_config(),
+            CMD3G_OPCODE::INTSCANTGT => {
+                let mut bitmap = vec![0_u8; SCAN_REPLY_LEN];
+                bitmap[self.target as usize / 8] |= 1 << (self.target % 8);
+                bitmap
+            }
+            CMD3G_OPCODE::INTRUNMODE if frame.x() == RUNMODE_START => {
+                self.counter = self.counter.wrapping_add(1);
+                let list = self.list.clone();
+                self.execute(&list);
+                return Action::None;
+            }
+            CMD3G_OPCODE::INTABORT => {
+                self.run = Run::Idle;
+                return Action::None;
+            }
+            CMD3G_OPCODE::INTSUSPEND => {
+                if !self.is_idle() {
+                    if let Run::Busy(until) = self.run {
+                        let left =
+                            until.map(|until| until.saturating_duration_since(Instant::now()));
+                        self.run = Run::Suspended(left);
+                    }
+                }
+                return Action::None;
+            }
+            CMD3G_OPCODE::INTRESUME => {
+                if let Run::Suspended(left) = self.run {
+                    self.run = Run::Busy(left.map(|left| Instant::now() + left));
+                }
+                return Action::None;
+            }
+            CMD3G_OPCODE::INTSETIO => {
+                self.outputs = self.outputs & !frame.y() | frame.x() & frame.y();
+                return Action::None;
+            }
+            CMD3G_OPCODE::INTWAITIDLE => return Action::WaitIdle,
+            CMD3G_OPCODE::INTWAITIO => return Action::WaitIo(frame.x(), frame.y()),
+            _ => return Action::None,
+        };
+        // the requested length wins over the natural one
+        reply.resize(len, 0);
+        Action::Reply(reply)
+    }
+}
+
+/// Index of the DoWhile closing the WhileIO at `start`
+fn end_of_while(instructions: &[ScannerCommand], start: usize) -> usize {
+    let mut depth = 0;
+    for (i, command) in instructions.iter().enumerate().skip(start) {
+        match command {
+            ScannerCommand::WhileIO(..) => depth += 1,
+            ScannerCommand::DoWhile if depth == 1 => return i,
+            ScannerCommand::DoWhile => depth -= 1,
+            _ => {}
+        }
+    }
+    instructions.len()
+}
+
+/// Frames that need the PARAMS frame following them
+fn takes_params(frame: &CMD3G) -> bool {
+    matches!(
+        frame.op_code(),
+        CMD3G_OPCODE::CMD3G_ARCLINE
+            | CMD3G_OPCODE::CMD3G_CIRCLE
+            | CMD3G_OPCODE::CMD3G_IOWAIT
+            | CMD3G_OPCODE::CMD3G_TABLEWHILEIO
+    )
+}
+
+/// Commands received on one connection and not executed yet
+#[derive(Default)]
+struct Session {
+    pending: Vec<CMD3G>,
+}
+impl Session {
+    fn receive(&mut self, state: &mut MockState, frame: CMD3G) -> Action {
+        if state.record {
+            state.received.push(frame.clone());
+        }
+        if frame.target() != state.target && frame.target() != TGTALL {
+            return Action::None;
+        }
+        let op_code = frame.op_code();
+        if op_code == CMD3G_OPCODE::CMD3G_NOP {
+            return Action::None;
+        }
+        if state.list_mode.is_some() {
+            match op_code {
+                CMD3G_OPCODE::CMD3G_EOF => state.list_mode = None,
+                _ if is_interrupt(op_code) => return self.interrupt(state, &frame),
+                _ => state.list.push(frame),
+            }
+            return Action::None;
+        }
+        match op_code {
+            CMD3G_OPCODE::CMD3G_LIST => {
+                self.flush(state);
+                let mode = ListMode::try_from(frame.x() as u32).unwrap_or(ListMode::Append);
+                if mode != ListMode::Append {
+                    state.list.clear();
+                }
+                state.list_mode = Some(mode);
+                Action::None
+            }
+            _ if is_interrupt(op_code) => self.interrupt(state, &frame),
+            _ => {
+                self.pending.push(frame);
+                Action::None
+            }
+        }
+    }
+
+    fn interrupt(&mut self, state: &mut MockState, frame: &CMD3G) -> Action {
+        self.flush(state);
+        state.interrupt(frame)
+    }
+
+    /// Executes the pending commands, keeping back a frame waiting for its PARAMS and the
+    /// loops and branches not closed yet, which may span several blocks
+    fn flush(&mut self, state: &mut MockState) {
+        let mut depth = 0_usize;
+        let mut split = 0;
+        for (i, frame) in self.pending.iter().enumerate() {
+            match frame.op_code() {
+                CMD3G_OPCODE::CMD3G_SETLOOP | CMD3G_OPCODE::CMD3G_TABLEWHILEIO => depth += 1,
+                CMD3G_OPCODE::CMD3G_DOLOOP | CMD3G_OPCODE::CMD3G_BRANCH => {
+                    depth = depth.saturating_sub(1)
+                }
+                _ => {}
+            }
+            if depth == 0 && !takes_params(frame) {
+                split = i + 1;
+            }
+        }
+        let frames: Vec<CMD3G> = self.pending.drain(..split).collect();
+        if !frames.is_empty() {
+            state.execute(&frames);
+        }
+    }
+}
+
+/// Interrupts are handled on reception, even inside a list
+fn is_interrupt(op_code: CMD3G_OPCODE) -> bool {
+    u8::from(op_code) & 0x80 != 0 && op_code != CMD3G_OPCODE::CMD3G_EOF
+}
+
+/// A simulated Newson controller with one target, listening for blocks of CMD3G frames
+/// and answering queries from a `MockState`, for tests and offline development
+pub struct MockController {
+    address: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl MockController {
+    /// Listens on `address`, e.g. `("127.0.0.1", DEFAULT_PORT)` or a free port for tests
+    pub fn bind<A: ToSocketAddrs>(address: A) -> Result<MockController, AppError> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let mut state = MockState::new(TARGET);
+        if let IpAddr::V4(ip) = address.ip() {
+            state.ip = ip;
+        }
+        let state = Arc::new(Mutex::new(state));
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let (state, stop) = (state.clone(), stop.clone());
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        let (state, stop) = (state.clone(), stop.clone());
+                        thread::spawn(move || serve(stream, &state, &stop));
+                    }
+                }
+            });
+        }
+        Ok(MockController {
+            address,
+            state,
+            stop,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Locks the simulated state, e.g. to set the inputs or to check what was received
+    pub fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for MockController {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // wakes the listener up so that it sees `stop`
+        let mut address = self.address;
+        if address.ip().is_unspecified() {
+            address.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        let _ = TcpStream::connect(address);
+    }
+}
+
+/// Polling period of the connections while they wait, for data or for the target
+const POLL: Duration = Duration::from_millis(1);
+
+/// Handles one connection until it closes or the mock is dropped
+fn serve(mut stream: TcpStream, state: &Mutex<MockState>, stop: &AtomicBool) {
+    let lock = || state.lock().unwrap_or_else(|e| e.into_inner());
+    if stream.set_read_timeout(Some(POLL * 50)).is_err() {
+        return;
+    }
+    let mut session = Session::default();
+    let mut block = [0_u8; BLOCK_LEN];
+    loop {
+        let mut got = 0;
+        while got < BLOCK_LEN {
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+            match stream.read(&mut block[got..]) {
+                Ok(0) => return,
+                Ok(n) => got += n,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(_) => return,
+            }
+        }
+        // frames with an unknown opcode are dropped, as the target would ignore them
+        let frames = block
+            .chunks(FRAME_LEN)
+            .filter_map(|chunk| disasm::decode_frames(chunk).ok())
+            .flatten();
+        for frame in frames {
+            let action = session.receive(&mut lock(), frame);
+            let ready = |state: &mut MockState| match action {
+                Action::WaitIdle => state.is_idle(),
+                Action::WaitIo(value, mask) => IoPins(state.inputs).matches(value, mask),
+                _ => true,
+            };
+            while !ready(&mut lock()) {
+                if stop.load(Ordering::SeqCst) {
+                    return;
+                }
+                thread::sleep(POLL);
+            }
+            if let Action::Reply(reply) = &action {
+                if stream.write_all(reply).is_err() {
+                    return;
+                }
+            }
+        }
+        session.flush(&mut lock());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_script, ConnectOptions, Controller};
+
+    fn connect(mock: &MockController) -> Controller {
+        Controller::connect(mock.address(), ConnectOptions::new()).unwrap()
+    }
+
+    fn run(controller: &mut Controller, script: &str) {
+        controller.run(&parse_script(script).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn immediate_commands() {
+        let mock = MockController::bind("127.0.0.1:0").unwrap();
+        let mut controller = connect(&mock);
+        run(
+            &mut controller,
+            "rtSetSpeed(600); rtSetJumpSpeed(2000); rtJumpTo(1, 0); rtLineTo(2.5, -1);
+             rtCircle(2.5, 0, 90); rtArcTo(3, 3, 0.5); rtMoveTo(-1, -2);
+             rtSetIO(15, 5); rtSetAnalog(768, 3840); rtSetLaser(1);",
+        );
+        let status = controller.status().unwrap();
+        assert_eq!(status.deflection(), Position::new(-1.0, -2.0));
+        assert_eq!(status.outputs().bits(), 0x05);
+        assert!(status.system_state().is_idle());
+
+        let state = mock.state();
+        assert_eq!((state.speed, state.jump_speed), (600.0, 2000.0));
+        assert_eq!(state.analog_outputs, 0x300);
+        assert!(state.laser);
+        assert!(state.list.is_empty());
+    }
+
+    #[test]
+    fn loop_across_blocks() {
+        let mock = MockController::bind("127.0.0.1:0").unwrap();
+        let mut controller = connect(&mock);
+        // more frames than a block holds between SetLoop and DoLoop
+        let padding: String = (0..100).map(|i| format!("rtSetIO({}, 255);", i)).collect();
+        run(
+            &mut controller,
+            &format!(
+                "rtJumpTo(1, 0); rtSetLoop(3); {} rtCircle(0, 0, 90); rtDoLoop();",
+                padding
+            ),
+        );
+        let position = controller.status().unwrap().deflection();
+        assert!(position.distance(&Position::new(0.0, -1.0)) < 1e-3);
+        assert!(mock.state().received.is_empty());
+    }
+
+    #[test]
+    fn queries() {
+        let mock = MockController::bind("127.0.0.1:0").unwrap();
+        mock.state().inputs = 0b101;
+        mock.state().analog = [1, 2, 3, 4];
+        let mut controller = connect(&mock);
+        let status = controller.status().unwrap();
+        assert_eq!(status.inputs().bits(), 0b101);
+        assert_eq!(status.analogs(), [1, 2, 3, 4]);
+
+        let id = controller.target_id().unwrap();
+        assert_eq!(
+            (id.kind(), id.id(), id.firmware()),
+            (MOCK_KIND, TARGET, (1, 0, 0, 0))
+        );
+        let ip = controller.get_ip().unwrap();
+        assert_eq!(ip.ip(), Ipv4Addr::LOCALHOST);
+
+        let mut stream = TcpStream::connect(mock.address()).unwrap();
+        assert_eq!(crate::scan_targets(&mut stream).unwrap(), vec![TARGET]);
+        assert_eq!(crate::get_ip(&mut stream).unwrap(), ip);
+    }
+
+    #[test]
+    fn stored_list() {
+        let mock = MockController::bind("127.0.0.1:0").unwrap();
+        let mut controller = connect(&mock);
+        run(
+            &mut controller,
+            "rtListOpen(2); rtJumpTo(1, 1); rtSetLoop(3); rtLineTo(2, 2); rtSleep(20);
+             rtDoLoop(); rtListClose();",
+        );
+        run(
+            &mut controller,
+            "rtListOpen(1); rtSetIO(1, 1); rtListClose();",
+        );
+        // replied once the frames before it are handled
+        let status = controller.status().unwrap();
+        assert_eq!(status.deflection(), Position::new(0.0, 0.0));
+        assert_eq!(mock.state().list.len(), 6);
+
+        controller.start_list().unwrap();
+        // three sleeps of 20 ms
+        let status = controller.status().unwrap();
+        assert!(!status.system_state().is_idle());
+        assert_eq!(status.counter(), 1);
+        let status = controller.wait_idle(Duration::from_secs(1)).unwrap();
+        assert!(status.system_state().is_idle());
+        assert_eq!(status.deflection(), Position::new(2.0, 2.0));
+        assert_eq!(status.outputs().bits(), 1);
+    }
+
+    #[test]
+    fn interrupts() {
+        let mock = MockController::bind("127.0.0.1:0").unwrap();
+        mock.state().record = true;
+        let mut controller = connect(&mock);
+        run(
+            &mut controller,
+            "rtListOpen(4); rtWaitIO(1, 1, 0); rtListClose();",
+        );
+        assert!(!controller.status().unwrap().system_state().is_idle());
+        controller.suspend().unwrap();
+        controller.resume().unwrap();
+        assert!(matches!(
+            controller.wait_idle(Duration::from_millis(20)),
+            Err(AppError::Timeout)
+        ));
+        controller.abort().unwrap();
+        assert!(controller.wait_idle(Duration::from_secs(1)).is_ok());
+
+        // held back until the inputs match
+        let inputs = {
+            let state = mock.state.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                state.lock().unwrap().inputs = 0b10;
+            })
+        };
+        let status = controller
+            .wait_io(0b10, 0b11, Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(status.inputs().bits(), 0b10);
+        inputs.join().unwrap();
+
+        let received = &mock.state().received;
+        let opcodes: Vec<CMD3G_OPCODE> = received
+            .iter()
+            .map(CMD3G::op_code)
+            .filter(|op_code| *op_code != CMD3G_OPCODE::CMD3G_NOP)
+            .collect();
+        assert!(opcodes.contains(&CMD3G_OPCODE::INTSUSPEND));
+        assert!(opcodes.contains(&CMD3G_OPCODE::INTWAITIO));
+    }
+
+    #[test]
+    fn suspension() {
+        let mut state = MockState::new(TARGET);
+        state.execute(&commands::build_commandlist(&[ScannerCommand::Sleep(30)]));
+        state.interrupt(&commands::suspend(TARGET)[0]);
+        thread::sleep(Duration::from_millis(40));
+        assert!(!state.is_idle());
+        state.interrupt(&commands::resume(TARGET)[0]);
+        assert!(!state.is_idle());
+        thread::sleep(Duration::from_millis(40));
+        assert!(state.is_idle());
+    }
+}
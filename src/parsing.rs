@@ -1,22 +1,41 @@
-use crate::commands::Position;
+use crate::commands::{ListMode, Position};
 use crate::AppError;
-use regex::Regex;
+use std::convert::TryFrom;
+use std::fmt;
 use std::str::FromStr;
 
+mod ast;
+mod error;
+mod eval;
+mod lexer;
+mod parser;
+mod printer;
+
+pub use error::{ParseError, ParseErrorKind, ParseErrors, Span};
+pub use printer::{print_script, ScriptWriter};
+
+/// Maximum number of iterations of a single `for` or `while` loop
+pub const MAX_ITERATIONS: usize = 1_000_000;
+/// Maximum number of loop iterations and function calls of a whole script, nested ones
+/// included
+pub const MAX_TOTAL_ITERATIONS: usize = 2_000_000;
+/// Maximum nesting of user function calls
+pub const MAX_CALL_DEPTH: usize = 64;
+
 /// Representation of a scanner command with its parameters
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum ScannerCommand {
     None,
-    ListOpen(u32),
+    ListOpen(ListMode),
     ListClose,
     Jump(Position),
     SetIO(u16, u16),
     SetAnalog(u16, u16),
     Arc(Position, f32),
     Circle(Position, f32),
-    CircleMove(Position, f32),
     Line(Position),
-    WaitIO,
+    /// Waits until `inputs & mask == value`, giving up after `timeout` ms (0 waits forever)
+    WaitIO(u16, u16, u32),
     Move(Position),
     SetSpeed(f32),
     SetJumpSpeed(f32),
@@ -24,71 +43,353 @@ pub enum ScannerCommand {
     Burst(u16),
     SetLaser(bool),
     SetLaserTimes(u16, u16),
-    SetTarget(u32),
-    WhileIO,
+    /// Addresses the following commands to another target, TGTALL broadcasting them
+    SetTarget(u8),
+    /// Repeats the commands up to the matching DoWhile while `inputs & mask == value`
+    WhileIO(u16, u16),
     DoWhile,
-    SetLoop,
+    /// Repeats the commands up to the matching DoLoop the given number of times
+    SetLoop(u16),
     DoLoop,
 }
-/// Parse a scanner command string (e.g. "rtMoveTo(3.0, 4.5)") into the corresponding enum
-impl FromStr for ScannerCommand {
-    type Err = AppError;
+/// Type of a scanner command argument
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgType {
+    /// Coordinate in millimetres
+    Coord,
+    /// Single precision float (speeds, angles)
+    Float,
+    /// 8-bit unsigned integer (targets)
+    Byte,
+    /// 16-bit unsigned integer (IO values, masks, times)
+    Word,
+    /// 32-bit unsigned integer (timeouts)
+    Int,
+    /// Boolean, written `0`/`1` or `false`/`true`
+    Bool,
+    /// List mode number, see `ListMode`
+    ListMode,
+}
 
-    fn from_str(s: &str) -> Result<Self, AppError> {
-        fn parse_position(s: &str) -> Result<Position, AppError> {
-            let pos = s.split(',').collect::<Vec<&str>>();
-            if pos.len() != 2 {
-                return Err(AppError::ParseError);
-            }
-            match (
-                pos.get(0).unwrap().parse::<f64>(),
-                pos.get(1).unwrap().parse::<f64>(),
-            ) {
-                (Ok(x), Ok(y)) => Ok(Position::new(x, y)),
-                _ => Err(AppError::ParseError),
-            }
+/// Grammar entry of a rhothor command: its name and its typed arguments
+#[derive(Debug)]
+pub struct Signature {
+    pub name: &'static str,
+    pub args: &'static [(&'static str, ArgType)],
+}
+
+/// Every rhothor command understood by the parser
+pub const GRAMMAR: &[Signature] = &[
+    Signature {
+        name: "rtListOpen",
+        args: &[("mode", ArgType::ListMode)],
+    },
+    Signature {
+        name: "rtListClose",
+        args: &[],
+    },
+    Signature {
+        name: "rtJumpTo",
+        args: &[("x", ArgType::Coord), ("y", ArgType::Coord)],
+    },
+    Signature {
+        name: "rtMoveTo",
+        args: &[("x", ArgType::Coord), ("y", ArgType::Coord)],
+    },
+    Signature {
+        name: "rtLineTo",
+        args: &[("x", ArgType::Coord), ("y", ArgType::Coord)],
+    },
+    Signature {
+        name: "rtArcTo",
+        args: &[
+            ("x", ArgType::Coord),
+            ("y", ArgType::Coord),
+            ("bulge", ArgType::Float),
+        ],
+    },
+    Signature {
+        name: "rtCircle",
+        args: &[
+            ("x", ArgType::Coord),
+            ("y", ArgType::Coord),
+            ("angle", ArgType::Float),
+        ],
+    },
+    Signature {
+        name: "rtSetIO",
+        args: &[("value", ArgType::Word), ("mask", ArgType::Word)],
+    },
+    Signature {
+        name: "rtSetAnalog",
+        args: &[("value", ArgType::Word), ("mask", ArgType::Word)],
+    },
+    Signature {
+        name: "rtWaitIO",
+        args: &[
+            ("value", ArgType::Word),
+            ("mask", ArgType::Word),
+            ("timeout", ArgType::Int),
+        ],
+    },
+    Signature {
+        name: "rtSetSpeed",
+        args: &[("speed", ArgType::Float)],
+    },
+    Signature {
+        name: "rtSetJumpSpeed",
+        args: &[("speed", ArgType::Float)],
+    },
+    Signature {
+        name: "rtSleep",
+        args: &[("time", ArgType::Word)],
+    },
+    Signature {
+        name: "rtBurst",
+        args: &[("time", ArgType::Word)],
+    },
+    Signature {
+        name: "rtSetLaser",
+        args: &[("on", ArgType::Bool)],
+    },
+    Signature {
+        name: "rtSetLaserTimes",
+        args: &[("on_delay", ArgType::Word), ("off_delay", ArgType::Word)],
+    },
+    Signature {
+        name: "rtSetTarget",
+        args: &[("target", ArgType::Byte)],
+    },
+    Signature {
+        name: "rtWhileIO",
+        args: &[("value", ArgType::Word), ("mask", ArgType::Word)],
+    },
+    Signature {
+        name: "rtDoWhile",
+        args: &[],
+    },
+    Signature {
+        name: "rtSetLoop",
+        args: &[("count", ArgType::Word)],
+    },
+    Signature {
+        name: "rtDoLoop",
+        args: &[],
+    },
+];
+
+/// Looks up the grammar entry of a command name
+pub fn signature(name: &str) -> Option<&'static Signature> {
+    GRAMMAR.iter().find(|sig| sig.name == name)
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let args = self
+            .args
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<&str>>()
+            .join(", ");
+        write!(f, "{}({})", self.name, args)
+    }
+}
+
+impl fmt::Display for ArgType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArgType::Coord => write!(f, "a coordinate"),
+            ArgType::Float => write!(f, "a number"),
+            ArgType::Byte => write!(f, "an integer between 0 and 255"),
+            ArgType::Word => write!(f, "an integer between 0 and 65535"),
+            ArgType::Int => write!(f, "a non-negative integer"),
+            ArgType::Bool => write!(f, "a boolean"),
+            ArgType::ListMode => write!(f, "a list mode (0, 1, 2 or 4)"),
         }
+    }
+}
 
-        fn parse_f32(s: &str) -> Result<f32, AppError> {
-            match s.parse::<f32>() {
-                Ok(val) => Ok(val),
-                _ => Err(AppError::ParseError),
+/// A parsed command argument
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Arg {
+    Coord(f64),
+    Float(f32),
+    Byte(u8),
+    Word(u16),
+    Int(u32),
+    Bool(bool),
+    ListMode(ListMode),
+}
+
+impl ArgType {
+    /// Converts an evaluated argument to this type
+    pub(crate) fn convert(self, value: f64) -> Option<Arg> {
+        let integer = value.fract() == 0.0;
+        match self {
+            ArgType::Coord => Some(Arg::Coord(value)),
+            ArgType::Float if value.abs() <= f32::MAX as f64 => Some(Arg::Float(value as f32)),
+            ArgType::Byte if integer && (0.0..=u8::MAX as f64).contains(&value) => {
+                Some(Arg::Byte(value as u8))
+            }
+            ArgType::Word if integer && (0.0..=u16::MAX as f64).contains(&value) => {
+                Some(Arg::Word(value as u16))
             }
+            ArgType::Int if integer && (0.0..=u32::MAX as f64).contains(&value) => {
+                Some(Arg::Int(value as u32))
+            }
+            ArgType::Bool if value == 0.0 || value == 1.0 => Some(Arg::Bool(value == 1.0)),
+            ArgType::ListMode if integer && (0.0..=u32::MAX as f64).contains(&value) => {
+                ListMode::try_from(value as u32).ok().map(Arg::ListMode)
+            }
+            _ => None,
         }
+    }
+}
 
-        fn parse_int(s: &str) -> Result<u32, AppError> {
-            match s.parse::<u32>() {
-                Ok(val) => Ok(val),
-                _ => Err(AppError::ParseError),
+impl ScannerCommand {
+    /// Builds a command from its grammar entry and arguments of the matching types
+    pub(crate) fn from_args(sig: &Signature, args: &[Arg]) -> ScannerCommand {
+        use Arg::*;
+        match (sig.name, args) {
+            ("rtListOpen", [ListMode(mode)]) => ScannerCommand::ListOpen(*mode),
+            ("rtListClose", []) => ScannerCommand::ListClose,
+            ("rtJumpTo", [Coord(x), Coord(y)]) => ScannerCommand::Jump(Position::new(*x, *y)),
+            ("rtMoveTo", [Coord(x), Coord(y)]) => ScannerCommand::Move(Position::new(*x, *y)),
+            ("rtLineTo", [Coord(x), Coord(y)]) => ScannerCommand::Line(Position::new(*x, *y)),
+            ("rtArcTo", [Coord(x), Coord(y), Float(bf)]) => {
+                ScannerCommand::Arc(Position::new(*x, *y), *bf)
+            }
+            ("rtCircle", [Coord(x), Coord(y), Float(angle)]) => {
+                ScannerCommand::Circle(Position::new(*x, *y), *angle)
+            }
+            ("rtSetIO", [Word(value), Word(mask)]) => ScannerCommand::SetIO(*value, *mask),
+            ("rtSetAnalog", [Word(value), Word(mask)]) => ScannerCommand::SetAnalog(*value, *mask),
+            ("rtWaitIO", [Word(value), Word(mask), Int(timeout)]) => {
+                ScannerCommand::WaitIO(*value, *mask, *timeout)
+            }
+            ("rtSetSpeed", [Float(speed)]) => ScannerCommand::SetSpeed(*speed),
+            ("rtSetJumpSpeed", [Float(speed)]) => ScannerCommand::SetJumpSpeed(*speed),
+            ("rtSleep", [Word(time)]) => ScannerCommand::Sleep(*time),
+            ("rtBurst", [Word(time)]) => ScannerCommand::Burst(*time),
+            ("rtSetLaser", [Bool(on)]) => ScannerCommand::SetLaser(*on),
+            ("rtSetLaserTimes", [Word(on_delay), Word(off_delay)]) => {
+                ScannerCommand::SetLaserTimes(*on_delay, *off_delay)
             }
+            ("rtSetTarget", [Byte(target)]) => ScannerCommand::SetTarget(*target),
+            ("rtWhileIO", [Word(value), Word(mask)]) => ScannerCommand::WhileIO(*value, *mask),
+            ("rtDoWhile", []) => ScannerCommand::DoWhile,
+            ("rtSetLoop", [Word(count)]) => ScannerCommand::SetLoop(*count),
+            ("rtDoLoop", []) => ScannerCommand::DoLoop,
+            _ => unreachable!("grammar entry without constructor: {}", sig.name),
         }
+    }
+}
 
-        let re = Regex::new(r"(?P<command>[A-z]+)\((?P<args>(.*))\)").unwrap();
-        let caps = re.captures(s).unwrap();
-        let args = caps.name("args").unwrap().as_str();
-        let command = match caps.name("command").unwrap().as_str() {
-            "rtListOpen" => ScannerCommand::ListOpen(parse_int(args)?),
-            "rtListClose" => ScannerCommand::ListClose,
-            "rtJumpTo" => ScannerCommand::Jump(parse_position(args)?),
-            "rtMoveTo" => ScannerCommand::Move(parse_position(args)?),
-            "rtLineTo" => ScannerCommand::Line(parse_position(args)?),
-            "rtSetSpeed" => ScannerCommand::SetSpeed(parse_f32(args)?),
-            "rtSetJumpSpeed" => ScannerCommand::SetJumpSpeed(parse_f32(args)?),
-            "rtSetTarget" => ScannerCommand::SetTarget(parse_int(args)?),
-            _ => return Err(AppError::ParseError),
+/// Print a scanner command as a canonical script statement (e.g. "rtLineTo(1.5,2);"),
+/// `None` printing nothing
+impl fmt::Display for ScannerCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, args): (&str, Vec<String>) = match self {
+            ScannerCommand::None => return Ok(()),
+            ScannerCommand::ListOpen(mode) => ("rtListOpen", vec![(*mode as u32).to_string()]),
+            ScannerCommand::ListClose => ("rtListClose", vec![]),
+            ScannerCommand::Jump(pos) => ("rtJumpTo", coords(pos)),
+            ScannerCommand::Move(pos) => ("rtMoveTo", coords(pos)),
+            ScannerCommand::Line(pos) => ("rtLineTo", coords(pos)),
+            ScannerCommand::Arc(pos, bf) => ("rtArcTo", with(coords(pos), bf)),
+            ScannerCommand::Circle(pos, angle) => ("rtCircle", with(coords(pos), angle)),
+            ScannerCommand::SetIO(value, mask) => {
+                ("rtSetIO", vec![value.to_string(), mask.to_string()])
+            }
+            ScannerCommand::SetAnalog(value, mask) => {
+                ("rtSetAnalog", vec![value.to_string(), mask.to_string()])
+            }
+            ScannerCommand::WaitIO(value, mask, timeout) => (
+                "rtWaitIO",
+                vec![value.to_string(), mask.to_string(), timeout.to_string()],
+            ),
+            ScannerCommand::SetSpeed(speed) => ("rtSetSpeed", vec![speed.to_string()]),
+            ScannerCommand::SetJumpSpeed(speed) => ("rtSetJumpSpeed", vec![speed.to_string()]),
+            ScannerCommand::Sleep(time) => ("rtSleep", vec![time.to_string()]),
+            ScannerCommand::Burst(time) => ("rtBurst", vec![time.to_string()]),
+            ScannerCommand::SetLaser(on) => ("rtSetLaser", vec![on.to_string()]),
+            ScannerCommand::SetLaserTimes(on_delay, off_delay) => (
+                "rtSetLaserTimes",
+                vec![on_delay.to_string(), off_delay.to_string()],
+            ),
+            ScannerCommand::SetTarget(target) => ("rtSetTarget", vec![target.to_string()]),
+            ScannerCommand::WhileIO(value, mask) => {
+                ("rtWhileIO", vec![value.to_string(), mask.to_string()])
+            }
+            ScannerCommand::DoWhile => ("rtDoWhile", vec![]),
+            ScannerCommand::SetLoop(count) => ("rtSetLoop", vec![count.to_string()]),
+            ScannerCommand::DoLoop => ("rtDoLoop", vec![]),
         };
+        write!(f, "{}({});", name, args.join(","))
+    }
+}
+
+/// Shortest decimal forms of a position, which parse back to the same coordinates
+fn coords(pos: &Position) -> Vec<String> {
+    vec![pos.x().to_string(), pos.y().to_string()]
+}
 
-        Ok(command)
+fn with(mut args: Vec<String>, last: &f32) -> Vec<String> {
+    args.push(last.to_string());
+    args
+}
+
+/// Parse a scanner command string (e.g. "rtMoveTo(3.0, 4.5)") into the corresponding enum
+impl FromStr for ScannerCommand {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, AppError> {
+        let mut commands = parse_script(s)?;
+        match commands.len() {
+            1 => Ok(commands.remove(0)),
+            _ => Err(miscount(s, "a single command")),
+        }
     }
 }
 
-/// Parse a scanner script line, ditching comments and empty lines
+/// Parse a scanner script line, ditching comments and empty lines. A line holding several
+/// commands is an error.
 pub fn parse_line(s: &str) -> Result<Option<ScannerCommand>, AppError> {
-    let s = s.trim();
-    if s.starts_with("//") || s.is_empty() {
-        return Ok(None);
+    let mut commands = parse_script(s)?;
+    match commands.len() {
+        0 => Ok(None),
+        1 => Ok(commands.pop()),
+        _ => Err(miscount(s, "at most one command")),
+    }
+}
+
+/// Error for a string holding the wrong number of commands
+fn miscount(s: &str, expected: &'static str) -> AppError {
+    AppError::Parse(ParseErrors(vec![ParseError::new(
+        Span::new(1, 1, s.len()),
+        s.to_string(),
+        ParseErrorKind::Expected(expected),
+    )]))
+}
+
+/// Parse a whole scanner script, collecting every error instead of stopping at the first one.
+/// Arguments are arithmetic expressions over numbers, `var`/`const` names and math functions.
+/// Host-side `for`, `while`, `if`/`else` blocks and `fn` calls are expanded at parse time
+/// into a flat list; they are unrelated to the SetLoop/DoLoop commands running on the card.
+pub fn parse_script(source: &str) -> Result<Vec<ScannerCommand>, ParseErrors> {
+    let (tokens, mut errors) = lexer::tokenize(source);
+    let (statements, parse_errors) = parser::Parser::new(tokens).parse();
+    let (commands, eval_errors) = eval::Interpreter::new().run(&statements);
+    errors.extend(parse_errors);
+    errors.extend(eval_errors);
+    if errors.is_empty() {
+        Ok(commands)
+    } else {
+        // errors inside loops and functions are reported once, not once per iteration
+        errors.sort_by_key(|e| (e.span.line, e.span.column));
+        errors.dedup();
+        Err(ParseErrors(errors))
     }
-    Ok(Some(ScannerCommand::from_str(s)?))
 }
 
 #[cfg(test)]
@@ -99,7 +400,7 @@ mod tests {
     fn parse_command() {
         struct TestCase {
             got: Result<ScannerCommand, AppError>,
-            want: Result<ScannerCommand, AppError>,
+            want: Result<ScannerCommand, ParseErrorKind>,
         }
         let test_cases = vec![
             TestCase {
@@ -116,22 +417,31 @@ mod tests {
             },
             TestCase {
                 got: ScannerCommand::from_str("rtSetSpeed()"),
-                want: Err(AppError::ParseError),
+                want: Err(ParseErrorKind::ArgumentCount {
+                    expected: 1,
+                    got: 0,
+                }),
             },
             TestCase {
                 // two many arguments
                 got: ScannerCommand::from_str("rtSetSpeed(1.2, 0)"),
-                want: Err(AppError::ParseError),
+                want: Err(ParseErrorKind::ArgumentCount {
+                    expected: 1,
+                    got: 2,
+                }),
             },
             TestCase {
                 // two many arguments
                 got: ScannerCommand::from_str("rtJumpTo(1234.5,777.42,4.8)"),
-                want: Err(AppError::ParseError),
+                want: Err(ParseErrorKind::ArgumentCount {
+                    expected: 2,
+                    got: 3,
+                }),
             },
             TestCase {
                 // syntax error
                 got: ScannerCommand::from_str("rtJumpTo(1234.O,4.8)"),
-                want: Err(AppError::ParseError),
+                want: Err(ParseErrorKind::Expected("`,` or `)`")),
             },
             TestCase {
                 got: Ok(ScannerCommand::from_str("rtMoveTo(1.2,7.77)").unwrap()),
@@ -140,8 +450,259 @@ mod tests {
         ];
 
         for test in test_cases {
-            assert_eq!(test.got, test.want);
+            let got = test.got.map_err(|e| match e {
+                AppError::Parse(errors) => errors.0[0].kind.clone(),
+                e => panic!("unexpected error {:?}", e),
+            });
+            assert_eq!(got, test.want);
+        }
+    }
+
+    #[test]
+    fn parse_every_command() {
+        let test_cases = vec![
+            (
+                "rtListOpen(4)",
+                ScannerCommand::ListOpen(ListMode::CompileAndStart),
+            ),
+            (
+                "rtListOpen(0)",
+                ScannerCommand::ListOpen(ListMode::Immediate),
+            ),
+            ("rtListClose()", ScannerCommand::ListClose),
+            (
+                "rtArcTo(1.5, -2, 0.5)",
+                ScannerCommand::Arc(Position::new(1.5, -2.0), 0.5),
+            ),
+            (
+                "rtCircle(1,2,180)",
+                ScannerCommand::Circle(Position::new(1.0, 2.0), 180.0),
+            ),
+            ("rtSetIO(3,15)", ScannerCommand::SetIO(3, 15)),
+            ("rtSetAnalog(100,1)", ScannerCommand::SetAnalog(100, 1)),
+            ("rtWaitIO(1,3,500)", ScannerCommand::WaitIO(1, 3, 500)),
+            ("rtSleep(250)", ScannerCommand::Sleep(250)),
+            ("rtBurst(10)", ScannerCommand::Burst(10)),
+            ("rtSetLaser(true)", ScannerCommand::SetLaser(true)),
+            ("rtSetLaser(0)", ScannerCommand::SetLaser(false)),
+            ("rtSetLaserTimes(5,7)", ScannerCommand::SetLaserTimes(5, 7)),
+            ("rtSetTarget(2)", ScannerCommand::SetTarget(2)),
+            ("rtWhileIO(0,1)", ScannerCommand::WhileIO(0, 1)),
+            ("rtDoWhile()", ScannerCommand::DoWhile),
+            ("rtSetLoop(10)", ScannerCommand::SetLoop(10)),
+            ("rtDoLoop()", ScannerCommand::DoLoop),
+        ];
+        for (script, want) in test_cases {
+            assert_eq!(
+                ScannerCommand::from_str(script).unwrap(),
+                want,
+                "{}",
+                script
+            );
         }
+
+        // every grammar entry has a constructor
+        for sig in GRAMMAR {
+            let args = sig
+                .args
+                .iter()
+                .map(|(_, ty)| match ty {
+                    ArgType::Bool => "1",
+                    _ => "2",
+                })
+                .collect::<Vec<&str>>()
+                .join(",");
+            assert!(ScannerCommand::from_str(&format!("{}({})", sig.name, args)).is_ok());
+        }
+    }
+
+    #[test]
+    fn diagnostics() {
+        let script = "rtSetSpeed(1200);\n\n  rtLineTo(1, 2, 3); // too many\nrtSleep(-1);\nfoo;\nrtJumpTo(1, 2);\nrtMoveTo(1 2);";
+        let errors = parse_script(script).unwrap_err().0;
+        let got = errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            got,
+            vec![
+                "3:18: rtLineTo expects 2 arguments, got 3",
+                "4:9: rtSleep argument `time` expects an integer between 0 and 65535, found `-1`",
+                "5:1: unknown command `foo`",
+                "7:12: expected `,` or `)`, found `2`",
+            ]
+        );
+        assert_eq!(errors[0].command.as_deref(), Some("rtLineTo"));
+        assert_eq!(errors[0].found, "3");
+        assert_eq!(errors[0].expected_signature().unwrap(), "rtLineTo(x, y)");
+        assert_eq!(errors[0].span, Span::new(3, 18, 1));
+        assert_eq!(errors[1].expected_signature().unwrap(), "rtSleep(time)");
+
+        // a bare word or an unterminated call is an error, not a panic
+        assert!(parse_line("foo;").is_err());
+        assert!(parse_line("rtSleep(1").is_err());
+        assert!(parse_line(")").is_err());
+        // as is a line holding more than one command
+        assert!(parse_line("rtSleep(1); rtSleep(2);").is_err());
+        assert!(parse_line("rtSleep(1); // done").unwrap().is_some());
+    }
+
+    #[test]
+    fn expressions() {
+        let script = "
+            const SPEED = 600;
+            var x0 = -2.5;
+            var y0 = x0 * 2;
+            rtSetSpeed(SPEED * 2);
+            rtLineTo(x0 + 5, y0);
+            x0 = x0 + 1;
+            rtJumpTo(-x0, (y0 + 1) / 2);
+            rtCircle(0, sqrt(4), deg(PI));
+            rtSleep(abs(x0) * 100);
+            rtSetLaser(true);
+        ";
+        assert_eq!(
+            parse_script(script).unwrap(),
+            vec![
+                ScannerCommand::SetSpeed(1200.0),
+                ScannerCommand::Line(Position::new(2.5, -5.0)),
+                ScannerCommand::Jump(Position::new(1.5, -2.0)),
+                ScannerCommand::Circle(Position::new(0.0, 2.0), 180.0),
+                ScannerCommand::Sleep(150),
+                ScannerCommand::SetLaser(true),
+            ]
+        );
+
+        let script = "const N = 1;\nN = 2;\nvar N = 3;\nrtSleep(M);\nrtSleep(N / 2);\nrtSetSpeed(1 / (N - 1));";
+        let got = parse_script(script)
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            got,
+            vec![
+                "2:1: cannot assign to constant `N`",
+                "3:1: `N` is already defined",
+                "4:9: undefined variable `M`",
+                "5:9: rtSleep argument `time` expects an integer between 0 and 65535, found `0.5`",
+                "6:12: expression evaluates to `inf`, not a finite number",
+            ]
+        );
+    }
+
+    #[test]
+    fn control_flow() {
+        let script = "
+            const PITCH = 10;
+            fn square(x, y, size) {
+                rtJumpTo(x, y);
+                rtLineTo(x + size, y);
+                rtLineTo(x + size, y + size);
+            }
+            for row in 0..2 {
+                for col in 0..2 {
+                    square(col * PITCH, row * PITCH, 1);
+                }
+            }
+            var speed = 100;
+            while speed < 400 {
+                rtSetSpeed(speed);
+                speed = speed * 2;
+            }
+            for i in 0..3 {
+                if i == 0 {
+                    rtSleep(1);
+                } else if i == 1 {
+                    rtSleep(2);
+                } else {
+                    rtBurst(i);
+                }
+            }
+        ";
+        let commands = parse_script(script).unwrap();
+        assert_eq!(commands.len(), 4 * 3 + 2 + 3);
+        assert_eq!(
+            commands[3..6],
+            [
+                ScannerCommand::Jump(Position::new(10.0, 0.0)),
+                ScannerCommand::Line(Position::new(11.0, 0.0)),
+                ScannerCommand::Line(Position::new(11.0, 1.0)),
+            ]
+        );
+        assert_eq!(commands[9], ScannerCommand::Jump(Position::new(10.0, 10.0)));
+        assert_eq!(
+            commands[12..],
+            [
+                ScannerCommand::SetSpeed(100.0),
+                ScannerCommand::SetSpeed(200.0),
+                ScannerCommand::Sleep(1),
+                ScannerCommand::Sleep(2),
+                ScannerCommand::Burst(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn control_flow_errors() {
+        let script = "fn f(n) {\n  f(n + 1);\n}\nf(0);\nfor i in 0..3 {\n  rtSleep(i - 1);\n}\nwhile 1 {}\nfn rtSleep() {}\nf(1, 2);\nfor i in 0..1 { var x = 1; }\nrtSleep(x);";
+        let got = parse_script(script)
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            got,
+            vec![
+                "2:3: call to `f` exceeds 64 nested calls",
+                "6:11: rtSleep argument `time` expects an integer between 0 and 65535, found `-1`",
+                "8:1: loop exceeds 1000000 iterations",
+                "9:1: `rtSleep` is already defined",
+                "10:1: f expects 1 argument, got 2",
+                "12:9: undefined variable `x`",
+            ]
+        );
+
+        // nested loops are bounded as a whole, the limit being reported once
+        let script = "for i in 0..10 {\n  for j in 0..1000000 {}\n}";
+        let got = parse_script(script)
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            got,
+            ["2:3: script exceeds 2000000 loop iterations and calls"]
+        );
+
+        // as are calls, however few of them are nested
+        let script = "fn f(n) { if n < 40 { f(n + 1); f(n + 1); } }\nf(0);";
+        let got = parse_script(script)
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            got,
+            ["1:23: script exceeds 2000000 loop iterations and calls"]
+        );
+    }
+
+    #[test]
+    fn argument_types() {
+        assert!(ScannerCommand::from_str("rtSetIO(70000,1)").is_err());
+        assert!(ScannerCommand::from_str("rtSleep(-1)").is_err());
+        assert!(ScannerCommand::from_str("rtSleep(1.5)").is_err());
+        assert!(ScannerCommand::from_str("rtSetLaser(2)").is_err());
+        assert!(ScannerCommand::from_str("rtListClose(1)").is_err());
+        assert!(ScannerCommand::from_str("rtListOpen(3)").is_err());
+        assert!(ScannerCommand::from_str("rtSetTarget(256)").is_err());
+        assert_eq!(
+            ScannerCommand::from_str("rtSetTarget(TGTALL)").unwrap(),
+            ScannerCommand::SetTarget(0xFF)
+        );
+        assert!(ScannerCommand::from_str("rtFooBar(1)").is_err());
     }
 
     #[test]
@@ -0,0 +1,98 @@
+use crate::parsing::error::Span;
+
+/// Binary operator. Comparisons and logical operators evaluate to `1` or `0`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+/// Kind of an arithmetic expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Number(f64),
+    Variable(String),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+/// An arithmetic expression along with its location in the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+/// A script statement
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt {
+    /// `var name = expr;`
+    Var {
+        name: String,
+        value: Expr,
+        span: Span,
+    },
+    /// `const NAME = expr;`
+    Const {
+        name: String,
+        value: Expr,
+        span: Span,
+    },
+    /// `name = expr;`
+    Assign {
+        name: String,
+        value: Expr,
+        span: Span,
+    },
+    /// `rtXxx(expr, ...);` or a call to a user function
+    Command {
+        name: String,
+        args: Vec<Expr>,
+        span: Span,
+        close: Span,
+    },
+    /// `for name in start..end { ... }`, `end` excluded
+    For {
+        name: String,
+        start: Expr,
+        end: Expr,
+        body: Vec<Stmt>,
+        span: Span,
+    },
+    /// `while cond { ... }`
+    While {
+        cond: Expr,
+        body: Vec<Stmt>,
+        span: Span,
+    },
+    /// `if cond { ... } else { ... }`, `else if` being nested in `otherwise`
+    If {
+        cond: Expr,
+        then: Vec<Stmt>,
+        otherwise: Vec<Stmt>,
+    },
+    /// `fn name(params) { ... }`
+    Fn(FnDef),
+}
+
+/// A user function, expanded in place at each call
+#[derive(Debug, Clone, PartialEq)]
+pub struct FnDef {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<Stmt>,
+    pub span: Span,
+}
@@ -0,0 +1,210 @@
+use crate::parsing::{signature, ArgType};
+use std::fmt;
+
+/// Location of a piece of script text, 1-based line and column
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+impl Span {
+    pub fn new(line: usize, column: usize, len: usize) -> Span {
+        Span { line, column, len }
+    }
+}
+
+/// Reason of a parse error
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    /// A character that cannot start any token
+    UnexpectedChar,
+    /// Another token was expected, e.g. `(` or `)`
+    Expected(&'static str),
+    /// A command name missing from the grammar
+    UnknownCommand,
+    /// Wrong number of arguments for a command
+    ArgumentCount { expected: usize, got: usize },
+    /// An argument that cannot be converted to the type required by the signature
+    InvalidArgument { name: &'static str, ty: ArgType },
+    /// A numeric literal that cannot be read, e.g. `1.2.3`
+    InvalidNumber,
+    /// A name used before its `var` or `const` declaration
+    UndefinedVariable,
+    /// A name declared twice
+    Redefinition,
+    /// An assignment to a `const`
+    AssignToConstant,
+    /// A call to a function that does not exist
+    UnknownFunction,
+    /// An expression evaluating to infinity or NaN, e.g. a division by zero
+    InvalidValue,
+    /// A `fn` declared inside a block rather than at top level
+    NestedFunction,
+    /// A loop running more than the allowed number of iterations
+    IterationLimit,
+    /// Loops and calls of a script running more than the allowed number of steps in total
+    TotalIterationLimit,
+    /// Function calls nested deeper than the allowed depth
+    RecursionLimit,
+}
+
+/// A parse error with its location, the command being parsed and the offending token
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub file: Option<String>,
+    pub span: Span,
+    pub command: Option<String>,
+    pub found: String,
+    pub kind: ParseErrorKind,
+}
+impl ParseError {
+    pub fn new(span: Span, found: String, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            file: None,
+            span,
+            command: None,
+            found,
+            kind,
+        }
+    }
+
+    pub fn with_command(mut self, command: &str) -> ParseError {
+        self.command = Some(command.to_string());
+        self
+    }
+
+    /// Expected signature of the command being parsed, e.g. `rtLineTo(x, y)`
+    pub fn expected_signature(&self) -> Option<String> {
+        self.command
+            .as_deref()
+            .and_then(signature)
+            .map(|sig| sig.to_string())
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file)?;
+        }
+        write!(f, "{}:{}: ", self.span.line, self.span.column)?;
+        let command = self.command.as_deref().unwrap_or_default();
+        match &self.kind {
+            ParseErrorKind::UnexpectedChar => write!(f, "unexpected character `{}`", self.found),
+            ParseErrorKind::Expected(what) if self.found.is_empty() => {
+                write!(f, "expected {}, found end of file", what)
+            }
+            ParseErrorKind::Expected(what) => {
+                write!(f, "expected {}, found `{}`", what, self.found)
+            }
+            ParseErrorKind::UnknownCommand => write!(f, "unknown command `{}`", self.found),
+            ParseErrorKind::ArgumentCount { expected, got } => write!(
+                f,
+                "{} expects {} argument{}, got {}",
+                command,
+                expected,
+                if *expected == 1 { "" } else { "s" },
+                got
+            ),
+            ParseErrorKind::InvalidArgument { name, ty } => write!(
+                f,
+                "{} argument `{}` expects {}, found `{}`",
+                command, name, ty, self.found
+            ),
+            ParseErrorKind::InvalidNumber => write!(f, "invalid number `{}`", self.found),
+            ParseErrorKind::UndefinedVariable => {
+                write!(f, "undefined variable `{}`", self.found)
+            }
+            ParseErrorKind::Redefinition => write!(f, "`{}` is already defined", self.found),
+            ParseErrorKind::AssignToConstant => {
+                write!(f, "cannot assign to constant `{}`", self.found)
+            }
+            ParseErrorKind::UnknownFunction => write!(f, "unknown function `{}`", self.found),
+            ParseErrorKind::InvalidValue => write!(
+                f,
+                "expression evaluates to `{}`, not a finite number",
+                self.found
+            ),
+            ParseErrorKind::NestedFunction => {
+                write!(f, "functions can only be declared at top level")
+            }
+            ParseErrorKind::IterationLimit => write!(
+                f,
+                "loop exceeds {} iterations",
+                crate::parsing::MAX_ITERATIONS
+            ),
+            ParseErrorKind::TotalIterationLimit => write!(
+                f,
+                "script exceeds {} loop iterations and calls",
+                crate::parsing::MAX_TOTAL_ITERATIONS
+            ),
+            ParseErrorKind::RecursionLimit => write!(
+                f,
+                "call to `{}` exceeds {} nested calls",
+                self.found,
+                crate::parsing::MAX_CALL_DEPTH
+            ),
+        }
+    }
+}
+impl std::error::Error for ParseError {}
+
+/// Every error found while parsing a script
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseErrors(pub Vec<ParseError>);
+
+impl ParseErrors {
+    pub fn iter(&self) -> std::slice::Iter<'_, ParseError> {
+        self.0.iter()
+    }
+
+    /// Attaches a file name to every error
+    pub fn in_file(mut self, file: &str) -> ParseErrors {
+        self.0
+            .iter_mut()
+            .for_each(|e| e.file = Some(file.to_string()));
+        self
+    }
+}
+
+impl fmt::Display for ParseErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for ParseErrors {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let mut error = ParseError::new(
+            Span::new(42, 13, 1),
+            "3".to_string(),
+            ParseErrorKind::ArgumentCount {
+                expected: 2,
+                got: 3,
+            },
+        )
+        .with_command("rtLineTo");
+        assert_eq!(
+            error.to_string(),
+            "42:13: rtLineTo expects 2 arguments, got 3"
+        );
+        error.file = Some("job.txt".to_string());
+        assert_eq!(
+            error.to_string(),
+            "job.txt:42:13: rtLineTo expects 2 arguments, got 3"
+        );
+        assert_eq!(error.expected_signature().unwrap(), "rtLineTo(x, y)");
+    }
+}
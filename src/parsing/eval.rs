@@ -0,0 +1,527 @@
+use crate::parsing::ast::{BinOp, Expr, ExprKind, FnDef, Stmt};
+use crate::parsing::error::{ParseError, ParseErrorKind, Span};
+use crate::parsing::{
+    signature, Arg, ScannerCommand, MAX_CALL_DEPTH, MAX_ITERATIONS, MAX_TOTAL_ITERATIONS,
+};
+use std::collections::HashMap;
+
+/// Implementation of a math function over its evaluated arguments
+type MathFn = fn(&[f64]) -> f64;
+
+/// Mathematical functions available in expressions: name, arity and implementation
+const FUNCTIONS: &[(&str, usize, MathFn)] = &[
+    ("sin", 1, |a| a[0].sin()),
+    ("cos", 1, |a| a[0].cos()),
+    ("tan", 1, |a| a[0].tan()),
+    ("asin", 1, |a| a[0].asin()),
+    ("acos", 1, |a| a[0].acos()),
+    ("atan", 1, |a| a[0].atan()),
+    ("atan2", 2, |a| a[0].atan2(a[1])),
+    ("sqrt", 1, |a| a[0].sqrt()),
+    ("abs", 1, |a| a[0].abs()),
+    ("floor", 1, |a| a[0].floor()),
+    ("ceil", 1, |a| a[0].ceil()),
+    ("round", 1, |a| a[0].round()),
+    ("min", 2, |a| a[0].min(a[1])),
+    ("max", 2, |a| a[0].max(a[1])),
+    ("pow", 2, |a| a[0].powf(a[1])),
+    ("rad", 1, |a| a[0].to_radians()),
+    ("deg", 1, |a| a[0].to_degrees()),
+];
+
+/// Constants defined before the first line of every script
+const BUILTIN_CONSTANTS: &[(&str, f64)] = &[
+    ("PI", std::f64::consts::PI),
+    ("true", 1.0),
+    ("false", 0.0),
+    ("TGTALL", crate::commands::TGTALL as f64),
+];
+
+/// Value bound to a name
+struct Binding {
+    value: f64,
+    constant: bool,
+}
+
+/// Walks script statements, evaluating expressions, expanding loops, conditionals
+/// and user function calls, and emitting the resulting flat list of scanner commands
+pub struct Interpreter<'a> {
+    /// Innermost scope last; the first one holds globals
+    scopes: Vec<HashMap<String, Binding>>,
+    functions: HashMap<&'a str, &'a FnDef>,
+    depth: usize,
+    /// Loop iterations and function calls run so far, over the whole script
+    steps: usize,
+    commands: Vec<ScannerCommand>,
+    errors: Vec<ParseError>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new() -> Interpreter<'a> {
+        let globals = BUILTIN_CONSTANTS
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    Binding {
+                        value: *value,
+                        constant: true,
+                    },
+                )
+            })
+            .collect();
+        Interpreter {
+            scopes: vec![globals],
+            functions: HashMap::new(),
+            depth: 0,
+            steps: 0,
+            commands: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Runs every statement, collecting the errors of each.
+    /// Functions are declared first so that they can be called before their definition.
+    pub fn run(mut self, statements: &'a [Stmt]) -> (Vec<ScannerCommand>, Vec<ParseError>) {
+        for stmt in statements {
+            if let Stmt::Fn(def) = stmt {
+                if let Err(e) = self.define(def) {
+                    self.errors.push(e);
+                }
+            }
+        }
+        for stmt in statements {
+            if let Err(e) = self.statement(stmt) {
+                self.errors.push(e);
+            }
+        }
+        (self.commands, self.errors)
+    }
+
+    fn define(&mut self, def: &'a FnDef) -> Result<(), ParseError> {
+        if signature(&def.name).is_some() || self.functions.contains_key(def.name.as_str()) {
+            return Err(ParseError::new(
+                def.span,
+                def.name.clone(),
+                ParseErrorKind::Redefinition,
+            ));
+        }
+        self.functions.insert(&def.name, def);
+        Ok(())
+    }
+
+    /// Runs statements in a new scope
+    fn block(&mut self, statements: &'a [Stmt]) {
+        self.scopes.push(HashMap::new());
+        for stmt in statements {
+            if let Err(e) = self.statement(stmt) {
+                self.errors.push(e);
+            }
+        }
+        self.scopes.pop();
+    }
+
+    /// Counts an iteration of the loop at `span`, `iterations` being its own count so far.
+    /// False once the script has run out of steps, see `step`.
+    fn iteration(
+        &mut self,
+        iterations: &mut usize,
+        span: Span,
+        keyword: &str,
+    ) -> Result<bool, ParseError> {
+        *iterations += 1;
+        if *iterations > MAX_ITERATIONS {
+            let kind = ParseErrorKind::IterationLimit;
+            return Err(ParseError::new(span, keyword.to_string(), kind));
+        }
+        self.step(span, keyword)
+    }
+
+    /// Counts a loop iteration or function call against the budget of the whole script.
+    /// False once it has run out, the step crossing the limit failing and the loops and
+    /// calls around it stopping silently.
+    fn step(&mut self, span: Span, found: &str) -> Result<bool, ParseError> {
+        self.steps += 1;
+        match self.steps {
+            n if n <= MAX_TOTAL_ITERATIONS => Ok(true),
+            n if n == MAX_TOTAL_ITERATIONS + 1 => Err(ParseError::new(
+                span,
+                found.to_string(),
+                ParseErrorKind::TotalIterationLimit,
+            )),
+            _ => Ok(false),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Binding> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn statement(&mut self, stmt: &'a Stmt) -> Result<(), ParseError> {
+        match stmt {
+            Stmt::Var { name, value, span } => self.declare(name, value, *span, false),
+            Stmt::Const { name, value, span } => self.declare(name, value, *span, true),
+            Stmt::Assign { name, value, span } => {
+                let value = self.eval(value)?;
+                let binding = self
+                    .scopes
+                    .iter_mut()
+                    .rev()
+                    .find_map(|scope| scope.get_mut(name));
+                match binding {
+                    Some(binding) if !binding.constant => {
+                        binding.value = value;
+                        Ok(())
+                    }
+                    Some(_) => Err(ParseError::new(
+                        *span,
+                        name.clone(),
+                        ParseErrorKind::AssignToConstant,
+                    )),
+                    None => Err(ParseError::new(
+                        *span,
+                        name.clone(),
+                        ParseErrorKind::UndefinedVariable,
+                    )),
+                }
+            }
+            Stmt::Command {
+                name,
+                args,
+                span,
+                close,
+            } => match self.functions.get(name.as_str()) {
+                Some(def) => self.call(def, args, *span),
+                None => {
+                    let command = self.command(name, args, *span, *close)?;
+                    self.commands.push(command);
+                    Ok(())
+                }
+            },
+            Stmt::For {
+                name,
+                start,
+                end,
+                body,
+                span,
+            } => {
+                let (start, end) = (self.eval(start)?, self.eval(end)?);
+                let mut i = start;
+                let mut iterations = 0;
+                while i < end {
+                    if !self.iteration(&mut iterations, *span, "for")? {
+                        break;
+                    }
+                    let mut scope = HashMap::new();
+                    scope.insert(
+                        name.clone(),
+                        Binding {
+                            value: i,
+                            constant: true,
+                        },
+                    );
+                    self.scopes.push(scope);
+                    self.block(body);
+                    self.scopes.pop();
+                    i += 1.0;
+                }
+                Ok(())
+            }
+            Stmt::While { cond, body, span } => {
+                let mut iterations = 0;
+                while self.eval(cond)? != 0.0 {
+                    if !self.iteration(&mut iterations, *span, "while")? {
+                        break;
+                    }
+                    self.block(body);
+                }
+                Ok(())
+            }
+            Stmt::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                if self.eval(cond)? != 0.0 {
+                    self.block(then);
+                } else {
+                    self.block(otherwise);
+                }
+                Ok(())
+            }
+            // declared up front by `run`; nested declarations are rejected by the parser
+            Stmt::Fn(_) => Ok(()),
+        }
+    }
+
+    /// Expands a user function call in place, its body only seeing globals and parameters
+    fn call(&mut self, def: &'a FnDef, args: &[Expr], span: Span) -> Result<(), ParseError> {
+        if args.len() != def.params.len() {
+            return Err(ParseError::new(
+                span,
+                def.name.clone(),
+                ParseErrorKind::ArgumentCount {
+                    expected: def.params.len(),
+                    got: args.len(),
+                },
+            )
+            .with_command(&def.name));
+        }
+        if self.depth >= MAX_CALL_DEPTH {
+            return Err(ParseError::new(
+                span,
+                def.name.clone(),
+                ParseErrorKind::RecursionLimit,
+            ));
+        }
+        if !self.step(span, &def.name)? {
+            return Ok(());
+        }
+        let params = def
+            .params
+            .iter()
+            .zip(args.iter())
+            .map(|(param, arg)| {
+                let value = self.eval(arg)?;
+                Ok((
+                    param.clone(),
+                    Binding {
+                        value,
+                        constant: false,
+                    },
+                ))
+            })
+            .collect::<Result<HashMap<String, Binding>, ParseError>>()?;
+
+        let caller_scopes = self.scopes.split_off(1);
+        self.scopes.push(params);
+        self.depth += 1;
+        self.block(&def.body);
+        self.depth -= 1;
+        self.scopes.truncate(1);
+        self.scopes.extend(caller_scopes);
+        Ok(())
+    }
+
+    fn declare(
+        &mut self,
+        name: &str,
+        value: &Expr,
+        span: Span,
+        constant: bool,
+    ) -> Result<(), ParseError> {
+        let value = self.eval(value)?;
+        let scope = self.scopes.last_mut().expect("global scope");
+        if scope.contains_key(name) {
+            return Err(ParseError::new(
+                span,
+                name.to_string(),
+                ParseErrorKind::Redefinition,
+            ));
+        }
+        scope.insert(name.to_string(), Binding { value, constant });
+        Ok(())
+    }
+
+    /// Checks a command call against the grammar and converts its arguments
+    fn command(
+        &self,
+        name: &str,
+        args: &[Expr],
+        span: Span,
+        close: Span,
+    ) -> Result<ScannerCommand, ParseError> {
+        let sig = signature(name).ok_or_else(|| {
+            ParseError::new(span, name.to_string(), ParseErrorKind::UnknownCommand)
+        })?;
+        if args.len() != sig.args.len() {
+            let (span, found) = match args.get(sig.args.len()) {
+                Some(extra) => (
+                    extra.span,
+                    self.eval(extra).map(|v| v.to_string()).unwrap_or_default(),
+                ),
+                None => (close, ")".to_string()),
+            };
+            return Err(ParseError::new(
+                span,
+                found,
+                ParseErrorKind::ArgumentCount {
+                    expected: sig.args.len(),
+                    got: args.len(),
+                },
+            )
+            .with_command(name));
+        }
+        let args = args
+            .iter()
+            .zip(sig.args.iter())
+            .map(|(arg, (arg_name, ty))| {
+                let value = self.eval(arg)?;
+                ty.convert(value).ok_or_else(|| {
+                    ParseError::new(
+                        arg.span,
+                        value.to_string(),
+                        ParseErrorKind::InvalidArgument {
+                            name: arg_name,
+                            ty: *ty,
+                        },
+                    )
+                    .with_command(name)
+                })
+            })
+            .collect::<Result<Vec<Arg>, ParseError>>()?;
+
+        Ok(ScannerCommand::from_args(sig, &args))
+    }
+
+    /// Evaluates an expression to a finite number
+    fn eval(&self, expr: &Expr) -> Result<f64, ParseError> {
+        let value = match &expr.kind {
+            ExprKind::Number(value) => *value,
+            ExprKind::Variable(name) => match self.lookup(name) {
+                Some(binding) => binding.value,
+                None => {
+                    return Err(ParseError::new(
+                        expr.span,
+                        name.clone(),
+                        ParseErrorKind::UndefinedVariable,
+                    ))
+                }
+            },
+            ExprKind::Neg(operand) => -self.eval(operand)?,
+            ExprKind::Not(operand) => truth(self.eval(operand)? == 0.0),
+            ExprKind::Binary(op, lhs, rhs) => {
+                let (lhs, rhs) = (self.eval(lhs)?, self.eval(rhs)?);
+                match op {
+                    BinOp::Add => lhs + rhs,
+                    BinOp::Sub => lhs - rhs,
+                    BinOp::Mul => lhs * rhs,
+                    BinOp::Div => lhs / rhs,
+                    BinOp::Rem => lhs % rhs,
+                    BinOp::Eq => truth(lhs == rhs),
+                    BinOp::Ne => truth(lhs != rhs),
+                    BinOp::Lt => truth(lhs < rhs),
+                    BinOp::Le => truth(lhs <= rhs),
+                    BinOp::Gt => truth(lhs > rhs),
+                    BinOp::Ge => truth(lhs >= rhs),
+                    BinOp::And => truth(lhs != 0.0 && rhs != 0.0),
+                    BinOp::Or => truth(lhs != 0.0 || rhs != 0.0),
+                }
+            }
+            ExprKind::Call(name, args) => {
+                let (_, arity, function) = FUNCTIONS
+                    .iter()
+                    .find(|(f, _, _)| f == name)
+                    .ok_or_else(|| {
+                        ParseError::new(expr.span, name.clone(), ParseErrorKind::UnknownFunction)
+                    })?;
+                if args.len() != *arity {
+                    return Err(ParseError::new(
+                        expr.span,
+                        name.clone(),
+                        ParseErrorKind::ArgumentCount {
+                            expected: *arity,
+                            got: args.len(),
+                        },
+                    )
+                    .with_command(name));
+                }
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg))
+                    .collect::<Result<Vec<f64>, ParseError>>()?;
+                function(&args)
+            }
+        };
+        if value.is_finite() {
+            Ok(value)
+        } else {
+            Err(ParseError::new(
+                expr.span,
+                value.to_string(),
+                ParseErrorKind::InvalidValue,
+            ))
+        }
+    }
+}
+
+/// Numeric value of a condition
+fn truth(b: bool) -> f64 {
+    if b {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::lexer::tokenize;
+    use crate::parsing::parser::Parser;
+
+    fn eval(source: &str) -> Result<f64, ParseError> {
+        let (statements, errors) =
+            Parser::new(tokenize(&format!("var result = {}", source)).0).parse();
+        assert!(errors.is_empty(), "{:?}", errors);
+        let mut interpreter = Interpreter::new();
+        interpreter.statement(&statements[0])?;
+        Ok(interpreter.lookup("result").unwrap().value)
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(eval("1 + 2 * 3").unwrap(), 7.0);
+        assert_eq!(eval("(1 + 2) * 3").unwrap(), 9.0);
+        assert_eq!(eval("10 / 4 - 1").unwrap(), 1.5);
+        assert_eq!(eval("-2 * -(3 - 1)").unwrap(), 4.0);
+        assert_eq!(eval("2 - 1 - 1").unwrap(), 0.0);
+        assert_eq!(eval("8 / 2 / 2").unwrap(), 2.0);
+        assert_eq!(eval("7 % 3").unwrap(), 1.0);
+    }
+
+    #[test]
+    fn conditions() {
+        assert_eq!(eval("1 < 2").unwrap(), 1.0);
+        assert_eq!(eval("2 <= 1").unwrap(), 0.0);
+        assert_eq!(eval("1 + 1 == 2 && !(3 != 3)").unwrap(), 1.0);
+        assert_eq!(eval("0 || 2 > 3").unwrap(), 0.0);
+        assert_eq!(eval("!0").unwrap(), 1.0);
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(eval("sqrt(16) + abs(-2)").unwrap(), 6.0);
+        assert_eq!(eval("cos(0) + sin(0)").unwrap(), 1.0);
+        assert_eq!(eval("max(1, min(5, 3))").unwrap(), 3.0);
+        assert!((eval("sin(PI / 2)").unwrap() - 1.0).abs() < 1e-12);
+        assert_eq!(eval("deg(PI)").unwrap(), 180.0);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            eval("1 / 0").unwrap_err().kind,
+            ParseErrorKind::InvalidValue
+        );
+        assert_eq!(
+            eval("sqrt(-1)").unwrap_err().kind,
+            ParseErrorKind::InvalidValue
+        );
+        assert_eq!(
+            eval("foo(1)").unwrap_err().kind,
+            ParseErrorKind::UnknownFunction
+        );
+        assert_eq!(
+            eval("y + 1").unwrap_err().kind,
+            ParseErrorKind::UndefinedVariable
+        );
+        assert_eq!(
+            eval("sqrt(1, 2)").unwrap_err().kind,
+            ParseErrorKind::ArgumentCount {
+                expected: 1,
+                got: 2
+            }
+        );
+    }
+}
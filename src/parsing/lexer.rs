@@ -0,0 +1,283 @@
+use crate::parsing::error::{ParseError, ParseErrorKind, Span};
+
+/// Kind of a lexical token in a rhothor script
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Ident(String),
+    Number(String),
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Comma,
+    Semicolon,
+    DotDot,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Assign,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Not,
+}
+
+/// A token along with its location in the source
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl TokenKind {
+    /// Source text of the token, as shown in diagnostics
+    pub fn text(&self) -> String {
+        match self {
+            TokenKind::Ident(s) | TokenKind::Number(s) => s.clone(),
+            TokenKind::LParen => "(".to_string(),
+            TokenKind::RParen => ")".to_string(),
+            TokenKind::LBrace => "{".to_string(),
+            TokenKind::RBrace => "}".to_string(),
+            TokenKind::Comma => ",".to_string(),
+            TokenKind::Semicolon => ";".to_string(),
+            TokenKind::DotDot => "..".to_string(),
+            TokenKind::Plus => "+".to_string(),
+            TokenKind::Minus => "-".to_string(),
+            TokenKind::Star => "*".to_string(),
+            TokenKind::Slash => "/".to_string(),
+            TokenKind::Percent => "%".to_string(),
+            TokenKind::Assign => "=".to_string(),
+            TokenKind::Eq => "==".to_string(),
+            TokenKind::Ne => "!=".to_string(),
+            TokenKind::Lt => "<".to_string(),
+            TokenKind::Le => "<=".to_string(),
+            TokenKind::Gt => ">".to_string(),
+            TokenKind::Ge => ">=".to_string(),
+            TokenKind::And => "&&".to_string(),
+            TokenKind::Or => "||".to_string(),
+            TokenKind::Not => "!".to_string(),
+        }
+    }
+}
+
+/// Splits a script into tokens, ditching whitespace and `//` comments.
+/// Unknown characters are reported and skipped so that lexing always completes.
+pub fn tokenize(source: &str) -> (Vec<Token>, Vec<ParseError>) {
+    let mut tokens = Vec::new();
+    let mut errors = Vec::new();
+
+    for (line_idx, line) in source.lines().enumerate() {
+        let chars = line.chars().collect::<Vec<char>>();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+            let start = i;
+            let kind = match c {
+                _ if c.is_whitespace() => {
+                    i += 1;
+                    continue;
+                }
+                '/' if next == Some('/') => break,
+                '(' => TokenKind::LParen,
+                ')' => TokenKind::RParen,
+                '{' => TokenKind::LBrace,
+                '}' => TokenKind::RBrace,
+                ',' => TokenKind::Comma,
+                ';' => TokenKind::Semicolon,
+                '.' if next == Some('.') => {
+                    i += 1;
+                    TokenKind::DotDot
+                }
+                '+' => TokenKind::Plus,
+                '-' => TokenKind::Minus,
+                '*' => TokenKind::Star,
+                '/' => TokenKind::Slash,
+                '%' => TokenKind::Percent,
+                '=' | '!' | '<' | '>' if next == Some('=') => {
+                    i += 1;
+                    match c {
+                        '=' => TokenKind::Eq,
+                        '!' => TokenKind::Ne,
+                        '<' => TokenKind::Le,
+                        _ => TokenKind::Ge,
+                    }
+                }
+                '=' => TokenKind::Assign,
+                '!' => TokenKind::Not,
+                '<' => TokenKind::Lt,
+                '>' => TokenKind::Gt,
+                '&' | '|' if next == Some(c) => {
+                    i += 1;
+                    match c {
+                        '&' => TokenKind::And,
+                        _ => TokenKind::Or,
+                    }
+                }
+                _ if c.is_ascii_digit() || c == '.' => {
+                    // a `..` range operator ends the number, e.g. `0..10`
+                    while i < chars.len()
+                        && (chars[i].is_ascii_digit()
+                            || (chars[i] == '.' && chars.get(i + 1) != Some(&'.')))
+                    {
+                        i += 1;
+                    }
+                    if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                        let mut j = i + 1;
+                        if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                            j += 1;
+                        }
+                        if j < chars.len() && chars[j].is_ascii_digit() {
+                            i = j;
+                            while i < chars.len() && chars[i].is_ascii_digit() {
+                                i += 1;
+                            }
+                        }
+                    }
+                    i -= 1;
+                    TokenKind::Number(chars[start..=i].iter().collect())
+                }
+                _ if c.is_ascii_alphabetic() || c == '_' => {
+                    while i + 1 < chars.len()
+                        && (chars[i + 1].is_ascii_alphanumeric() || chars[i + 1] == '_')
+                    {
+                        i += 1;
+                    }
+                    TokenKind::Ident(chars[start..=i].iter().collect())
+                }
+                _ => {
+                    errors.push(ParseError::new(
+                        Span::new(line_idx + 1, start + 1, 1),
+                        c.to_string(),
+                        ParseErrorKind::UnexpectedChar,
+                    ));
+                    i += 1;
+                    continue;
+                }
+            };
+            i += 1;
+            tokens.push(Token {
+                kind,
+                span: Span::new(line_idx + 1, start + 1, i - start),
+            });
+        }
+    }
+
+    (tokens, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        tokenize(source).0.into_iter().map(|t| t.kind).collect()
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(
+            kinds("rtJumpTo(-1.5e3, 2); // comment"),
+            vec![
+                TokenKind::Ident("rtJumpTo".to_string()),
+                TokenKind::LParen,
+                TokenKind::Minus,
+                TokenKind::Number("1.5e3".to_string()),
+                TokenKind::Comma,
+                TokenKind::Number("2".to_string()),
+                TokenKind::RParen,
+                TokenKind::Semicolon,
+            ]
+        );
+        assert!(kinds("// only a comment").is_empty());
+        assert_eq!(
+            kinds("var x=(a+1)*2/3;"),
+            vec![
+                TokenKind::Ident("var".to_string()),
+                TokenKind::Ident("x".to_string()),
+                TokenKind::Assign,
+                TokenKind::LParen,
+                TokenKind::Ident("a".to_string()),
+                TokenKind::Plus,
+                TokenKind::Number("1".to_string()),
+                TokenKind::RParen,
+                TokenKind::Star,
+                TokenKind::Number("2".to_string()),
+                TokenKind::Slash,
+                TokenKind::Number("3".to_string()),
+                TokenKind::Semicolon,
+            ]
+        );
+    }
+
+    #[test]
+    fn control_flow_tokens() {
+        assert_eq!(
+            kinds("for i in 0..10 { if i % 2 == 0 && !(i >= 4 || i != 1) {} }"),
+            vec![
+                TokenKind::Ident("for".to_string()),
+                TokenKind::Ident("i".to_string()),
+                TokenKind::Ident("in".to_string()),
+                TokenKind::Number("0".to_string()),
+                TokenKind::DotDot,
+                TokenKind::Number("10".to_string()),
+                TokenKind::LBrace,
+                TokenKind::Ident("if".to_string()),
+                TokenKind::Ident("i".to_string()),
+                TokenKind::Percent,
+                TokenKind::Number("2".to_string()),
+                TokenKind::Eq,
+                TokenKind::Number("0".to_string()),
+                TokenKind::And,
+                TokenKind::Not,
+                TokenKind::LParen,
+                TokenKind::Ident("i".to_string()),
+                TokenKind::Ge,
+                TokenKind::Number("4".to_string()),
+                TokenKind::Or,
+                TokenKind::Ident("i".to_string()),
+                TokenKind::Ne,
+                TokenKind::Number("1".to_string()),
+                TokenKind::RParen,
+                TokenKind::LBrace,
+                TokenKind::RBrace,
+                TokenKind::RBrace,
+            ]
+        );
+        assert_eq!(
+            kinds("1.5..2"),
+            vec![
+                TokenKind::Number("1.5".to_string()),
+                TokenKind::DotDot,
+                TokenKind::Number("2".to_string()),
+            ]
+        );
+        assert_eq!(kinds("a<b"), kinds("a < b"));
+        assert_eq!(kinds("a<=b")[1], TokenKind::Le);
+    }
+
+    #[test]
+    fn spans() {
+        let (tokens, errors) = tokenize("rtSleep(1);\n  rtBurst(20);");
+        assert!(errors.is_empty());
+        assert_eq!(tokens[0].span, Span::new(1, 1, 7));
+        assert_eq!(tokens[5].span, Span::new(2, 3, 7));
+        assert_eq!(tokens[7].span, Span::new(2, 11, 2));
+    }
+
+    #[test]
+    fn unexpected_char() {
+        let (tokens, errors) = tokenize("rtSleep(1) @");
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span, Span::new(1, 12, 1));
+        assert_eq!(errors[0].kind, ParseErrorKind::UnexpectedChar);
+    }
+}
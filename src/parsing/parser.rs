@@ -0,0 +1,555 @@
+use crate::parsing::ast::{BinOp, Expr, ExprKind, FnDef, Stmt};
+use crate::parsing::error::{ParseError, ParseErrorKind, Span};
+use crate::parsing::lexer::{Token, TokenKind};
+use crate::parsing::signature;
+
+/// Words that cannot be used as variable or function names
+const KEYWORDS: &[&str] = &["var", "const", "for", "in", "while", "if", "else", "fn"];
+
+/// Recursive descent parser turning tokens into script statements
+pub struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    errors: Vec<ParseError>,
+    /// Number of blocks enclosing the current statement
+    depth: usize,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Token>) -> Parser {
+        Parser {
+            tokens,
+            pos: 0,
+            errors: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    /// Parses every statement, recovering after each error
+    pub fn parse(mut self) -> (Vec<Stmt>, Vec<ParseError>) {
+        let statements = self.statements();
+        (statements, self.errors)
+    }
+
+    /// Parses statements up to the end of input or of the enclosing block
+    fn statements(&mut self) -> Vec<Stmt> {
+        let mut statements = Vec::new();
+        while self.pos < self.tokens.len() {
+            if self.depth > 0 && self.peek() == Some(&TokenKind::RBrace) {
+                break;
+            }
+            let start = self.pos;
+            match self.statement() {
+                Ok(stmt) => statements.push(stmt),
+                Err(e) => {
+                    self.errors.push(e);
+                    self.recover(start);
+                }
+            }
+        }
+        statements
+    }
+
+    /// block := '{' statement* '}'
+    fn block(&mut self) -> Result<Vec<Stmt>, ParseError> {
+        self.expect(TokenKind::LBrace, "`{`")?;
+        self.depth += 1;
+        let statements = self.statements();
+        self.depth -= 1;
+        self.expect(TokenKind::RBrace, "`}`")?;
+        Ok(statements)
+    }
+
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.pos).map(|t| &t.kind)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&TokenKind> {
+        self.tokens.get(self.pos + offset).map(|t| &t.kind)
+    }
+
+    /// Span of the current token, or just past the last one at end of input
+    fn span(&self) -> Span {
+        match self.tokens.get(self.pos) {
+            Some(token) => token.span,
+            None => self
+                .tokens
+                .last()
+                .map(|t| Span::new(t.span.line, t.span.column + t.span.len, 0))
+                .unwrap_or_else(|| Span::new(1, 1, 0)),
+        }
+    }
+
+    /// Span running from `start` to the end of the previous token
+    fn span_from(&self, start: Span) -> Span {
+        match self.pos.checked_sub(1).and_then(|i| self.tokens.get(i)) {
+            Some(last) if last.span.line == start.line => Span::new(
+                start.line,
+                start.column,
+                last.span.column + last.span.len - start.column,
+            ),
+            _ => start,
+        }
+    }
+
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        let found = self.peek().map(TokenKind::text).unwrap_or_default();
+        ParseError::new(self.span(), found, kind)
+    }
+
+    fn expect(&mut self, kind: TokenKind, what: &'static str) -> Result<(), ParseError> {
+        if self.peek() == Some(&kind) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(ParseErrorKind::Expected(what)))
+        }
+    }
+
+    fn ident(&mut self, what: &'static str) -> Result<String, ParseError> {
+        match self.peek() {
+            Some(TokenKind::Ident(name)) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => Err(self.error(ParseErrorKind::Expected(what))),
+        }
+    }
+
+    fn end_of_statement(&mut self) {
+        if self.peek() == Some(&TokenKind::Semicolon) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skips the rest of a broken statement: up to the next `;`, `}` or line
+    fn recover(&mut self, start: usize) {
+        let line = self.tokens[start].span.line;
+        self.pos = self.pos.max(start + 1);
+        while let Some(token) = self.tokens.get(self.pos) {
+            if token.span.line != line || token.kind == TokenKind::RBrace {
+                break;
+            }
+            self.pos += 1;
+            if token.kind == TokenKind::Semicolon {
+                break;
+            }
+        }
+    }
+
+    /// statement := ('var' | 'const') IDENT '=' expr [';']
+    ///            | IDENT '=' expr [';']
+    ///            | IDENT '(' [expr (',' expr)*] ')' [';']
+    ///            | 'for' IDENT 'in' expr '..' expr block
+    ///            | 'while' expr block
+    ///            | 'if' expr block ['else' (if-statement | block)]
+    ///            | 'fn' IDENT '(' [IDENT (',' IDENT)*] ')' block
+    fn statement(&mut self) -> Result<Stmt, ParseError> {
+        let span = self.span();
+        let keyword = match self.peek() {
+            Some(TokenKind::Ident(name)) => name.clone(),
+            _ => return Err(self.error(ParseErrorKind::Expected("a statement"))),
+        };
+        self.pos += 1;
+        match (keyword.as_str(), self.peek()) {
+            ("for", _) => {
+                let name = self.ident("a loop variable")?;
+                match self.peek() {
+                    Some(TokenKind::Ident(word)) if word == "in" => self.pos += 1,
+                    _ => return Err(self.error(ParseErrorKind::Expected("`in`"))),
+                }
+                let start = self.expr()?;
+                self.expect(TokenKind::DotDot, "`..`")?;
+                let end = self.expr()?;
+                let body = self.block()?;
+                Ok(Stmt::For {
+                    name,
+                    start,
+                    end,
+                    body,
+                    span,
+                })
+            }
+            ("while", _) => {
+                let cond = self.expr()?;
+                let body = self.block()?;
+                Ok(Stmt::While { cond, body, span })
+            }
+            ("if", _) => self.if_statement(),
+            ("fn", _) if self.depth > 0 => Err(ParseError::new(
+                span,
+                keyword,
+                ParseErrorKind::NestedFunction,
+            )),
+            ("fn", _) => {
+                let name = self.ident("a function name")?;
+                self.expect(TokenKind::LParen, "`(`")?;
+                let mut params = Vec::new();
+                if self.peek() != Some(&TokenKind::RParen) {
+                    loop {
+                        params.push(self.ident("a parameter name")?);
+                        match self.peek() {
+                            Some(TokenKind::Comma) => self.pos += 1,
+                            _ => break,
+                        }
+                    }
+                }
+                self.expect(TokenKind::RParen, "`,` or `)`")?;
+                let body = self.block()?;
+                Ok(Stmt::Fn(FnDef {
+                    name,
+                    params,
+                    body,
+                    span,
+                }))
+            }
+            (word, _) if KEYWORDS.contains(&word) && word != "var" && word != "const" => Err(
+                ParseError::new(span, keyword, ParseErrorKind::Expected("a statement")),
+            ),
+            ("var", Some(TokenKind::Ident(_))) | ("const", Some(TokenKind::Ident(_))) => {
+                let name = self.ident("a name")?;
+                self.expect(TokenKind::Assign, "`=`")?;
+                let value = self.expr()?;
+                self.end_of_statement();
+                let span = self.span_from(span);
+                Ok(match keyword.as_str() {
+                    "var" => Stmt::Var { name, value, span },
+                    _ => Stmt::Const { name, value, span },
+                })
+            }
+            ("var", _) | ("const", _) => Err(self.error(ParseErrorKind::Expected("a name"))),
+            (_, Some(TokenKind::Assign)) => {
+                self.pos += 1;
+                let value = self.expr()?;
+                self.end_of_statement();
+                Ok(Stmt::Assign {
+                    name: keyword,
+                    value,
+                    span,
+                })
+            }
+            (_, Some(TokenKind::LParen)) => {
+                self.pos += 1;
+                let args = self.args().map_err(|e| e.with_command(&keyword))?;
+                let close = self.span();
+                self.expect(TokenKind::RParen, "`,` or `)`")
+                    .map_err(|e| e.with_command(&keyword))?;
+                self.end_of_statement();
+                Ok(Stmt::Command {
+                    name: keyword,
+                    args,
+                    span,
+                    close,
+                })
+            }
+            // a bare word that names no command
+            (word, None | Some(TokenKind::Semicolon)) if signature(word).is_none() => Err(
+                ParseError::new(span, keyword, ParseErrorKind::UnknownCommand),
+            ),
+            _ => Err(self
+                .error(ParseErrorKind::Expected("`(` or `=`"))
+                .with_command(&keyword)),
+        }
+    }
+
+    /// Parses what follows an `if` keyword
+    fn if_statement(&mut self) -> Result<Stmt, ParseError> {
+        let cond = self.expr()?;
+        let then = self.block()?;
+        let otherwise = match self.peek() {
+            Some(TokenKind::Ident(word)) if word == "else" => {
+                self.pos += 1;
+                match self.peek() {
+                    Some(TokenKind::Ident(word)) if word == "if" => {
+                        self.pos += 1;
+                        vec![self.if_statement()?]
+                    }
+                    _ => self.block()?,
+                }
+            }
+            _ => Vec::new(),
+        };
+        Ok(Stmt::If {
+            cond,
+            then,
+            otherwise,
+        })
+    }
+
+    /// args := [expr (',' expr)*], stopping before the closing `)`
+    fn args(&mut self) -> Result<Vec<Expr>, ParseError> {
+        let mut args = Vec::new();
+        if self.peek() == Some(&TokenKind::RParen) {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.expr()?);
+            match self.peek() {
+                Some(TokenKind::Comma) => self.pos += 1,
+                _ => return Ok(args),
+            }
+        }
+    }
+
+    /// Parses a left-associative chain of binary operators
+    fn binary(
+        &mut self,
+        operand: fn(&mut Parser) -> Result<Expr, ParseError>,
+        operator: fn(&TokenKind) -> Option<BinOp>,
+    ) -> Result<Expr, ParseError> {
+        let start = self.span();
+        let mut lhs = operand(self)?;
+        while let Some(op) = self.peek().and_then(operator) {
+            self.pos += 1;
+            let rhs = operand(self)?;
+            lhs = Expr {
+                kind: ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)),
+                span: self.span_from(start),
+            };
+        }
+        Ok(lhs)
+    }
+
+    /// expr := and ('||' and)*
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        self.binary(Parser::and, |t| match t {
+            TokenKind::Or => Some(BinOp::Or),
+            _ => None,
+        })
+    }
+
+    /// and := comparison ('&&' comparison)*
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        self.binary(Parser::comparison, |t| match t {
+            TokenKind::And => Some(BinOp::And),
+            _ => None,
+        })
+    }
+
+    /// comparison := sum (('==' | '!=' | '<' | '<=' | '>' | '>=') sum)*
+    fn comparison(&mut self) -> Result<Expr, ParseError> {
+        self.binary(Parser::sum, |t| match t {
+            TokenKind::Eq => Some(BinOp::Eq),
+            TokenKind::Ne => Some(BinOp::Ne),
+            TokenKind::Lt => Some(BinOp::Lt),
+            TokenKind::Le => Some(BinOp::Le),
+            TokenKind::Gt => Some(BinOp::Gt),
+            TokenKind::Ge => Some(BinOp::Ge),
+            _ => None,
+        })
+    }
+
+    /// sum := term (('+' | '-') term)*
+    fn sum(&mut self) -> Result<Expr, ParseError> {
+        self.binary(Parser::term, |t| match t {
+            TokenKind::Plus => Some(BinOp::Add),
+            TokenKind::Minus => Some(BinOp::Sub),
+            _ => None,
+        })
+    }
+
+    /// term := unary (('*' | '/' | '%') unary)*
+    fn term(&mut self) -> Result<Expr, ParseError> {
+        self.binary(Parser::unary, |t| match t {
+            TokenKind::Star => Some(BinOp::Mul),
+            TokenKind::Slash => Some(BinOp::Div),
+            TokenKind::Percent => Some(BinOp::Rem),
+            _ => None,
+        })
+    }
+
+    /// unary := ('-' | '!') unary | primary
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        let start = self.span();
+        let wrap = match self.peek() {
+            Some(TokenKind::Minus) => ExprKind::Neg,
+            Some(TokenKind::Not) => ExprKind::Not,
+            _ => return self.primary(),
+        };
+        self.pos += 1;
+        let operand = self.unary()?;
+        Ok(Expr {
+            kind: wrap(Box::new(operand)),
+            span: self.span_from(start),
+        })
+    }
+
+    /// primary := NUMBER | IDENT | IDENT '(' args ')' | '(' expr ')'
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let start = self.span();
+        let kind = match self.peek().cloned() {
+            Some(TokenKind::Number(text)) => {
+                let value = text
+                    .parse::<f64>()
+                    .map_err(|_| self.error(ParseErrorKind::InvalidNumber))?;
+                self.pos += 1;
+                ExprKind::Number(value)
+            }
+            Some(TokenKind::Ident(name)) if self.peek_at(1) == Some(&TokenKind::LParen) => {
+                self.pos += 2;
+                let args = self.args()?;
+                self.expect(TokenKind::RParen, "`,` or `)`")?;
+                ExprKind::Call(name, args)
+            }
+            Some(TokenKind::Ident(name)) if !KEYWORDS.contains(&name.as_str()) => {
+                self.pos += 1;
+                ExprKind::Variable(name)
+            }
+            Some(TokenKind::LParen) => {
+                self.pos += 1;
+                let inner = self.expr()?;
+                self.expect(TokenKind::RParen, "`)`")?;
+                inner.kind
+            }
+            _ => return Err(self.error(ParseErrorKind::Expected("an expression"))),
+        };
+        Ok(Expr {
+            kind,
+            span: self.span_from(start),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::lexer::tokenize;
+
+    fn parse_expr(source: &str) -> Expr {
+        let mut parser = Parser::new(tokenize(source).0);
+        parser.expr().unwrap()
+    }
+
+    fn num(value: f64) -> Box<Expr> {
+        Box::new(Expr {
+            kind: ExprKind::Number(value),
+            span: Span::new(0, 0, 0),
+        })
+    }
+
+    /// Drops spans so that trees can be compared structurally
+    fn strip(expr: Expr) -> Expr {
+        let kind = match expr.kind {
+            ExprKind::Neg(e) => ExprKind::Neg(Box::new(strip(*e))),
+            ExprKind::Not(e) => ExprKind::Not(Box::new(strip(*e))),
+            ExprKind::Binary(op, l, r) => {
+                ExprKind::Binary(op, Box::new(strip(*l)), Box::new(strip(*r)))
+            }
+            ExprKind::Call(name, args) => {
+                ExprKind::Call(name, args.into_iter().map(strip).collect())
+            }
+            kind => kind,
+        };
+        Expr {
+            kind,
+            span: Span::new(0, 0, 0),
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(
+            strip(parse_expr("1 + 2 * 3")).kind,
+            ExprKind::Binary(
+                BinOp::Add,
+                num(1.0),
+                Box::new(strip(Expr {
+                    kind: ExprKind::Binary(BinOp::Mul, num(2.0), num(3.0)),
+                    span: Span::new(0, 0, 0),
+                }))
+            )
+        );
+        assert_eq!(
+            strip(parse_expr("-(1 - 2)")).kind,
+            ExprKind::Neg(Box::new(Expr {
+                kind: ExprKind::Binary(BinOp::Sub, num(1.0), num(2.0)),
+                span: Span::new(0, 0, 0),
+            }))
+        );
+    }
+
+    #[test]
+    fn logical_precedence() {
+        let binary = |op, l, r| Expr {
+            kind: ExprKind::Binary(op, l, r),
+            span: Span::new(0, 0, 0),
+        };
+        let var = |name: &str| {
+            Box::new(Expr {
+                kind: ExprKind::Variable(name.to_string()),
+                span: Span::new(0, 0, 0),
+            })
+        };
+        // a < 1 || b == 2 && c  =>  (a < 1) || ((b == 2) && c)
+        assert_eq!(
+            strip(parse_expr("a < 1 || b == 2 && c")),
+            binary(
+                BinOp::Or,
+                Box::new(binary(BinOp::Lt, var("a"), num(1.0))),
+                Box::new(binary(
+                    BinOp::And,
+                    Box::new(binary(BinOp::Eq, var("b"), num(2.0))),
+                    var("c")
+                ))
+            )
+        );
+    }
+
+    #[test]
+    fn blocks() {
+        let source = "fn part(x) {\n  rtJumpTo(x, 0);\n}\nfor i in 0..3 {\n  if i % 2 == 0 { part(i); } else if i > 1 { rtSleep(1); } else { rtSleep(2); }\n}\nwhile 0 { }";
+        let (statements, errors) = Parser::new(tokenize(source).0).parse();
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(statements.len(), 3);
+        assert!(
+            matches!(&statements[0], Stmt::Fn(FnDef { name, params, body, .. }) if name == "part" && params == &["x"] && body.len() == 1)
+        );
+        match &statements[1] {
+            Stmt::For { name, body, .. } => {
+                assert_eq!(name, "i");
+                assert!(
+                    matches!(&body[0], Stmt::If { then, otherwise, .. } if then.len() == 1 && matches!(&otherwise[0], Stmt::If { .. }))
+                );
+            }
+            stmt => panic!("unexpected {:?}", stmt),
+        }
+        assert!(matches!(&statements[2], Stmt::While { body, .. } if body.is_empty()));
+    }
+
+    #[test]
+    fn block_errors() {
+        let (_, errors) =
+            Parser::new(tokenize("for i in 0..2 {\n  rtSleep(1 2);\n  rtSleep(3);\n}").0).parse();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span.line, 2);
+
+        let (_, errors) = Parser::new(tokenize("while 1 {\n  fn f() {}\n}").0).parse();
+        assert_eq!(errors[0].kind, ParseErrorKind::NestedFunction);
+
+        let (_, errors) = Parser::new(tokenize("if 1 {\n  rtSleep(3);").0).parse();
+        assert_eq!(errors[0].kind, ParseErrorKind::Expected("`}`"));
+
+        let (_, errors) = Parser::new(tokenize("var for = 1;").0).parse();
+        assert_eq!(errors[0].kind, ParseErrorKind::Expected("a name"));
+    }
+
+    #[test]
+    fn expression_span() {
+        assert_eq!(parse_expr("  x0 + 5 ").span, Span::new(1, 3, 6));
+    }
+
+    #[test]
+    fn statements() {
+        let (statements, errors) =
+            Parser::new(tokenize("var x = 1;\nconst Y = x;\nx = 2\nrtSleep(x)").0).parse();
+        assert!(errors.is_empty());
+        assert_eq!(statements.len(), 4);
+        assert!(matches!(&statements[0], Stmt::Var { name, .. } if name == "x"));
+        assert!(matches!(&statements[1], Stmt::Const { name, .. } if name == "Y"));
+        assert!(matches!(&statements[2], Stmt::Assign { name, .. } if name == "x"));
+        assert!(
+            matches!(&statements[3], Stmt::Command { name, args, .. } if name == "rtSleep" && args.len() == 1)
+        );
+    }
+}
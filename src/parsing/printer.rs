@@ -0,0 +1,204 @@
+use super::ScannerCommand;
+use std::io::{self, Write};
+
+/// Writes scanner commands back as a rhothor script, one statement per line, indenting
+/// the commands between ListOpen/ListClose, SetLoop/DoLoop and WhileIO/DoWhile
+pub struct ScriptWriter<W: Write> {
+    out: W,
+    depth: usize,
+}
+
+impl<W: Write> ScriptWriter<W> {
+    pub fn new(out: W) -> ScriptWriter<W> {
+        ScriptWriter { out, depth: 0 }
+    }
+
+    /// Writes a command on its own line, `None` being skipped
+    pub fn command(&mut self, command: &ScannerCommand) -> io::Result<()> {
+        self.statement(command, None)
+    }
+
+    /// Writes a command followed by a `//` comment on the same line
+    pub fn command_with_comment(
+        &mut self,
+        command: &ScannerCommand,
+        comment: &str,
+    ) -> io::Result<()> {
+        self.statement(command, Some(comment))
+    }
+
+    /// Writes a comment, one `//` line per line of text
+    pub fn comment(&mut self, comment: &str) -> io::Result<()> {
+        for line in comment.lines() {
+            self.indent()?;
+            writeln!(self.out, "// {}", line)?;
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn statement(&mut self, command: &ScannerCommand, comment: Option<&str>) -> io::Result<()> {
+        if let ScannerCommand::None = command {
+            return match comment {
+                Some(comment) => self.comment(comment),
+                None => Ok(()),
+            };
+        }
+        if let ScannerCommand::ListClose | ScannerCommand::DoLoop | ScannerCommand::DoWhile =
+            command
+        {
+            self.depth = self.depth.saturating_sub(1);
+        }
+        self.indent()?;
+        match comment {
+            // a trailing comment ends at the end of the line
+            Some(comment) => writeln!(self.out, "{} // {}", command, comment.replace('\n', " "))?,
+            None => writeln!(self.out, "{}", command)?,
+        }
+        if let ScannerCommand::ListOpen(_)
+        | ScannerCommand::SetLoop(_)
+        | ScannerCommand::WhileIO(..) = command
+        {
+            self.depth += 1;
+        }
+        Ok(())
+    }
+
+    fn indent(&mut self) -> io::Result<()> {
+        write!(self.out, "{:1$}", "", self.depth * 4)
+    }
+}
+
+/// Prints commands as a script that `parse_script` reads back into the same commands
+pub fn print_script(commands: &[ScannerCommand]) -> String {
+    let mut writer = ScriptWriter::new(Vec::new());
+    for command in commands {
+        // writing to a Vec cannot fail
+        writer.command(command).unwrap();
+    }
+    String::from_utf8(writer.into_inner()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{ListMode, Position};
+    use crate::parsing::parse_script;
+    use proptest::prelude::*;
+
+    #[test]
+    fn print() {
+        use ScannerCommand::*;
+        assert_eq!(
+            Line(Position::new(1.5, 2.0)).to_string(),
+            "rtLineTo(1.5,2);"
+        );
+        assert_eq!(
+            Arc(Position::new(-0.1, 0.0), 0.5).to_string(),
+            "rtArcTo(-0.1,0,0.5);"
+        );
+        assert_eq!(SetLaser(true).to_string(), "rtSetLaser(true);");
+        assert_eq!(
+            ListOpen(ListMode::CompileAndStore).to_string(),
+            "rtListOpen(2);"
+        );
+        assert_eq!(DoLoop.to_string(), "rtDoLoop();");
+        assert_eq!(None.to_string(), "");
+
+        let commands = vec![
+            ListOpen(ListMode::CompileAndStart),
+            SetLoop(2),
+            Jump(Position::new(0.0, 0.0)),
+            DoLoop,
+            None,
+            ListClose,
+        ];
+        assert_eq!(
+            print_script(&commands),
+            "rtListOpen(4);\n    rtSetLoop(2);\n        rtJumpTo(0,0);\n    rtDoLoop();\nrtListClose();\n"
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let mut writer = ScriptWriter::new(Vec::new());
+        writer.comment("generated\nby a test").unwrap();
+        writer
+            .command_with_comment(&ScannerCommand::SetLoop(3), "three\ntimes")
+            .unwrap();
+        writer.comment("inside").unwrap();
+        writer
+            .command_with_comment(&ScannerCommand::DoLoop, "done")
+            .unwrap();
+        let script = String::from_utf8(writer.into_inner()).unwrap();
+        assert_eq!(
+            script,
+            "// generated\n// by a test\nrtSetLoop(3); // three times\n    // inside\nrtDoLoop(); // done\n"
+        );
+        assert_eq!(
+            parse_script(&script).unwrap(),
+            vec![ScannerCommand::SetLoop(3), ScannerCommand::DoLoop]
+        );
+    }
+
+    fn finite_f64() -> impl Strategy<Value = f64> {
+        prop_oneof![
+            -1000.0..1000.0,
+            proptest::num::f64::NORMAL | proptest::num::f64::SUBNORMAL | proptest::num::f64::ZERO,
+        ]
+    }
+
+    fn finite_f32() -> impl Strategy<Value = f32> {
+        prop_oneof![
+            -1000.0f32..1000.0,
+            proptest::num::f32::NORMAL | proptest::num::f32::SUBNORMAL | proptest::num::f32::ZERO,
+        ]
+    }
+
+    fn position() -> impl Strategy<Value = Position> {
+        (finite_f64(), finite_f64()).prop_map(|(x, y)| Position::new(x, y))
+    }
+
+    fn command() -> impl Strategy<Value = ScannerCommand> {
+        use ScannerCommand::*;
+        let mode = prop_oneof![
+            Just(ListMode::Immediate),
+            Just(ListMode::Append),
+            Just(ListMode::CompileAndStore),
+            Just(ListMode::CompileAndStart),
+        ];
+        prop_oneof![
+            mode.prop_map(ListOpen),
+            Just(ListClose),
+            position().prop_map(Jump),
+            position().prop_map(Move),
+            position().prop_map(Line),
+            (position(), finite_f32()).prop_map(|(p, bf)| Arc(p, bf)),
+            (position(), finite_f32()).prop_map(|(p, a)| Circle(p, a)),
+            (any::<u16>(), any::<u16>()).prop_map(|(v, m)| SetIO(v, m)),
+            (any::<u16>(), any::<u16>()).prop_map(|(v, m)| SetAnalog(v, m)),
+            (any::<u16>(), any::<u16>(), any::<u32>()).prop_map(|(v, m, t)| WaitIO(v, m, t)),
+            finite_f32().prop_map(SetSpeed),
+            finite_f32().prop_map(SetJumpSpeed),
+            any::<u16>().prop_map(Sleep),
+            any::<u16>().prop_map(Burst),
+            any::<bool>().prop_map(SetLaser),
+            (any::<u16>(), any::<u16>()).prop_map(|(on, off)| SetLaserTimes(on, off)),
+            any::<u8>().prop_map(SetTarget),
+            (any::<u16>(), any::<u16>()).prop_map(|(v, m)| WhileIO(v, m)),
+            Just(DoWhile),
+            any::<u16>().prop_map(SetLoop),
+            Just(DoLoop),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(commands in proptest::collection::vec(command(), 0..32)) {
+            prop_assert_eq!(parse_script(&print_script(&commands)).unwrap(), commands);
+        }
+    }
+}
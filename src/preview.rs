@@ -0,0 +1,469 @@
+use crate::commands::{Position, FLAT_BULGE};
+use crate::parsing::ScannerCommand;
+use std::fmt::Write;
+
+/// Largest side of a picture in pixels, keeping PNG pixmaps to a sane size
+pub const MAX_SIZE: u32 = 16384;
+
+/// How a `Preview` is drawn
+#[derive(Debug, Clone)]
+pub struct PreviewOptions {
+    size: u32,
+    color_by_speed: bool,
+    jumps: bool,
+}
+
+impl Default for PreviewOptions {
+    fn default() -> PreviewOptions {
+        PreviewOptions {
+            size: 1024,
+            color_by_speed: false,
+            jumps: true,
+        }
+    }
+}
+
+impl PreviewOptions {
+    pub fn new() -> PreviewOptions {
+        PreviewOptions::default()
+    }
+    /// Length in pixels of the longest side of the picture, at most `MAX_SIZE`
+    pub fn with_size(mut self, size: u32) -> PreviewOptions {
+        self.size = size.clamp(1, MAX_SIZE);
+        self
+    }
+    /// Colours marks from blue for the slowest to red for the fastest
+    pub fn with_color_by_speed(mut self, color_by_speed: bool) -> PreviewOptions {
+        self.color_by_speed = color_by_speed;
+        self
+    }
+    /// Whether jumps and moves are drawn, true by default
+    pub fn with_jumps(mut self, jumps: bool) -> PreviewOptions {
+        self.jumps = jumps;
+        self
+    }
+}
+
+/// What a stroke of the preview stands for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StrokeKind {
+    /// Laser on, at the marking speed if one was set
+    Mark(Option<f32>),
+    /// Laser off: a jump or a move
+    Jump,
+}
+
+/// A polyline, arcs and circles being flattened into short segments
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stroke {
+    pub kind: StrokeKind,
+    pub points: Vec<Position>,
+}
+
+/// Geometry of a job, in millimetres, ready to be drawn.
+///
+/// Loops are drawn once, as repeating them retraces the same absolute path; the scanner
+/// starts at the origin.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preview {
+    pub strokes: Vec<Stroke>,
+    /// Positions where the laser bursts without moving
+    pub bursts: Vec<Position>,
+}
+
+/// Longest flattened segment of an arc, in degrees
+const ARC_STEP: f64 = 5.0;
+
+impl Preview {
+    pub fn new(commands: &[ScannerCommand]) -> Preview {
+        let mut preview = Preview {
+            strokes: Vec::new(),
+            bursts: Vec::new(),
+        };
+        let mut position = Position::new(0.0, 0.0);
+        let mut speed = None;
+        for command in commands {
+            let mark = StrokeKind::Mark(speed);
+            let points = match command {
+                ScannerCommand::Jump(to) | ScannerCommand::Move(to) => {
+                    preview.add(StrokeKind::Jump, position, vec![*to])
+                }
+                ScannerCommand::Line(to) => preview.add(mark, position, vec![*to]),
+                // the target runs a flat arc as MOVETO, with the laser off
+                ScannerCommand::Arc(to, bulge) if bulge.abs() < FLAT_BULGE => {
+                    preview.add(StrokeKind::Jump, position, vec![*to])
+                }
+                ScannerCommand::Arc(to, bulge) => {
+                    let points = arc(&position, to, *bulge as f64);
+                    preview.add(mark, position, points)
+                }
+                ScannerCommand::Circle(center, angle) => {
+                    let points = rotation(&position, center, *angle as f64);
+                    preview.add(mark, position, points)
+                }
+                ScannerCommand::SetSpeed(new) => {
+                    speed = Some(*new);
+                    None
+                }
+                ScannerCommand::Burst(_) => {
+                    preview.bursts.push(position);
+                    None
+                }
+                _ => None,
+            };
+            if let Some(end) = points {
+                position = end;
+            }
+        }
+        preview
+    }
+
+    /// Appends points to the last stroke if it continues it, returns the new position
+    fn add(&mut self, kind: StrokeKind, from: Position, points: Vec<Position>) -> Option<Position> {
+        let end = *points.last()?;
+        match self.strokes.last_mut() {
+            Some(last) if last.kind == kind && last.points.last() == Some(&from) => {
+                last.points.extend(points)
+            }
+            _ => {
+                let mut stroke = vec![from];
+                stroke.extend(points);
+                self.strokes.push(Stroke {
+                    kind,
+                    points: stroke,
+                });
+            }
+        }
+        Some(end)
+    }
+
+    /// Smallest and largest corners of the box holding every stroke and burst drawn
+    pub fn bounds(&self, jumps: bool) -> Option<(Position, Position)> {
+        let points = self
+            .strokes
+            .iter()
+            .filter(|stroke| jumps || stroke.kind != StrokeKind::Jump)
+            .flat_map(|stroke| stroke.points.iter())
+            .chain(self.bursts.iter());
+        Position::bounds(points)
+    }
+
+    /// Draws the preview as an SVG document
+    pub fn to_svg(&self, options: &PreviewOptions) -> String {
+        let canvas = Canvas::new(self, options);
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+             viewBox=\"0 0 {w} {h}\">\n<rect width=\"100%\" height=\"100%\" fill=\"white\"/>\n",
+            w = canvas.width,
+            h = canvas.height
+        );
+        for (stroke, color) in canvas.strokes(self) {
+            let points: Vec<String> = stroke
+                .points
+                .iter()
+                .map(|p| {
+                    let (x, y) = canvas.map(p);
+                    format!("{:.2},{:.2}", x, y)
+                })
+                .collect();
+            let dash = match stroke.kind {
+                StrokeKind::Jump => format!(" stroke-dasharray=\"{0},{0}\"", 4.0 * canvas.line),
+                StrokeKind::Mark(_) => String::new(),
+            };
+            let _ = writeln!(
+                svg,
+                "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\" \
+                 stroke-linejoin=\"round\"{}/>",
+                points.join(" "),
+                hex(color),
+                canvas.line,
+                dash
+            );
+        }
+        for burst in &self.bursts {
+            let (x, y) = canvas.map(burst);
+            let _ = writeln!(
+                svg,
+                "<circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"{}\" fill=\"{}\"/>",
+                x,
+                y,
+                3.0 * canvas.line,
+                hex(BURST)
+            );
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    /// Draws the preview as a PNG image
+    #[cfg(feature = "png")]
+    pub fn to_png(&self, options: &PreviewOptions) -> Result<Vec<u8>, crate::AppError> {
+        use tiny_skia::{FillRule, Paint, PathBuilder, Pixmap, StrokeDash, Transform};
+
+        let canvas = Canvas::new(self, options);
+        let mut pixmap = Pixmap::new(canvas.width, canvas.height)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "empty image"))?;
+        pixmap.fill(tiny_skia::Color::WHITE);
+        let paint = |(r, g, b): (u8, u8, u8)| {
+            let mut paint = Paint::default();
+            paint.set_color_rgba8(r, g, b, 255);
+            paint.anti_alias = true;
+            paint
+        };
+        for (stroke, color) in canvas.strokes(self) {
+            let mut path = PathBuilder::new();
+            for (i, point) in stroke.points.iter().enumerate() {
+                let (x, y) = canvas.map(point);
+                if i == 0 {
+                    path.move_to(x as f32, y as f32);
+                } else {
+                    path.line_to(x as f32, y as f32);
+                }
+            }
+            let path = match path.finish() {
+                Some(path) => path,
+                None => continue,
+            };
+            let width = canvas.line as f32;
+            let style = tiny_skia::Stroke {
+                width,
+                line_join: tiny_skia::LineJoin::Round,
+                dash: match stroke.kind {
+                    StrokeKind::Jump => StrokeDash::new(vec![4.0 * width, 4.0 * width], 0.0),
+                    StrokeKind::Mark(_) => None,
+                },
+                ..tiny_skia::Stroke::default()
+            };
+            pixmap.stroke_path(&path, &paint(color), &style, Transform::identity(), None);
+        }
+        for burst in &self.bursts {
+            let (x, y) = canvas.map(burst);
+            let radius = 3.0 * canvas.line as f32;
+            if let Some(dot) = PathBuilder::from_circle(x as f32, y as f32, radius) {
+                let paint = paint(BURST);
+                pixmap.fill_path(&dot, &paint, FillRule::Winding, Transform::identity(), None);
+            }
+        }
+        pixmap
+            .encode_png()
+            .map_err(|e| std::io::Error::other(e).into())
+    }
+}
+
+const MARK: (u8, u8, u8) = (0, 0, 0);
+const JUMP: (u8, u8, u8) = (160, 160, 160);
+const BURST: (u8, u8, u8) = (220, 0, 0);
+
+/// Picture coordinates: millimetres scaled to pixels, y pointing down
+struct Canvas {
+    width: u32,
+    height: u32,
+    scale: f64,
+    origin: Position,
+    /// Stroke width in pixels
+    line: f64,
+    options: PreviewOptions,
+    /// Slowest and fastest marking speeds
+    speeds: Option<(f32, f32)>,
+}
+
+impl Canvas {
+    fn new(preview: &Preview, options: &PreviewOptions) -> Canvas {
+        let (min, max) = preview
+            .bounds(options.jumps)
+            .unwrap_or((Position::new(0.0, 0.0), Position::new(0.0, 0.0)));
+        // a margin of 2 % on every side, and room for a single point
+        let span = (max.x() - min.x()).max(max.y() - min.y()).max(1e-3);
+        let margin = span * 0.02;
+        let (w, h) = (
+            max.x() - min.x() + 2.0 * margin,
+            max.y() - min.y() + 2.0 * margin,
+        );
+        let scale = options.size as f64 / w.max(h);
+        let speeds = preview
+            .strokes
+            .iter()
+            .filter_map(|stroke| match stroke.kind {
+                StrokeKind::Mark(speed) => speed,
+                StrokeKind::Jump => None,
+            })
+            .fold(None, |speeds: Option<(f32, f32)>, speed| {
+                let (slow, fast) = speeds.unwrap_or((speed, speed));
+                Some((slow.min(speed), fast.max(speed)))
+            });
+        Canvas {
+            width: ((w * scale).round() as u32).max(1),
+            height: ((h * scale).round() as u32).max(1),
+            scale,
+            origin: Position::new(min.x() - margin, max.y() + margin),
+            line: (options.size as f64 / 512.0).max(1.0),
+            options: options.clone(),
+            speeds,
+        }
+    }
+
+    fn map(&self, p: &Position) -> (f64, f64) {
+        (
+            (p.x() - self.origin.x()) * self.scale,
+            (self.origin.y() - p.y()) * self.scale,
+        )
+    }
+
+    /// Strokes to draw along with their colour, jumps first so that marks cover them
+    fn strokes<'a>(&self, preview: &'a Preview) -> Vec<(&'a Stroke, (u8, u8, u8))> {
+        let jumps = preview
+            .strokes
+            .iter()
+            .filter(|stroke| self.options.jumps && stroke.kind == StrokeKind::Jump)
+            .map(|stroke| (stroke, JUMP));
+        let marks = preview
+            .strokes
+            .iter()
+            .filter_map(|stroke| match stroke.kind {
+                StrokeKind::Mark(speed) => Some((stroke, self.color(speed))),
+                StrokeKind::Jump => None,
+            });
+        jumps.chain(marks).collect()
+    }
+
+    fn color(&self, speed: Option<f32>) -> (u8, u8, u8) {
+        match (self.options.color_by_speed, speed, self.speeds) {
+            (true, Some(speed), Some((slow, fast))) => {
+                let t = if fast > slow {
+                    (speed - slow) / (fast - slow)
+                } else {
+                    0.5
+                };
+                hue(240.0 * (1.0 - t as f64))
+            }
+            _ => MARK,
+        }
+    }
+}
+
+/// Fully saturated colour of hue `degrees`, 0 being red and 240 blue
+fn hue(degrees: f64) -> (u8, u8, u8) {
+    let h = degrees / 60.0;
+    let x = 1.0 - (h % 2.0 - 1.0).abs();
+    let (r, g, b) = match h as u32 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+    let byte = |v: f64| (v * 255.0).round() as u8;
+    (byte(r), byte(g), byte(b))
+}
+
+fn hex((r, g, b): (u8, u8, u8)) -> String {
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// Points along the rotation of `from` around `center` by `angle` degrees
+fn rotation(from: &Position, center: &Position, angle: f64) -> Vec<Position> {
+    let steps = ((angle.abs() / ARC_STEP).ceil() as usize).max(1);
+    (1..=steps)
+        .map(|i| from.rotate(center, angle * i as f64 / steps as f64))
+        .collect()
+}
+
+/// Points along the arc from `from` to `to` whose bulge is the tangent of a quarter of its
+/// included angle, counterclockwise for a positive bulge
+fn arc(from: &Position, to: &Position, bulge: f64) -> Vec<Position> {
+    let center = match from.arc_center(to, bulge) {
+        Some(center) => center,
+        None => return vec![*to],
+    };
+    let mut points = rotation(from, &center, 4.0 * bulge.atan().to_degrees());
+    // ends exactly where the command does
+    if let Some(last) = points.last_mut() {
+        *last = *to;
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_script;
+
+    fn preview(script: &str) -> Preview {
+        Preview::new(&parse_script(script).unwrap())
+    }
+
+    fn near(a: &Position, b: &Position) -> bool {
+        (a.x() - b.x()).abs() < 1e-9 && (a.y() - b.y()).abs() < 1e-9
+    }
+
+    #[test]
+    fn strokes() {
+        let preview = preview(
+            "rtJumpTo(1, 1); rtSetSpeed(100); rtLineTo(2, 1); rtLineTo(2, 2);
+             rtSetSpeed(200); rtLineTo(1, 2); rtJumpTo(0, 0); rtBurst(5);",
+        );
+        let kinds: Vec<StrokeKind> = preview.strokes.iter().map(|s| s.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                StrokeKind::Jump,
+                StrokeKind::Mark(Some(100.0)),
+                StrokeKind::Mark(Some(200.0)),
+                StrokeKind::Jump
+            ]
+        );
+        assert_eq!(preview.strokes[1].points.len(), 3);
+        assert_eq!(preview.bursts, vec![Position::new(0.0, 0.0)]);
+        assert_eq!(
+            preview.bounds(false),
+            Some((Position::new(0.0, 0.0), Position::new(2.0, 2.0)))
+        );
+    }
+
+    #[test]
+    fn curves() {
+        // half circle through (0, -5), counterclockwise from (-5, 0)
+        let preview = preview("rtJumpTo(-5, 0); rtArcTo(5, 0, 1); rtCircle(0, 0, 180);");
+        let mark = &preview.strokes[1].points;
+        assert!(mark.iter().any(|p| near(p, &Position::new(0.0, -5.0))));
+        assert!(mark.iter().all(|p| (p.x().hypot(p.y()) - 5.0).abs() < 1e-9));
+        assert!(near(mark.last().unwrap(), &Position::new(-5.0, 0.0)));
+        assert_eq!(mark.len(), 1 + 36 + 36);
+
+        // a flat arc is a move, as the target runs it
+        let flat = self::preview("rtLineTo(5, 0); rtArcTo(5, 5, 0);");
+        assert_eq!(flat.strokes.len(), 2);
+        assert_eq!(flat.strokes[1].kind, StrokeKind::Jump);
+    }
+
+    #[test]
+    fn svg() {
+        let preview = preview("rtJumpTo(0, 10); rtSetSpeed(1); rtLineTo(10, 0); rtBurst(1);");
+        let options = PreviewOptions::new().with_size(100);
+        let svg = preview.to_svg(&options);
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains("width=\"100\" height=\"100\""));
+        // y points down: (0, 10) is at the top left corner, within the margin
+        assert!(svg.contains("points=\"1.92,1.92 98.08,98.08\" fill=\"none\" stroke=\"#000000\""));
+        assert!(svg.contains("stroke-dasharray"));
+        assert!(svg.contains("<circle cx=\"98.08\" cy=\"98.08\""));
+
+        let svg = preview.to_svg(&options.with_jumps(false).with_color_by_speed(true));
+        assert!(!svg.contains("stroke-dasharray"));
+        assert!(svg.contains("stroke=\"#00ff00\""));
+
+        let svg = preview.to_svg(&PreviewOptions::new().with_size(100_000));
+        assert!(svg.contains("width=\"16384\" height=\"16384\""));
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn png() {
+        let preview = preview("rtSetSpeed(1); rtLineTo(10, 5);");
+        let png = preview
+            .to_png(&PreviewOptions::new().with_size(64))
+            .unwrap();
+        assert_eq!(&png[1..4], b"PNG");
+    }
+}
@@ -0,0 +1,203 @@
+use crate::commands::TgtStatus;
+use crate::controller::Controller;
+use crate::{AppError, ReplyStream};
+use std::thread;
+use std::time::Duration;
+
+/// Fields that differ between two status snapshots
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct StatusDiff {
+    /// Input pins that toggled
+    pub inputs: u16,
+    /// Output pins that toggled
+    pub outputs: u16,
+    pub system_state: bool,
+    pub return_value: bool,
+    pub counter: bool,
+    /// Analog channels that changed
+    pub analog: [bool; 4],
+    pub otf: bool,
+    pub table: bool,
+    /// Deflection x, y or z moved
+    pub deflection: bool,
+}
+impl StatusDiff {
+    pub fn between(old: &TgtStatus, new: &TgtStatus) -> StatusDiff {
+        let (old_analog, new_analog) = (old.analogs(), new.analogs());
+        let mut analog = [false; 4];
+        for (channel, changed) in analog.iter_mut().enumerate() {
+            *changed = old_analog[channel] != new_analog[channel];
+        }
+        StatusDiff {
+            inputs: old.inputs().bits() ^ new.inputs().bits(),
+            outputs: old.outputs().bits() ^ new.outputs().bits(),
+            system_state: old.system_state() != new.system_state(),
+            return_value: old.return_value() != new.return_value(),
+            counter: old.counter() != new.counter(),
+            analog,
+            otf: old.otf() != new.otf(),
+            table: old.table() != new.table(),
+            deflection: old.get_position() != new.get_position()
+                || old.deflection_z() != new.deflection_z(),
+        }
+    }
+
+    /// Diff of a first snapshot, where everything is new
+    pub fn all() -> StatusDiff {
+        StatusDiff {
+            inputs: 0xFFFF,
+            outputs: 0xFFFF,
+            system_state: true,
+            return_value: true,
+            counter: true,
+            analog: [true; 4],
+            otf: true,
+            table: true,
+            deflection: true,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == StatusDiff::default()
+    }
+
+    /// Names of the changed fields, as used in the `watch` JSON output
+    pub fn fields(&self) -> Vec<&'static str> {
+        let fields = [
+            ("inputs", self.inputs != 0),
+            ("outputs", self.outputs != 0),
+            ("state", self.system_state),
+            ("return_value", self.return_value),
+            ("counter", self.counter),
+            ("analog", self.analog.iter().any(|changed| *changed)),
+            ("otf", self.otf),
+            ("table", self.table),
+            ("deflection", self.deflection),
+        ];
+        fields
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+/// A status snapshot along with what changed since the previous one
+#[derive(Debug, PartialEq, Clone)]
+pub struct StatusUpdate {
+    pub status: TgtStatus,
+    pub diff: StatusDiff,
+}
+
+/// Keeps the last status seen and turns new snapshots into updates
+#[derive(Debug, Default)]
+pub struct StatusWatcher {
+    last: Option<TgtStatus>,
+}
+impl StatusWatcher {
+    pub fn new() -> StatusWatcher {
+        StatusWatcher::default()
+    }
+
+    /// Returns an update if `status` differs from the last one, always for the first one
+    pub fn update(&mut self, status: TgtStatus) -> Option<StatusUpdate> {
+        let diff = match &self.last {
+            Some(last) => StatusDiff::between(last, &status),
+            None => StatusDiff::all(),
+        };
+        if diff.is_empty() {
+            return None;
+        }
+        self.last = Some(status.clone());
+        Some(StatusUpdate { status, diff })
+    }
+}
+
+/// Something the target status can be queried from
+pub trait StatusSource {
+    fn poll_status(&mut self) -> Result<TgtStatus, AppError>;
+}
+
+/// A connection to the target, queried with `get_status`
+impl<S: ReplyStream> StatusSource for S {
+    fn poll_status(&mut self) -> Result<TgtStatus, AppError> {
+        crate::get_status(self)
+    }
+}
+
+/// Queries the target the commands are addressed to, reconnecting as configured
+impl StatusSource for Controller {
+    fn poll_status(&mut self) -> Result<TgtStatus, AppError> {
+        self.status()
+    }
+}
+
+/// Polls the target status every `interval`, yielding only the snapshots that changed
+pub struct StatusSubscription<'a, S> {
+    stream: &'a mut S,
+    interval: Duration,
+    watcher: StatusWatcher,
+    polled: bool,
+}
+impl<'a, S: StatusSource> StatusSubscription<'a, S> {
+    pub fn new(stream: &'a mut S, interval: Duration) -> StatusSubscription<'a, S> {
+        StatusSubscription {
+            stream,
+            interval,
+            watcher: StatusWatcher::new(),
+            polled: false,
+        }
+    }
+}
+impl<'a, S: StatusSource> Iterator for StatusSubscription<'a, S> {
+    type Item = Result<StatusUpdate, AppError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.polled {
+                thread::sleep(self.interval);
+            }
+            self.polled = true;
+            match self.stream.poll_status() {
+                Ok(status) => {
+                    if let Some(update) = self.watcher.update(status) {
+                        return Some(Ok(update));
+                    }
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(inputs: u16, deflx: i32, analog: u8) -> TgtStatus {
+        let mut reply = inputs.to_le_bytes().to_vec();
+        reply.extend_from_slice(&[0, 0, 0x40, 0, 0, 0, 0, analog, 0, 0]);
+        for value in &[0_i32, 0, 0, 0, 0, deflx, 0, 0] {
+            reply.extend_from_slice(&value.to_le_bytes());
+        }
+        bincode::deserialize(&reply).unwrap()
+    }
+
+    #[test]
+    fn diffs() {
+        let mut watcher = StatusWatcher::new();
+        let first = watcher.update(status(0b0001, 0, 0)).unwrap();
+        assert_eq!(first.diff, StatusDiff::all());
+
+        assert_eq!(watcher.update(status(0b0001, 0, 0)), None);
+
+        let update = watcher.update(status(0b0100, 1500, 0)).unwrap();
+        assert_eq!(update.diff.inputs, 0b0101);
+        assert!(update.diff.deflection);
+        assert_eq!(update.diff.fields(), vec!["inputs", "deflection"]);
+
+        let update = watcher.update(status(0b0100, 1500, 9)).unwrap();
+        assert_eq!(update.diff.analog, [false, true, false, false]);
+        assert_eq!(update.diff.fields(), vec!["analog"]);
+    }
+}